@@ -0,0 +1,285 @@
+use crate::chainparams::{ChainParams, Network};
+use crate::errors::Result;
+use bitcoin_hashes::{ripemd160, Hash};
+use bitcoincash_addr::{CashAddrCodec, AddressCodec, HashType};
+use failure::format_err;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+
+/// AddressFormat selects how an address is written out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddressFormat {
+    Base58,
+    CashAddr,
+}
+
+impl FromStr for AddressFormat {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<AddressFormat> {
+        match s {
+            "base58" => Ok(AddressFormat::Base58),
+            "cashaddr" => Ok(AddressFormat::CashAddr),
+            _ => Err(format_err!("unknown address format '{}'", s)),
+        }
+    }
+}
+
+/// Address is a decoded address: a network version byte and a pubkey hash, or the
+/// script hash of a multisig address. Its base58 form is canonical, CashAddr is
+/// accepted and shown on request
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Address {
+    version: u8,
+    pub_key_hash: Vec<u8>,
+}
+
+/// HashPubKey returns RIPEMD160(SHA256(public key))
+pub fn hash_pub_key(public_key: &[u8]) -> Vec<u8> {
+    // 1. SHA256(公钥)
+    let sha256_result = Sha256::digest(public_key);
+
+    // 2. RIPEMD160(SHA256(公钥))
+    ripemd160::Hash::hash(&sha256_result).to_byte_array().to_vec()
+}
+
+fn cash_addr_prefix(network: Network) -> &'static str {
+    match network {
+        Network::Main => "bitcoincash",
+        Network::Test => "bchtest",
+        Network::Regtest => "bchreg",
+    }
+}
+
+/// Checksum returns the first 4 bytes of the double SHA256 of a base58check payload
+pub fn checksum(payload: &[u8]) -> Vec<u8> {
+    // 双重SHA256, 取前4字节作为校验和
+    let first = Sha256::digest(payload);
+    let second = Sha256::digest(first);
+    second[0..4].to_vec()
+}
+
+impl Address {
+    pub fn new(version: u8, pub_key_hash: Vec<u8>) -> Address {
+        Address {
+            version,
+            pub_key_hash,
+        }
+    }
+
+    /// FromPubKey returns the address of a public key on the network with this version
+    pub fn from_pub_key(public_key: &[u8], version: u8) -> Address {
+        Address::new(version, hash_pub_key(public_key))
+    }
+
+    /// Parse decodes an address in either format and checks it belongs to the given network,
+    /// a CashAddr address may leave out the network prefix
+    pub fn parse(s: &str, params: &ChainParams) -> Result<Address> {
+        let address = if s.contains(':') {
+            let (address, network) = Address::from_cash_addr(s)?;
+            if network != params.network {
+                return Err(format_err!("address {} is not a {} network address", s, params.network));
+            }
+            address
+        } else {
+            match Address::from_str(s) {
+                Ok(address) => address,
+                Err(e) => {
+                    let prefixed = format!("{}:{}", cash_addr_prefix(params.network), s);
+                    Address::from_cash_addr(&prefixed).map_err(|_| e)?.0
+                }
+            }
+        };
+        address.check_network(params)?;
+        Ok(address)
+    }
+
+    /// FromCashAddr decodes a CashAddr address, returning the network its prefix names
+    fn from_cash_addr(s: &str) -> Result<(Address, Network)> {
+        let decoded = CashAddrCodec::decode(s)
+            .map_err(|e| format_err!("address {} is not a valid CashAddr address: {}", s, e))?;
+        if decoded.body.len() != 20 {
+            return Err(format_err!("address {} has a wrong length", s));
+        }
+        let network = match decoded.network {
+            bitcoincash_addr::Network::Main => Network::Main,
+            bitcoincash_addr::Network::Test => Network::Test,
+            bitcoincash_addr::Network::Regtest => Network::Regtest,
+        };
+        let version = match decoded.hash_type {
+            HashType::Key => network.params().address_version,
+            HashType::Script => network.params().script_version,
+        };
+        Ok((Address::new(version, decoded.body), network))
+    }
+
+    /// Encode writes the address in the given format for a network
+    pub fn encode(&self, format: AddressFormat, params: &ChainParams) -> Result<String> {
+        match format {
+            AddressFormat::Base58 => Ok(self.to_string()),
+            AddressFormat::CashAddr => {
+                let network = match params.network {
+                    Network::Main => bitcoincash_addr::Network::Main,
+                    Network::Test => bitcoincash_addr::Network::Test,
+                    Network::Regtest => bitcoincash_addr::Network::Regtest,
+                };
+                let hash_type = if self.is_script() { HashType::Script } else { HashType::Key };
+                CashAddrCodec::encode(&self.pub_key_hash, hash_type, network)
+                    .map_err(|e| format_err!("cannot encode address {}: {}", self, e))
+            }
+        }
+    }
+
+    pub fn pub_key_hash(&self) -> &[u8] {
+        &self.pub_key_hash
+    }
+
+    /// FromScript returns the address of a multisig script on a network
+    pub fn from_script(script: &[u8], params: &ChainParams) -> Address {
+        Address::new(params.script_version, hash_pub_key(script))
+    }
+
+    /// IsScript tells whether the address pays to a script hash rather than a pubkey hash
+    pub fn is_script(&self) -> bool {
+        [Network::Main, Network::Test, Network::Regtest]
+            .iter()
+            .any(|n| n.params().script_version == self.version)
+    }
+
+    /// CheckNetwork fails when the address version is not one of the network
+    pub fn check_network(&self, params: &ChainParams) -> Result<()> {
+        if self.version != params.address_version && self.version != params.script_version {
+            return Err(format_err!(
+                "address {} is not a {} network address",
+                self,
+                params.network
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Address {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Address> {
+        if s.contains(':') {
+            return Ok(Address::from_cash_addr(s)?.0);
+        }
+        let data = bs58::decode(s)
+            .into_vec()
+            .map_err(|e| format_err!("address {} is not valid base58: {}", s, e))?;
+        if data.len() != 25 {
+            return Err(format_err!("address {} has a wrong length", s));
+        }
+        let (payload, sum) = data.split_at(21);
+        if checksum(payload) != sum {
+            return Err(format_err!("address {} has a wrong checksum", s));
+        }
+        let version = payload[0];
+        let known = [Network::Main, Network::Test, Network::Regtest]
+            .iter()
+            .any(|n| n.params().address_version == version || n.params().script_version == version);
+        if !known {
+            return Err(format_err!("address {} has an unknown version byte {:#04x}", s, version));
+        }
+        Ok(Address::new(version, payload[1..].to_vec()))
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // 版本号 + 公钥哈希 + 校验和, 再Base58编码
+        let mut payload = vec![self.version];
+        payload.extend_from_slice(&self.pub_key_hash);
+        let sum = checksum(&payload);
+        payload.extend_from_slice(&sum);
+        f.write_str(&bs58::encode(payload).into_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chainparams::REGTEST_GENESIS_ADDRESS;
+
+    #[test]
+    fn test_parse_address() {
+        let address = Address::from_str("1111111111111111111114oLvT2").unwrap();
+        assert_eq!(address.version, 0x00);
+        assert_eq!(address.pub_key_hash(), &[0; 20]);
+
+        let regtest = Address::parse(REGTEST_GENESIS_ADDRESS, &ChainParams::regtest()).unwrap();
+        assert_eq!(regtest.pub_key_hash(), &[0; 20]);
+        assert_eq!(regtest.to_string(), REGTEST_GENESIS_ADDRESS);
+        assert!(Address::parse(REGTEST_GENESIS_ADDRESS, &ChainParams::main()).is_err());
+    }
+
+    #[test]
+    fn test_testnet_and_regtest_differ() {
+        let (test, regtest) = (ChainParams::testnet(), ChainParams::regtest());
+        for (params, other) in [(&test, &regtest), (&regtest, &test)] {
+            let address = Address::from_pub_key(&[7; 32], params.address_version);
+            let script = Address::from_script(b"script", params);
+            assert_eq!(Address::parse(&address.to_string(), params).unwrap(), address);
+            assert!(Address::parse(&address.to_string(), other).is_err());
+            assert!(Address::parse(&script.to_string(), other).is_err());
+            assert!(script.is_script() && !address.is_script());
+        }
+        assert!(REGTEST_GENESIS_ADDRESS.starts_with('r'));
+    }
+
+    #[test]
+    fn test_reject_bad_address() {
+        // a typo breaks the checksum
+        assert!(Address::from_str("1111111111111111111114oLvT3").is_err());
+        assert!(Address::from_str("bob").is_err());
+        assert!(Address::from_str("0OIl").is_err());
+        let unknown = Address::new(0x07, vec![0; 20]).to_string();
+        assert!(Address::from_str(&unknown).is_err());
+    }
+
+    #[test]
+    fn test_from_pub_key() {
+        let address = Address::from_pub_key(&[7; 32], 0x00);
+        let parsed = Address::from_str(&address.to_string()).unwrap();
+        assert_eq!(parsed, address);
+        assert_eq!(parsed.pub_key_hash(), hash_pub_key(&[7; 32]).as_slice());
+    }
+
+    #[test]
+    fn test_cash_addr() {
+        let params = ChainParams::main();
+        let address = Address::from_pub_key(&[7; 32], params.address_version);
+        let cash = address.encode(AddressFormat::CashAddr, &params).unwrap();
+        assert!(cash.starts_with("bitcoincash:q"));
+
+        assert_eq!(Address::parse(&cash, &params).unwrap(), address);
+        let short = cash.trim_start_matches("bitcoincash:");
+        assert_eq!(Address::parse(short, &params).unwrap().pub_key_hash(), address.pub_key_hash());
+        assert!(Address::parse(&cash, &ChainParams::testnet()).is_err());
+
+        let regtest = ChainParams::regtest();
+        let reg = Address::from_pub_key(&[7; 32], regtest.address_version);
+        let reg_cash = reg.encode(AddressFormat::CashAddr, &regtest).unwrap();
+        assert!(reg_cash.starts_with("bchreg:"));
+        assert!(Address::parse(&reg_cash, &ChainParams::testnet()).is_err());
+        assert_eq!(Address::parse(&reg_cash, &regtest).unwrap(), reg);
+    }
+
+    #[test]
+    fn test_script_address() {
+        let params = ChainParams::main();
+        let address = Address::from_script(b"script", &params);
+        assert!(address.is_script());
+        assert!(address.to_string().starts_with('3'));
+        assert_eq!(Address::parse(&address.to_string(), &params).unwrap(), address);
+        assert!(Address::parse(&address.to_string(), &ChainParams::testnet()).is_err());
+
+        let cash = address.encode(AddressFormat::CashAddr, &params).unwrap();
+        assert!(cash.starts_with("bitcoincash:p"));
+        assert_eq!(Address::parse(&cash, &params).unwrap(), address);
+        assert!(Address::from_script(b"script", &ChainParams::testnet()).to_string().starts_with('2'));
+    }
+}
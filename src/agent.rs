@@ -0,0 +1,184 @@
+use crate::chainparams::ChainParams;
+use crate::errors::Result;
+
+/// AGENT_COMMAND is the hidden subcommand the wallet agent process runs
+pub const AGENT_COMMAND: &str = "walletagent";
+
+/// MAX_UNLOCK_TIMEOUT caps the seconds walletpassphrase keeps the key in the agent
+pub const MAX_UNLOCK_TIMEOUT: u64 = 3600;
+
+#[cfg(unix)]
+pub use unix::{key, lock, serve, start};
+
+/// The agent keeps the key behind a unix socket, only unix targets have one
+#[cfg(unix)]
+mod unix {
+    use super::*;
+    use failure::format_err;
+    use std::fs::{DirBuilder, Permissions};
+    use std::io::{BufRead, BufReader, ErrorKind, Write};
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::os::unix::process::CommandExt;
+    use std::path::Path;
+    use std::process::{Command, Stdio};
+    use std::time::{Duration, SystemTime};
+
+    /// Start hands the key of an unlocked wallet to a new agent process keeping it in memory
+    /// for timeout seconds, the key goes through a pipe and is never written to disk.
+    /// It returns when the agent listens, with the time in milliseconds it locks again
+    pub fn start(params: &ChainParams, key: &[u8; 32], timeout: u64) -> Result<u128> {
+        lock(params)?;
+        let mut child = Command::new(std::env::current_exe()?)
+            .arg(AGENT_COMMAND)
+            .arg(params.agent_path())
+            .arg(timeout.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            // 不随终端的 Ctrl-C 一起退出
+            .process_group(0)
+            .spawn()?;
+        let mut stdin = child.stdin.take().ok_or_else(|| format_err!("cannot reach the wallet agent"))?;
+        writeln!(stdin, "{}", hex::encode(key))?;
+        drop(stdin);
+        let mut ready = String::new();
+        if let Some(stdout) = child.stdout.take() {
+            BufReader::new(stdout).read_line(&mut ready)?;
+        }
+        ready.trim().parse().map_err(|_| format_err!("the wallet agent did not start"))
+    }
+
+    /// Serve runs the agent: it gives the key to every connection on the socket until the timeout,
+    /// at most MAX_UNLOCK_TIMEOUT, passes or walletlock stops it, ready is called with the lock time once it listens
+    pub fn serve(socket: &str, key: &str, timeout: u64, ready: impl FnOnce(u128) -> Result<()>) -> Result<()> {
+        let until = now_millis()? + timeout.min(MAX_UNLOCK_TIMEOUT) as u128 * 1000;
+        // the socket is bound inside a directory only the owner can enter, so nobody else
+        // can connect to it before its own permissions are narrowed
+        let dir = Path::new(socket).parent().ok_or_else(|| format_err!("no directory for the wallet agent"))?;
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        std::fs::set_permissions(dir, Permissions::from_mode(0o700))?;
+        std::fs::remove_file(socket).ok();
+        let listener = UnixListener::bind(socket)?;
+        std::fs::set_permissions(socket, Permissions::from_mode(0o600))?;
+        listener.set_nonblocking(true)?;
+        ready(until)?;
+
+        while now_millis()? < until {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    // a client failing does not stop the agent
+                    if let Ok(true) = answer(stream, key, until) {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => std::thread::sleep(Duration::from_millis(50)),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        std::fs::remove_file(socket).ok();
+        Ok(())
+    }
+
+    /// Answer replies to one request on the socket, telling whether it asked the agent to stop
+    fn answer(mut stream: UnixStream, key: &str, until: u128) -> std::io::Result<bool> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let mut request = String::new();
+        BufReader::new(&stream).read_line(&mut request)?;
+        match request.trim() {
+            "KEY" => writeln!(stream, "{} {}", key, until).map(|_| false),
+            "LOCK" => writeln!(stream, "OK").map(|_| true),
+            _ => Ok(false),
+        }
+    }
+
+    /// Key asks the running agent for the wallet key and the time it locks, none when the wallet is not unlocked
+    pub fn key(params: &ChainParams) -> Option<([u8; 32], u128)> {
+        let reply = request(params, "KEY").ok()??;
+        let (key, until) = reply.split_once(' ')?;
+        let key = hex::decode(key).ok()?.try_into().ok()?;
+        Some((key, until.parse().ok()?))
+    }
+
+    /// Lock stops the running agent, telling whether there was one
+    pub fn lock(params: &ChainParams) -> Result<bool> {
+        Ok(request(params, "LOCK")?.is_some())
+    }
+
+    fn request(params: &ChainParams, command: &str) -> Result<Option<String>> {
+        let mut stream = match UnixStream::connect(params.agent_path()) {
+            Ok(stream) => stream,
+            Err(_) => return Ok(None),
+        };
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        writeln!(stream, "{}", command)?;
+        let mut reply = String::new();
+        BufReader::new(&stream).read_line(&mut reply)?;
+        Ok(Some(reply.trim().to_string()))
+    }
+
+    fn now_millis() -> Result<u128> {
+        Ok(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis())
+    }
+
+}
+
+/// Start fails where there is no wallet agent, an encrypted wallet cannot be unlocked there
+#[cfg(not(unix))]
+pub fn start(_params: &ChainParams, _key: &[u8; 32], _timeout: u64) -> Result<u128> {
+    Err(failure::format_err!("unlocking the wallet is not supported on this platform"))
+}
+
+#[cfg(not(unix))]
+pub fn serve(_socket: &str, _key: &str, _timeout: u64, _ready: impl FnOnce(u128) -> Result<()>) -> Result<()> {
+    Err(failure::format_err!("the wallet agent is not supported on this platform"))
+}
+
+#[cfg(not(unix))]
+pub fn key(_params: &ChainParams) -> Option<([u8; 32], u128)> {
+    None
+}
+
+#[cfg(not(unix))]
+pub fn lock(_params: &ChainParams) -> Result<bool> {
+    Ok(false)
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use std::time::SystemTime;
+    use crate::blockchain::Fixture;
+    use crate::chainparams::Network;
+
+    #[test]
+    fn test_agent() {
+        let f = Fixture::new(Network::Regtest);
+        let params = &f.params;
+        assert!(key(params).is_none());
+        assert!(!lock(params).unwrap());
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let socket = params.agent_path();
+        let agent = std::thread::spawn(move || {
+            serve(&socket, &hex::encode([7; 32]), u64::MAX, |until| Ok(tx.send(until)?)).unwrap()
+        });
+        let until = rx.recv().unwrap();
+        assert_eq!(key(params), Some(([7; 32], until)));
+        // however long it is asked for, the key is held at most MAX_UNLOCK_TIMEOUT
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+        assert!(until <= now + MAX_UNLOCK_TIMEOUT as u128 * 1000);
+        // only the owner can connect
+        let mode = std::fs::metadata(params.agent_path()).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let dir = Path::new(&params.agent_path()).parent().unwrap().to_path_buf();
+        assert_eq!(std::fs::metadata(dir).unwrap().permissions().mode() & 0o777, 0o700);
+
+        assert!(lock(params).unwrap());
+        agent.join().unwrap();
+        assert!(key(params).is_none());
+    }
+}
@@ -1,15 +1,15 @@
 use super::*;
 use bincode::serialize;
+use chainparams::ChainParams;
 use transaction::Transaction;
 use sha2::{Sha256, Digest};
 use merkle_cbt::merkle_tree::Merge;
 use merkle_cbt::merkle_tree::CBMT;
 use serde::{Deserialize, Serialize};
-use std::time::SystemTime;
 use log::info;
-use sha2::digest::Update;
 
-const TARGET_HEXS: usize = 4;
+/// MAX_BLOCK_SIZE is the largest encoded block a bootstrap file may carry
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
 
 #[derive(Clone, Debug,Serialize, Deserialize)]
 pub struct Block {
@@ -19,6 +19,7 @@ pub struct Block {
     hash: String,
     nonce: i32,
     height: i32,
+    target_hexs: usize,
 }
 
 impl Block {
@@ -38,15 +39,47 @@ impl Block {
         self.height
     }
 
-    /// NewBlock creates and returns Block
+    pub fn get_timestamp(&self) -> u128 {
+        self.timestamp
+    }
+
+    pub fn get_nonce(&self) -> i32 {
+        self.nonce
+    }
+
+    pub fn get_target_hexs(&self) -> usize {
+        self.target_hexs
+    }
+
+    /// NewBlock creates and returns Block, timestamped by the chain's clock after its parent's timestamp
     pub fn new_block(
+        data: Vec<Transaction>,
+        prev_block_hash: String,
+        prev_timestamp: Option<u128>,
+        height: i32,
+        params: &ChainParams,
+    ) -> Result<Block> {
+        let timestamp = params.clock.block_time(prev_timestamp)?;
+        Block::mine(data, prev_block_hash, height, timestamp, params.target_hexs)
+    }
+
+    /// NewGenesisBlock creates and returns genesis Block
+    pub fn new_genesis_block(coinbase: Transaction, params: &ChainParams) -> Block {
+        match params.genesis_timestamp {
+            Some(timestamp) => {
+                Block::mine(vec![coinbase], String::new(), 0, timestamp, params.target_hexs).unwrap()
+            }
+            None => Block::new_block(vec![coinbase], String::new(), None, 0, params).unwrap(),
+        }
+    }
+
+    fn mine(
         data: Vec<Transaction>,
         prev_block_hash: String,
         height: i32,
+        timestamp: u128,
+        target_hexs: usize,
     ) -> Result<Block> {
-        let timestamp = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)?
-            .as_millis();
         let mut block = Block {
             timestamp,
             transactions: data,
@@ -54,34 +87,30 @@ impl Block {
             hash: String::new(),
             nonce: 0,
             height,
+            target_hexs,
         };
         block.run_proof_of_work()?;
         Ok(block)
     }
 
-    /// NewGenesisBlock creates and returns genesis Block
-    pub fn new_genesis_block(coinbase: Transaction) -> Block {
-        Block::new_block(vec![coinbase], String::new(), 0).unwrap()
-    }
-
     /// Run performs a proof-of-work
     fn run_proof_of_work(&mut self) -> Result<()> {
         info!("Mining the block");
         while !self.validate()? {
             self.nonce += 1;
         }
-        let mut data = self.prepare_hash_data()?;
+        let data = self.prepare_hash_data()?;
         let mut hasher = Sha256::new();
         sha2::Digest::update(&mut hasher,&data);
         let hex_result = hasher.finalize().iter().map(|b| format!("{:02x}", b))
             .collect::<Vec<String>>()
             .join("");
-        self.hash = hex_result.clone();
+        self.hash = hex_result;
         Ok(())
     }
 
     // HashTransactions returns a hash of the transactions in the block
-    fn hash_transactions(&self) -> Result<Vec<u8>> {
+    pub fn hash_transactions(&self) -> Result<Vec<u8>> {
         let mut transactions = Vec::new();
         for tx in &self.transactions {
             transactions.push(tx.hash()?.as_bytes().to_owned());
@@ -91,12 +120,15 @@ impl Block {
         Ok(tree.root())
     }
 
+    /// PrepareHashData is what the block hash commits to. Since verifychain it holds the merkle
+    /// root instead of the transactions, blocks stored by earlier versions fail level 1 and their
+    /// chain has to be created again
     fn prepare_hash_data(&self) -> Result<Vec<u8>> {
         let content = (
             self.prev_block_hash.clone(),
-            self.transactions.clone(),
+            self.hash_transactions()?,
             self.timestamp,
-            TARGET_HEXS,
+            self.target_hexs,
             self.nonce,
         );
         let bytes = serialize(&content)?;
@@ -104,16 +136,27 @@ impl Block {
     }
 
     /// Validate validates block's PoW
-    fn validate(&self) -> Result<bool> {
-        let mut data = self.prepare_hash_data()?;
+    pub fn validate(&self) -> Result<bool> {
+        let data = self.prepare_hash_data()?;
         let mut hasher = Sha256::new();
         sha2::Digest::update(&mut hasher,&data);
         let hex_result = hasher.finalize().iter().map(|b| format!("{:02x}", b))
             .collect::<Vec<String>>()
             .join("");
         let mut vec1: Vec<u8> = Vec::new();
-        vec1.resize(TARGET_HEXS, '0' as u8);
-        Ok(hex_result[0..TARGET_HEXS] == String::from_utf8(vec1)?)
+        vec1.resize(self.target_hexs, b'0');
+        Ok(hex_result[0..self.target_hexs] == String::from_utf8(vec1)?)
+    }
+
+    /// CheckHash validates block's PoW and that the stored hash matches its content
+    pub fn check_hash(&self) -> Result<bool> {
+        let data = self.prepare_hash_data()?;
+        let mut hasher = Sha256::new();
+        sha2::Digest::update(&mut hasher,&data);
+        let hex_result = hasher.finalize().iter().map(|b| format!("{:02x}", b))
+            .collect::<Vec<String>>()
+            .join("");
+        Ok(hex_result == self.hash && self.validate()?)
     }
     
 }
@@ -1,17 +1,45 @@
+use crate::address::Address;
 use crate::block::Block;
+use crate::chainparams::{ChainParams, REGTEST_GENESIS_ADDRESS};
+use crate::coinselect::{CoinSelector, Selection};
 use crate::errors::Result;
 use log::{debug, info};
 use bincode::{deserialize, serialize};
-use crate::transaction::Transaction;
-use crate::tx::{TXInput,TXOutput,TXOutputs};
-use std::collections::HashMap;
+use crate::script::{Script, LOCKTIME_THRESHOLD};
+use crate::transaction::{SpendContext, Transaction};
+use crate::store;
+use crate::tx::TXOutput;
+use crate::wallet::Wallets;
+use ed25519_dalek::SigningKey;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
 use failure::format_err;
 
+/// STORE_VERSION is the layout of the blocks in the store, a store of another version is refused
+// 1: blocks are hashed over their merkle root
+// 2: blocks carry the target they were mined at
+// 3: scripts are opcode scripts instead of strings
+// 4: transactions carry a lock time and inputs a sequence
+const STORE_VERSION: u32 = 4;
+
+/// MEDIAN_TIME_SPAN is the number of blocks whose median timestamp time-based locks are checked against
+const MEDIAN_TIME_SPAN: usize = 11;
+
 #[derive(Debug,Clone)]
 pub struct Blockchain{
     // blocks: Vec<Block>
     current_hash: String,
     db: sled::Db,
+    params: ChainParams,
+}
+
+/// ChainCheck is the outcome of re-validating the stored chain
+#[derive(Debug, Clone, Serialize)]
+pub struct ChainCheck {
+    pub checked: usize,
+    pub first_bad_height: Option<i32>,
+    pub last_good_hash: Option<String>,
+    pub reason: Option<String>,
 }
 
 pub struct BlockchainIter<'a>{
@@ -22,42 +50,107 @@ pub struct BlockchainIter<'a>{
 
 impl Blockchain {
 
-    pub fn get_db(&self) -> &sled::Db {
-        &self.db
+    /// GetTipHash returns the hash of the latest block
+    pub fn get_tip_hash(&self) -> String {
+        self.current_hash.clone()
     }
 
 
-    pub fn new() -> Result<Blockchain> {
+    pub fn get_params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    pub fn new(params: &ChainParams) -> Result<Blockchain> {
         info!("open blockchain");
 
-        let db = sled::open("data/blocks")?;
+        let db = store::open(&params.blocks_path())?;
+        Blockchain::open(db, params)
+    }
+
+    /// Open reads the tip of an opened block database, a chain with a fixed
+    /// genesis block gets it written on first use
+    fn open(db: sled::Db, params: &ChainParams) -> Result<Blockchain> {
         let hash = match db.get("LAST")? {
             Some(l) => l.to_vec(),
             None => Vec::new(),
         };
         info!("Found block database");
+        match db.get("NETWORK")? {
+            Some(magic) => params.check_magic(&magic, "block store")?,
+            None => {
+                db.insert("NETWORK", &params.magic)?;
+            }
+        }
+        let version = db.get("VERSION")?.and_then(|v| Some(u32::from_le_bytes(v.as_ref().try_into().ok()?)));
+        match version {
+            Some(STORE_VERSION) => {}
+            None if hash.is_empty() => {
+                db.insert("VERSION", &STORE_VERSION.to_le_bytes())?;
+            }
+            _ => {
+                return Err(format_err!(
+                    "the block store in {} has format {}, this version reads format {}: move it away and create the chain again",
+                    params.blocks_path(),
+                    version.unwrap_or(0),
+                    STORE_VERSION
+                ))
+            }
+        }
+        if hash.is_empty() && params.genesis_timestamp.is_some() {
+            return Blockchain::init(db, String::from(REGTEST_GENESIS_ADDRESS), params);
+        }
         let lasthash = if hash.is_empty() {
             String::new()
         } else {
             String::from_utf8(hash.to_vec())?
         };
-        Ok(Blockchain { current_hash: lasthash, db })
+        Ok(Blockchain { current_hash: lasthash, db, params: params.clone() })
     }
 
 
-    pub fn create_blockchain(address: String) -> Result<Blockchain> {
+    /// CreateBlockchain starts a new chain whose genesis block pays an address, a chain with a
+    /// fixed genesis block takes no address since its reward always goes to REGTEST_GENESIS_ADDRESS
+    pub fn create_blockchain(address: Option<String>, params: &ChainParams) -> Result<Blockchain> {
         info!("Creating new blockchain");
+        let address = match (address, params.genesis_timestamp) {
+            (Some(address), None) => {
+                Address::parse(&address, params)?;
+                address
+            }
+            (None, Some(_)) => String::from(REGTEST_GENESIS_ADDRESS),
+            (None, None) => return Err(format_err!("the genesis block needs an address to pay")),
+            (Some(_), Some(_)) => {
+                return Err(format_err!(
+                    "the {} genesis block always pays {}, mine to your address with generate --to",
+                    params.network,
+                    REGTEST_GENESIS_ADDRESS
+                ))
+            }
+        };
 
-        std::fs::remove_dir_all("data/blocks").ok();
-        let db = sled::open("data/blocks")?;
+        store::close(&params.blocks_path())?;
+        std::fs::remove_dir_all(params.blocks_path()).ok();
+        let db = store::open(&params.blocks_path())?;
         debug!("Creating new block database");
-        let cbtx = Transaction::new_coinbase(address, String::from("GENESIS_COINBASE_DATA"))?;
-        let genesis: Block = Block::new_genesis_block(cbtx);
+        Blockchain::init(db, address, params)
+    }
+
+    fn init(db: sled::Db, address: String, params: &ChainParams) -> Result<Blockchain> {
+        let cbtx = Transaction::new_coinbase(
+            address,
+            String::from("GENESIS_COINBASE_DATA"),
+            params.subsidy,
+            params.coinbase_commitment(0, ""),
+        )?;
+        let genesis: Block = Block::new_genesis_block(cbtx, params);
+        db.insert("NETWORK", &params.magic)?;
+        db.insert("VERSION", &STORE_VERSION.to_le_bytes())?;
         db.insert(genesis.get_hash(), serialize(&genesis)?)?;
         db.insert("LAST", genesis.get_hash().as_bytes())?;
         let bc = Blockchain {
             current_hash: genesis.get_hash(),
             db,
+            params: params.clone(),
         };
         bc.db.flush()?;
         Ok(bc)
@@ -65,17 +158,82 @@ impl Blockchain {
 
 
     pub fn add_block_with_tx(&mut self, transactions: Vec<Transaction>) ->Result<()>{
-        let lasthash = self.db.get("LAST")?.unwrap();
+        let lasthash = match self.db.get("LAST")? {
+            Some(h) => h,
+            None => return Err(format_err!("No existing blockchain found, create one first")),
+        };
+        let mut spent = self.find_spent_outputs();
+        let context = self.next_spend_context()?;
+        let fees = self.block_fees(&transactions)?;
+        for (i, tx) in transactions.iter().enumerate() {
+            if tx.is_coinbase() && i != 0 {
+                return Err(format_err!("ERROR: Coinbase {} is not the first transaction", tx.id));
+            }
+            if !self.verify_transacton(tx)? {
+                return Err(format_err!("ERROR: Invalid transaction {}", tx.id));
+            }
+            tx.check_outputs()?;
+            self.check_tx_values(tx, fees)?;
+            tx.check_coinbase(context.height, &self.params)?;
+            self.check_tx_locks(tx, &context)?;
+            if tx.is_coinbase() {
+                continue;
+            }
+            for vin in &tx.vin {
+                if !spent.insert((vin.txid.clone(), vin.vout)) {
+                    return Err(format_err!(
+                        "ERROR: Transaction {} spends {}:{} which is already spent",
+                        tx.id, vin.txid, vin.vout
+                    ));
+                }
+            }
+        }
+        let height = self.get_best_height()? + 1;
 
-        let new_block = Block::new_block(transactions,String::from_utf8(lasthash.to_vec())?,4)?;
+        let prev_hash = String::from_utf8(lasthash.to_vec())?;
+        let prev_timestamp = self.get_block(&prev_hash)?.get_timestamp();
+        let new_block = Block::new_block(transactions,prev_hash,Some(prev_timestamp),height,&self.params)?;
         self.db.insert(new_block.get_hash(),bincode::serialize(&new_block)?)?;
-        self.db.insert("LAST",new_block.get_hash().as_bytes());
+        self.db.insert("LAST",new_block.get_hash().as_bytes())?;
+        self.current_hash = new_block.get_hash();
+        self.db.flush()?;
         Ok(())
     }
 
+    /// NextCoinbase creates the coinbase of the next block, paying the subsidy and the fees
+    /// of the transactions mined with it to an address
+    pub fn next_coinbase(&self, to: &str, data: &str, transactions: &[Transaction]) -> Result<Transaction> {
+        self.coinbase_paying(to, data, self.params.subsidy as i64 + self.block_fees(transactions)?)
+    }
+
+    /// FeeCoinbase creates the coinbase of a block the wallet commits itself, it mints no subsidy
+    /// and only pays the fees of the transactions back to an address
+    pub fn fee_coinbase(&self, to: &str, transactions: &[Transaction]) -> Result<Transaction> {
+        self.coinbase_paying(to, "", self.block_fees(transactions)?)
+    }
+
+    fn coinbase_paying(&self, to: &str, data: &str, reward: i64) -> Result<Transaction> {
+        let reward = i32::try_from(reward).map_err(|_| format_err!("ERROR: the block reward does not fit an amount"))?;
+        Transaction::new_coinbase(
+            to.to_string(),
+            data.to_string(),
+            reward,
+            self.params.coinbase_commitment(self.get_best_height()? + 1, &self.current_hash),
+        )
+    }
+
+    /// BlockFees returns what the transactions of a block leave to its miner, their inputs less their outputs
+    pub fn block_fees(&self, transactions: &[Transaction]) -> Result<i64> {
+        let mut fees = 0;
+        for tx in transactions.iter().filter(|tx| !tx.is_coinbase()) {
+            fees += tx.input_value(&self.get_prev_txs(tx)?)? - tx.output_value();
+        }
+        Ok(fees)
+    }
+
     pub fn add_block(&mut self, block: Block) ->Result<()>{
         let data = serialize(&block)?;
-        if let Some(_) = self.db.get(block.get_hash())? {
+        if self.db.get(block.get_hash())?.is_some() {
             return Ok(());
         }
 
@@ -93,11 +251,27 @@ impl Blockchain {
 
     // GetBlock finds a block by its hash and returns it
     pub fn get_block(&self, block_hash: &str) -> Result<Block> {
-        let data = self.db.get(block_hash)?.unwrap();
-        let block = deserialize(&data.to_vec())?;
+        let data = match self.db.get(block_hash)? {
+            Some(data) => data,
+            None => return Err(format_err!("Block is not found")),
+        };
+        let block = deserialize(&data)?;
         Ok(block)
     }
 
+    /// GetBlockByHeight finds a block on the main chain by its height
+    pub fn get_block_by_height(&self, height: i32) -> Result<Block> {
+        for b in self.iter() {
+            if b.get_height() == height {
+                return Ok(b);
+            }
+            if b.get_height() < height {
+                break;
+            }
+        }
+        Err(format_err!("Block is not found at height {}", height))
+    }
+
     /// GetBestHeight returns the height of the latest block
     pub fn get_best_height(&self) -> Result<i32> {
         let lasthash = if let Some(h) = self.db.get("LAST")? {
@@ -105,95 +279,125 @@ impl Blockchain {
         } else {
             return Ok(-1);
         };
-        let last_data = self.db.get(lasthash)?.unwrap();
-        let last_block: Block = deserialize(&last_data.to_vec())?;
+        let last_data = match self.db.get(&lasthash)? {
+            Some(data) => data,
+            None => return Err(format_err!("Tip block {} is missing", String::from_utf8_lossy(&lasthash))),
+        };
+        let last_block: Block = deserialize(&last_data)?;
         Ok(last_block.get_height())
     }
 
-    /// returns a list of transactions containing unspent outputs
-    pub fn find_unspent_transactions(&self,address: &str) -> Vec<Transaction> {
+    /// FindUnspentOutputs returns the unspent outputs locked to an address, newest first
+    pub fn find_unspent_outputs(&self, address: &str) -> Vec<(String, i32, TXOutput)> {
+        self.find_unspent_outputs_of(&[address.to_string()])
+    }
+
+    /// FindUnspentOutputsOf returns the unspent outputs locked to any of the addresses, newest first
+    pub fn find_unspent_outputs_of(&self, addresses: &[String]) -> Vec<(String, i32, TXOutput)> {
+        self.collect_unspent_outputs(addresses, false)
+    }
 
-        let mut spend_txos: HashMap<String, Vec<i32>> = HashMap::new();
-        let mut unspend_txs: Vec<Transaction> = Vec::new();
+    /// FindMatureOutputsOf returns the unspent outputs of the addresses a new block can spend,
+    /// leaving out coinbase outputs that have not matured yet
+    pub fn find_mature_outputs_of(&self, addresses: &[String]) -> Vec<(String, i32, TXOutput)> {
+        self.collect_unspent_outputs(addresses, true)
+    }
 
+    fn collect_unspent_outputs(&self, addresses: &[String], mature_only: bool) -> Vec<(String, i32, TXOutput)> {
+        let next_height = self.get_best_height().unwrap_or(-1) + 1;
+        let scripts: HashSet<Script> = addresses
+            .iter()
+            .filter_map(|address| address.parse::<Address>().ok())
+            .map(|address| Script::from_address(&address))
+            .collect();
+        if scripts.is_empty() {
+            return Vec::new();
+        }
+        let spent = self.find_spent_outputs();
+        let mut unspent = Vec::new();
         for block in self.iter() {
             for tx in block.get_transactions() {
-                for index in 0..tx.vout.len() {
-                    if let Some(ids) = spend_txos.get(&tx.id) {
-                        if ids.contains(&(index as i32)) {
-                            continue;
-                        }
-                    }
-
-                    if tx.vout[index].can_be_unlock_with(address) {
-                        unspend_txs.push(tx.to_owned());
-                    }
+                if mature_only && tx.is_coinbase() && !self.is_mature(block.get_height(), next_height) {
+                    continue;
                 }
-
-                if !tx.is_coinbase() {
-                    for i in &tx.vin {
-                        if i.can_unlock_output_with(address) { 
-                            match spend_txos.get_mut(&i.txid) {
-                                Some(v) => {
-                                    v.push(i.vout);
-                                }
-                                None => {
-                                    spend_txos.insert(i.txid.clone(), vec![i.vout]);
-                                }
-                            }
-                        }
+                for (index, out) in tx.vout.iter().enumerate() {
+                    let outpoint = (tx.id.clone(), index as i32);
+                    if scripts.contains(&out.script_pub_key) && !spent.contains(&outpoint) {
+                        unspent.push((outpoint.0, outpoint.1, out.clone()));
                     }
                 }
             }
         }
-        unspend_txs
+        unspent
     }
 
     /// FindUTXO finds and returns all unspent transaction outputs
+    #[allow(non_snake_case)]
     pub fn find_UTXO(&self,address: &str) -> Vec<TXOutput> {
-        let mut utxos = Vec::<TXOutput>::new();
-        let mut unspend_txs = self.find_unspent_transactions(address);
+        self.find_unspent_outputs(address)
+            .into_iter()
+            .map(|(_, _, out)| out)
+            .collect()
+    }
+
+    /// FindSpendableOutputs selects unspent outputs of an address covering amount plus the input fees
+    pub fn find_spendable_outputs(&self, address: &str, amount: i32, selector: &CoinSelector) -> Result<Selection> {
+        selector.select(self.find_mature_outputs_of(&[address.to_string()]), amount)
+    }
 
-        println!("{:?}",unspend_txs);
+    /// IsMature tells whether a coinbase mined at a height can be spent in a block at spend_height.
+    /// There is no mempool, every command mines its transactions into a block at once, so the rule
+    /// is enforced where blocks are validated and coin selection only offers mature outputs
+    pub fn is_mature(&self, coinbase_height: i32, spend_height: i32) -> bool {
+        coinbase_height < self.params.coinbase_maturity_height
+            || spend_height - coinbase_height >= self.params.coinbase_maturity
+    }
 
-        for tx in unspend_txs {
-            for out in &tx.vout {
-                if out.can_be_unlock_with(&address) {
-                    utxos.push(out.clone());
+    /// FindSpentOutputs returns the outputs spent by the inputs on the chain
+    pub fn find_spent_outputs(&self) -> HashSet<(String, i32)> {
+        let mut spent = HashSet::new();
+        for block in self.iter() {
+            for tx in block.get_transactions() {
+                if !tx.is_coinbase() {
+                    for vin in &tx.vin {
+                        spent.insert((vin.txid.clone(), vin.vout));
+                    }
                 }
             }
         }
-
-        utxos
+        spent
     }
 
-    pub fn find_spendable_outputs(&self,address: &str,amount: i32) -> (i32,HashMap<String,Vec<i32>>) {
-        let mut unspent_outputs: HashMap<String,Vec<i32>> = HashMap::new();
-        let mut accumulated = 0;
-        let mut unspend_txs = self.find_unspent_transactions(address);
-
-        for tx in unspend_txs {
-            for index in 0..tx.vout.len() {
-                if tx.vout[index].can_be_unlock_with(address) && accumulated< amount {
-                    match unspent_outputs.get_mut(&tx.id) {
-                        Some(v) => {
-                            v.push(index as i32);
-                        }
-                        None => {
-                            unspent_outputs.insert(tx.id.clone(), vec![index as i32]);
-                        }
-                    }
-                    accumulated += tx.vout[index].value;
-                    
-                    if accumulated >= amount {
-                        return (accumulated, unspent_outputs);
+    /// FindUsedAddresses returns every address an output on the chain pays to
+    pub fn find_used_addresses(&self) -> HashSet<String> {
+        let mut used = HashSet::new();
+        for block in self.iter() {
+            for tx in block.get_transactions() {
+                for out in &tx.vout {
+                    if let Some(address) = out.address(&self.params) {
+                        used.insert(address);
                     }
                 }
             }
         }
-        (accumulated, unspent_outputs)
+        used
     }
 
+    /// FindData returns the earliest block and transaction with an OP_RETURN output carrying some data
+    pub fn find_data(&self, data: &[u8]) -> Option<(Block, Transaction)> {
+        let mut found = None;
+        for block in self.iter() {
+            let tx = block
+                .get_transactions()
+                .iter()
+                .find(|tx| tx.vout.iter().any(|out| out.data().as_deref() == Some(data)))
+                .cloned();
+            if let Some(tx) = tx {
+                found = Some((block, tx));
+            }
+        }
+        found
+    }
 
     /// FindTransaction finds a transaction by its ID
     pub fn find_transacton(&self, id: &str) -> Result<Transaction> {
@@ -207,15 +411,394 @@ impl Blockchain {
         Err(format_err!("Transaction is not found"))
     }
 
-    pub fn iter(&self) -> BlockchainIter {
+    /// FindTransactionBlock finds the block on the main chain containing a transaction
+    pub fn find_transaction_block(&self, id: &str) -> Result<Block> {
+        for b in self.iter() {
+            if b.get_transactions().iter().any(|tx| tx.id == id) {
+                return Ok(b);
+            }
+        }
+        Err(format_err!("Transaction is not found"))
+    }
+
+    fn get_prev_txs(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
+        let mut prev_txs = HashMap::new();
+        for vin in &tx.vin {
+            if tx.is_coinbase() {
+                break;
+            }
+            let prev_tx = self.find_transacton(&vin.txid)?;
+            prev_txs.insert(prev_tx.id.clone(), prev_tx);
+        }
+        Ok(prev_txs)
+    }
+
+    /// SignWithWallet signs every input of a transaction with the wallet key of the address it spends
+    pub fn sign_with_wallet(&self, tx: &mut Transaction, wallets: &Wallets) -> Result<()> {
+        let prev_txs = self.get_prev_txs(tx)?;
+        tx.sign_with_wallet(wallets, &prev_txs)
+    }
+
+    /// SignTransaction signs the inputs of a transaction
+    pub fn sign_transacton(&self, tx: &mut Transaction, key: &SigningKey) -> Result<()> {
+        let prev_txs = self.get_prev_txs(tx)?;
+        tx.sign(key, &prev_txs)
+    }
+
+    /// VerifyTransaction checks the inputs of a transaction against the chain
+    pub fn verify_transacton(&self, tx: &Transaction) -> Result<bool> {
+        let prev_txs = self.get_prev_txs(tx)?;
+        tx.verify(&prev_txs)
+    }
+
+    /// MedianTimePast returns the median timestamp of the 11 blocks ending at a block,
+    /// 0 before the genesis block
+    pub fn median_time_past(&self, block_hash: &str) -> Result<u128> {
+        let mut times = Vec::new();
+        let mut hash = block_hash.to_string();
+        while !hash.is_empty() && times.len() < MEDIAN_TIME_SPAN {
+            let block = self.get_block(&hash)?;
+            times.push(block.get_timestamp());
+            hash = block.get_prev_hash();
+        }
+        times.sort();
+        Ok(times.get(times.len() / 2).copied().unwrap_or(0))
+    }
+
+    /// NextSpendContext returns the context of the block that would be mined next
+    pub fn next_spend_context(&self) -> Result<SpendContext> {
+        Ok(SpendContext {
+            height: self.get_best_height()? + 1,
+            median_time: self.median_time_past(&self.current_hash)?,
+        })
+    }
+
+    /// CheckTxValues fails when a transaction pays out more than its inputs hold,
+    /// or a coinbase more than the subsidy and the fees of its block
+    pub fn check_tx_values(&self, tx: &Transaction, fees: i64) -> Result<()> {
+        let out_value = tx.output_value();
+        if tx.is_coinbase() {
+            if out_value > self.params.subsidy as i64 + fees {
+                return Err(format_err!(
+                    "ERROR: Coinbase {} pays {}, more than the subsidy of {} and fees of {}",
+                    tx.id, out_value, self.params.subsidy, fees
+                ));
+            }
+            return Ok(());
+        }
+        let in_value = tx.input_value(&self.get_prev_txs(tx)?)?;
+        if in_value < out_value {
+            return Err(format_err!(
+                "ERROR: Transaction {} spends {} but pays out {}",
+                tx.id, in_value, out_value
+            ));
+        }
+        Ok(())
+    }
+
+    /// CheckTxLocks fails when a transaction may not be in a block at a context yet,
+    /// because of its lock time, the relative lock of an input or a coinbase it spends being immature
+    pub fn check_tx_locks(&self, tx: &Transaction, context: &SpendContext) -> Result<()> {
+        if !tx.is_final(context) {
+            return Err(format_err!(
+                "ERROR: Transaction {} is locked until {}",
+                tx.id,
+                describe_lock_time(tx.lock_time)
+            ));
+        }
+        if tx.is_coinbase() {
+            return Ok(());
+        }
+        let mut coins = Vec::new();
+        for vin in &tx.vin {
+            let block = self.find_transaction_block(&vin.txid)?;
+            let spends_coinbase = block.get_transactions().iter().any(|prev| prev.id == vin.txid && prev.is_coinbase());
+            if spends_coinbase && !self.is_mature(block.get_height(), context.height) {
+                return Err(format_err!(
+                    "ERROR: Transaction {} spends coinbase {} before it matures at height {}",
+                    tx.id,
+                    vin.txid,
+                    block.get_height() + self.params.coinbase_maturity
+                ));
+            }
+            coins.push(SpendContext {
+                height: block.get_height(),
+                median_time: self.median_time_past(&block.get_prev_hash())?,
+            });
+        }
+        if !tx.sequence_locks_reached(&coins, context) {
+            return Err(format_err!(
+                "ERROR: Transaction {} spends an output before its relative lock ends",
+                tx.id
+            ));
+        }
+        Ok(())
+    }
+
+    /// VerifyChain re-validates the last `depth` blocks (all when None) up to `level`:
+    /// 0 block linkage, 1 proof of work over the merkle root, 2 transaction ids,
+    /// 3 input unlocking, 4 replay of unspent outputs
+    pub fn verify_chain(&self, depth: Option<usize>, level: u8) -> Result<ChainCheck> {
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut failure: Option<(Option<i32>, String)> = None;
+        let mut hash = self.current_hash.clone();
+        // unknown when the tip itself cannot be read
+        let mut height = self.get_best_height().ok();
+
+        while !hash.is_empty() && depth.is_none_or(|d| blocks.len() < d) {
+            match self.get_block(&hash) {
+                Ok(b) if b.get_hash() == hash => {
+                    hash = b.get_prev_hash();
+                    height = Some(b.get_height() - 1);
+                    blocks.push(b);
+                }
+                Ok(_) => {
+                    failure = Some((height, format!("block {} is stored under the wrong key", hash)));
+                    blocks.clear();
+                    break;
+                }
+                Err(e) => {
+                    failure = Some((height, format!("block {} cannot be read: {}", hash, e)));
+                    blocks.clear();
+                    break;
+                }
+            }
+        }
+        blocks.reverse();
+
+        let mut utxos: HashMap<(String, i32), TXOutput> = HashMap::new();
+        if level >= 4 && failure.is_none() {
+            if let Some(first) = blocks.first() {
+                let mut older: Vec<Block> = self.iter()
+                    .skip_while(|b| b.get_hash() != first.get_hash())
+                    .skip(1)
+                    .collect();
+                older.reverse();
+                for b in &older {
+                    apply_utxos(&mut utxos, b, self.params.subsidy, false).ok();
+                }
+            }
+        }
+
+        let mut prev: Option<Block> = match blocks.first() {
+            Some(b) if !b.get_prev_hash().is_empty() => self.get_block(&b.get_prev_hash()).ok(),
+            _ => None,
+        };
+        let mut checked = 0;
+        let mut last_good: Option<Block> = None;
+        if failure.is_none() {
+            for block in &blocks {
+                if let Err(reason) = self.check_block(block, prev.as_ref(), level, &mut utxos) {
+                    failure = Some((Some(block.get_height()), reason));
+                    last_good = prev.clone();
+                    break;
+                }
+                checked += 1;
+                prev = Some(block.clone());
+            }
+        }
+
+        Ok(match failure {
+            None => ChainCheck {
+                checked,
+                first_bad_height: None,
+                last_good_hash: Some(self.current_hash.clone()),
+                reason: None,
+            },
+            Some((height, reason)) => {
+                // a block that cannot be read hides its parent, find the best intact block instead
+                let last_good = match (last_good, height) {
+                    (Some(b), _) => Some(b),
+                    (None, Some(height)) => self.find_intact_block(height),
+                    (None, None) => self.find_intact_block(i32::MAX),
+                };
+                ChainCheck {
+                    checked,
+                    first_bad_height: height.or(last_good.as_ref().map(|b| b.get_height() + 1)),
+                    last_good_hash: last_good.map(|b| b.get_hash()),
+                    reason: Some(reason),
+                }
+            }
+        })
+    }
+
+    /// FindIntactBlock returns the highest stored block below a height whose ancestors can all be read
+    fn find_intact_block(&self, below: i32) -> Option<Block> {
+        let mut candidates: Vec<Block> = self
+            .db
+            .iter()
+            .filter_map(|item| item.ok())
+            .filter_map(|(key, data)| {
+                let block: Block = deserialize(&data).ok()?;
+                (block.get_hash().as_bytes() == &key[..] && block.get_height() < below).then_some(block)
+            })
+            .collect();
+        candidates.sort_by_key(|b| -b.get_height());
+        candidates.into_iter().find(|b| {
+            let mut hash = b.get_prev_hash();
+            let mut height = b.get_height();
+            while !hash.is_empty() {
+                match self.get_block(&hash) {
+                    Ok(parent) if parent.get_hash() == hash && parent.get_height() + 1 == height => {
+                        hash = parent.get_prev_hash();
+                        height = parent.get_height();
+                    }
+                    _ => return false,
+                }
+            }
+            height == 0
+        })
+    }
+
+    fn check_block(
+        &self,
+        block: &Block,
+        prev: Option<&Block>,
+        level: u8,
+        utxos: &mut HashMap<(String, i32), TXOutput>,
+    ) -> std::result::Result<(), String> {
+        match prev {
+            Some(p) if p.get_hash() != block.get_prev_hash() => {
+                return Err(String::from("previous block hash does not link"));
+            }
+            Some(p) if p.get_height() + 1 != block.get_height() => {
+                return Err(String::from("block height does not follow its parent"));
+            }
+            None if block.get_height() != 0 || !block.get_prev_hash().is_empty() => {
+                return Err(String::from("parent block is missing"));
+            }
+            _ => {}
+        }
+
+        if level >= 1 && block.get_target_hexs() != self.params.target_hexs {
+            return Err(String::from("block difficulty does not match the chain"));
+        }
+        if level >= 1 && !block.check_hash().map_err(|e| e.to_string())? {
+            return Err(String::from("proof of work or block hash is invalid"));
+        }
+
+        if level >= 2 {
+            for (i, tx) in block.get_transactions().iter().enumerate() {
+                if tx.compute_id().map_err(|e| e.to_string())? != tx.id {
+                    return Err(format!("transaction {} has a wrong id", tx.id));
+                }
+                if tx.is_coinbase() && i != 0 {
+                    return Err(format!("coinbase {} is not the first transaction", tx.id));
+                }
+                tx.check_outputs().map_err(|e| e.to_string())?;
+                tx.check_coinbase(block.get_height(), &self.params).map_err(|e| e.to_string())?;
+            }
+        }
+
+        if level >= 3 {
+            let context = SpendContext {
+                height: block.get_height(),
+                median_time: self.median_time_past(&block.get_prev_hash()).map_err(|e| e.to_string())?,
+            };
+            for tx in block.get_transactions() {
+                if !self.verify_transacton(tx).map_err(|e| e.to_string())? {
+                    return Err(format!("transaction {} has an invalid input", tx.id));
+                }
+                self.check_tx_locks(tx, &context).map_err(|e| e.to_string())?;
+            }
+        }
+
+        if level >= 4 {
+            apply_utxos(utxos, block, self.params.subsidy, true)?;
+        }
+        Ok(())
+    }
+
+    /// ValidateBlock fully checks a block received from outside before it is added to the chain,
+    /// against the unspent outputs of its parent which it then spends and adds to
+    pub fn validate_block(&self, block: &Block, utxos: &mut HashMap<(String, i32), TXOutput>) -> Result<()> {
+        let prev = if block.get_prev_hash().is_empty() {
+            None
+        } else {
+            match self.get_block(&block.get_prev_hash()) {
+                Ok(b) => Some(b),
+                Err(_) => return Err(format_err!("Parent of block {} is not found", block.get_hash())),
+            }
+        };
+        if let Err(reason) = self.check_block(block, prev.as_ref(), 4, utxos) {
+            return Err(format_err!("Block {} is invalid: {}", block.get_hash(), reason));
+        }
+        Ok(())
+    }
+
+    /// RollbackTo moves the tip of the chain back to an existing block
+    pub fn rollback_to(&mut self, block_hash: &str) -> Result<()> {
+        let block = self.get_block(block_hash)?;
+        self.db.insert("LAST", block.get_hash().as_bytes())?;
+        self.current_hash = block.get_hash();
+        self.db.flush()?;
+        Ok(())
+    }
+
+    pub fn iter(&self) -> BlockchainIter<'_> {
         BlockchainIter{
             current_hash: self.current_hash.clone(),
-            bc: &self, 
+            bc: self,
         }
     }
 
 }
 
+/// describe_lock_time writes a lock time as the height or unix time it stands for
+pub fn describe_lock_time(lock_time: u32) -> String {
+    if (lock_time as i64) < LOCKTIME_THRESHOLD {
+        format!("height {}", lock_time)
+    } else {
+        format!("unix time {}", lock_time)
+    }
+}
+
+/// apply_utxos spends the inputs and adds the outputs of a block to an unspent output set
+pub fn apply_utxos(
+    utxos: &mut HashMap<(String, i32), TXOutput>,
+    block: &Block,
+    subsidy: i32,
+    check: bool,
+) -> std::result::Result<(), String> {
+    // the coinbase comes first but is checked once the fees of the block are known
+    let mut coinbase: Option<(&str, i64)> = None;
+    let mut fees: i64 = 0;
+    for tx in block.get_transactions() {
+        let out_value = tx.output_value();
+        if tx.is_coinbase() {
+            coinbase = Some((&tx.id, out_value));
+        } else {
+            let mut in_value: i64 = 0;
+            for vin in &tx.vin {
+                match utxos.remove(&(vin.txid.clone(), vin.vout)) {
+                    Some(out) => in_value += out.value as i64,
+                    None if check => {
+                        return Err(format!(
+                            "transaction {} spends missing or spent output {}:{}",
+                            tx.id, vin.txid, vin.vout
+                        ));
+                    }
+                    None => {}
+                }
+            }
+            if check && in_value < out_value {
+                return Err(format!("transaction {} spends more than its inputs", tx.id));
+            }
+            fees += in_value - out_value;
+        }
+        for (index, out) in tx.vout.iter().enumerate() {
+            if !out.script_pub_key.is_unspendable() {
+                utxos.insert((tx.id.clone(), index as i32), out.clone());
+            }
+        }
+    }
+    match coinbase {
+        Some((id, value)) if check && value > subsidy as i64 + fees => {
+            Err(format!("coinbase {} pays more than the subsidy and the fees", id))
+        }
+        _ => Ok(()),
+    }
+}
 
 impl<'a>  Iterator for BlockchainIter<'a> {
     type Item = Block;
@@ -238,12 +821,74 @@ impl<'a>  Iterator for BlockchainIter<'a> {
     }
     
 }
+
+/// Fixture is a temporary data dir, removed when it drops, with a wallet and an in-memory
+/// chain whose genesis coinbase pays alice, a fresh address of that wallet
+#[cfg(test)]
+pub(crate) struct Fixture {
+    pub params: ChainParams,
+    pub ws: Wallets,
+    pub bc: Blockchain,
+    pub alice: String,
+}
+
+#[cfg(test)]
+impl Fixture {
+    /// New creates a fixture with the consensus rules of a network
+    pub fn new(network: crate::chainparams::Network) -> Fixture {
+        Fixture::with_params(ChainParams::temporary(network))
+    }
+
+    /// WithParams creates a fixture for params made from ChainParams::temporary
+    pub fn with_params(params: ChainParams) -> Fixture {
+        let mut ws = Wallets::new(&params).unwrap();
+        let alice = ws.create_wallet().unwrap();
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let bc = Blockchain::init(db, alice.clone(), &params).unwrap();
+        Fixture { params, ws, bc, alice }
+    }
+
+    /// Mine adds a block holding only a coinbase paying an address
+    pub fn mine(&mut self, to: &str) {
+        let cbtx = self.bc.next_coinbase(to, "", &[]).unwrap();
+        self.bc.add_block_with_tx(vec![cbtx]).unwrap();
+    }
+
+    /// Wallets opens another, empty wallet store of a network under the data dir
+    pub fn wallets(&self, network: crate::chainparams::Network) -> Wallets {
+        let other = ChainParams::temporary(network);
+        let name = std::path::Path::new(&other.data_dir).file_name().unwrap().to_string_lossy();
+        let data_dir = format!("{}/{}", self.params.data_dir, name);
+        Wallets::new(&ChainParams { data_dir, ..other }).unwrap()
+    }
+
+    /// GenesisOnly opens another in-memory chain holding just the genesis block of this one,
+    /// only a network with a fixed genesis timestamp mines the same block again
+    pub fn genesis_only(&self) -> Blockchain {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        Blockchain::init(db, self.alice.clone(), &self.params).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        store::close(&self.params.data_dir).ok();
+        std::fs::remove_dir_all(&self.params.data_dir).ok();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::chainparams::{Network, COINBASE_MATURITY, MOCK_BLOCK_INTERVAL, REGTEST_GENESIS_TIMESTAMP};
+    use crate::script::MAX_NULL_DATA_SIZE;
+    use crate::tx::{TXInput, SEQUENCE_FINAL};
+    use crate::transaction::{relative_blocks, Locks};
     #[test]
     fn test_blockchain(){
-        let mut b = Blockchain::new().unwrap();
+        let f = Fixture::new(Network::Main);
+        let b = Blockchain::new(&f.params).unwrap();
 
         // b.add_block("data1".to_string());
         // b.add_block("data2".to_string());
@@ -255,4 +900,417 @@ mod tests {
         }
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_regtest_is_deterministic() {
+        let (f, g) = (Fixture::new(Network::Regtest), Fixture::new(Network::Regtest));
+        let mut a = Blockchain::create_blockchain(None, &f.params).unwrap();
+        let mut b = Blockchain::create_blockchain(None, &g.params).unwrap();
+        assert_eq!(a.get_tip_hash(), b.get_tip_hash());
+        assert_eq!(a.get_tip_hash(), "add1421ebab3a10bd69ce7beb42fb27c8dec8f696ae8eff176be58c4cd17da0c");
+
+        for bc in [&mut a, &mut b] {
+            for height in 1..=3 {
+                let cbtx = bc.next_coinbase(REGTEST_GENESIS_ADDRESS, &format!("regtest block {}", height), &[]).unwrap();
+                bc.add_block_with_tx(vec![cbtx]).unwrap();
+            }
+        }
+        assert_eq!(a.get_best_height().unwrap(), 3);
+        assert_eq!(a.get_tip_hash(), b.get_tip_hash());
+        // the mock clock still moves, so the median time past does too
+        let tip = a.get_block_by_height(3).unwrap();
+        assert_eq!(tip.get_timestamp(), REGTEST_GENESIS_TIMESTAMP + 3 * MOCK_BLOCK_INTERVAL);
+        assert!(a.median_time_past(&a.get_tip_hash()).unwrap() > REGTEST_GENESIS_TIMESTAMP);
+        assert_eq!(a.verify_chain(None, 4).unwrap().first_bad_height, None);
+    }
+
+    #[test]
+    fn test_create_blockchain() {
+        let f = Fixture::new(Network::Regtest);
+        let mine = Address::new(f.params.address_version, vec![1; 20]).to_string();
+        let err = Blockchain::create_blockchain(Some(mine.clone()), &f.params).unwrap_err();
+        assert!(err.to_string().contains("generate --to"));
+        let bc = Blockchain::create_blockchain(None, &f.params).unwrap();
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        assert_eq!(bc.get_tip_hash(), Blockchain::open(db, &f.params).unwrap().get_tip_hash());
+        assert!(Blockchain::create_blockchain(None, &ChainParams::temporary(Network::Main)).is_err());
+    }
+
+    #[test]
+    fn test_refuse_other_network() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        Blockchain::open(db.clone(), &ChainParams::regtest()).unwrap();
+        assert!(Blockchain::open(db.clone(), &ChainParams::testnet()).is_err());
+        assert!(Blockchain::open(db, &ChainParams::regtest()).is_ok());
+    }
+
+    #[test]
+    fn test_refuse_other_format() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        Blockchain::open(db.clone(), &ChainParams::regtest()).unwrap();
+        db.insert("VERSION", &(STORE_VERSION + 1).to_le_bytes()).unwrap();
+        let err = Blockchain::open(db.clone(), &ChainParams::regtest()).unwrap_err();
+        assert!(err.to_string().contains(&format!("has format {}, this version reads format {}", STORE_VERSION + 1, STORE_VERSION)));
+
+        // a chain stored before the version key was written
+        db.remove("VERSION").unwrap();
+        assert!(Blockchain::open(db.clone(), &ChainParams::regtest()).unwrap_err().to_string().contains("has format 0"));
+        db.insert("VERSION", &STORE_VERSION.to_le_bytes()).unwrap();
+        assert!(Blockchain::open(db, &ChainParams::regtest()).is_ok());
+    }
+
+    #[test]
+    fn test_verify_chain() {
+        let mut f = Fixture::new(Network::Main);
+        let alice = f.alice.clone();
+        let bob = Address::new(0x00, vec![2; 20]).to_string();
+        let tx = Transaction::new_UTXO(&alice, &bob, 30, Locks::default(), &CoinSelector::default(), &mut f.ws, &f.bc).unwrap();
+        f.bc.add_block_with_tx(vec![tx.clone()]).unwrap();
+        assert!(f.bc.add_block_with_tx(vec![tx]).is_err());
+        let check = f.bc.verify_chain(None, 4).unwrap();
+        assert_eq!(check.checked, 2);
+        assert_eq!(check.first_bad_height, None);
+
+        // tamper with the stored transfer without re-mining the block
+        let tip: Block = f.bc.get_block(&f.bc.get_tip_hash()).unwrap();
+        let mut data = serialize(&tip).unwrap();
+        let pos = data.windows(20).position(|w| w == [2; 20]).unwrap();
+        data[pos..pos + 20].copy_from_slice(&[3; 20]);
+        f.bc.db.insert(tip.get_hash(), data).unwrap();
+
+        let check = f.bc.verify_chain(None, 0).unwrap();
+        assert_eq!(check.first_bad_height, None);
+        let genesis = f.bc.get_block_by_height(0).unwrap().get_hash();
+        let check = f.bc.verify_chain(Some(1), 1).unwrap();
+        assert_eq!(check.first_bad_height, Some(1));
+        assert_eq!(check.last_good_hash.as_deref(), Some(genesis.as_str()));
+
+        // a tip torn by a crash cannot be read, the rollback is still offered
+        f.bc.db.insert(tip.get_hash(), vec![0xff; 8]).unwrap();
+        assert!(f.bc.get_block_by_height(0).is_err());
+        let check = f.bc.verify_chain(None, 3).unwrap();
+        assert_eq!(check.first_bad_height, Some(1));
+        assert_eq!(check.last_good_hash.as_deref(), Some(genesis.as_str()));
+        f.bc.rollback_to(&check.last_good_hash.unwrap()).unwrap();
+        assert_eq!(f.bc.get_best_height().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_reject_bad_values() {
+        let mut f = Fixture::new(Network::Main);
+        let alice = f.alice.clone();
+        let bob: Address = Address::new(0x00, vec![2; 20]);
+        let key = f.ws.signing_key(&alice).unwrap();
+        let genesis = f.bc.find_unspent_outputs(&alice).remove(0).0;
+        let spend = |bc: &Blockchain, vout: Vec<TXOutput>| {
+            let mut tx = Transaction {
+                id: String::new(),
+                vin: vec![TXInput { txid: genesis.clone(), vout: 0, script_sig: Script::new(), sequence: SEQUENCE_FINAL }],
+                vout,
+                lock_time: 0,
+            };
+            bc.sign_transacton(&mut tx, &key).unwrap();
+            tx
+        };
+
+        // a signed raw transaction minting coins out of nothing
+        let inflating = spend(&f.bc, vec![TXOutput::new(1_000_000, &bob)]);
+        let err = f.bc.add_block_with_tx(vec![inflating]).unwrap_err();
+        assert!(err.to_string().contains("spends 100 but pays out 1000000"));
+        let zero = spend(&f.bc, vec![TXOutput::new(0, &bob), TXOutput::new(100, &bob)]);
+        assert!(f.bc.add_block_with_tx(vec![zero]).is_err());
+        let negative = spend(&f.bc, vec![TXOutput::new(-50, &bob), TXOutput::new(150, &bob)]);
+        assert!(f.bc.add_block_with_tx(vec![negative]).is_err());
+
+        let mut empty = Transaction { id: String::new(), vin: Vec::new(), vout: vec![TXOutput::new(5, &bob)], lock_time: 0 };
+        empty.set_id().unwrap();
+        assert!(f.bc.add_block_with_tx(vec![empty]).is_err());
+
+        // a hand-made coinbase committing to the right height is still bound by the subsidy
+        let greedy = Transaction::new_coinbase(alice.clone(), String::new(), f.params.subsidy + 1, Some((1, 7))).unwrap();
+        let err = f.bc.add_block_with_tx(vec![greedy]).unwrap_err();
+        assert!(err.to_string().contains("more than the subsidy"));
+        let (first, second) = (f.bc.next_coinbase(&alice, "a", &[]).unwrap(), f.bc.next_coinbase(&alice, "b", &[]).unwrap());
+        let err = f.bc.add_block_with_tx(vec![first, second]).unwrap_err();
+        assert!(err.to_string().contains("not the first"));
+
+        f.bc.add_block_with_tx(vec![spend(&f.bc, vec![TXOutput::new(100, &bob)])]).unwrap();
+        assert_eq!(f.bc.get_best_height().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_fees_go_to_the_coinbase() {
+        let mut f = Fixture::new(Network::Main);
+        let alice = f.alice.clone();
+        let key = f.ws.signing_key(&alice).unwrap();
+        let genesis = f.bc.find_unspent_outputs(&alice).remove(0).0;
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput { txid: genesis, vout: 0, script_sig: Script::new(), sequence: SEQUENCE_FINAL }],
+            vout: vec![TXOutput::new(90, &Address::new(0x00, vec![2; 20]))],
+            lock_time: 0,
+        };
+        f.bc.sign_transacton(&mut tx, &key).unwrap();
+        assert_eq!(f.bc.block_fees(std::slice::from_ref(&tx)).unwrap(), 10);
+
+        // the miner may take the subsidy and the fees, not more
+        let greedy = Transaction::new_coinbase(alice.clone(), String::new(), f.params.subsidy + 11, Some((1, 7))).unwrap();
+        let err = f.bc.add_block_with_tx(vec![greedy, tx.clone()]).unwrap_err();
+        assert!(err.to_string().contains("more than the subsidy"));
+        let cbtx = f.bc.next_coinbase(&alice, "", std::slice::from_ref(&tx)).unwrap();
+        assert_eq!(cbtx.output_value(), f.params.subsidy as i64 + 10);
+        f.bc.add_block_with_tx(vec![cbtx, tx]).unwrap();
+        assert_eq!(f.bc.verify_chain(None, 4).unwrap().first_bad_height, None);
+    }
+
+    #[test]
+    fn test_lock_times() {
+        let mut f = Fixture::new(Network::Regtest);
+        let alice = f.alice.clone();
+        let bob = f.ws.create_wallet().unwrap();
+
+        // not before the block after height 2
+        let locked = Transaction::new_UTXO(&alice, &bob, 10, Locks::new(2, None), &CoinSelector::default(), &mut f.ws, &f.bc).unwrap();
+        assert!(f.bc.add_block_with_tx(vec![locked.clone()]).is_err());
+        f.mine(&bob);
+        assert!(f.bc.add_block_with_tx(vec![locked.clone()]).is_err());
+        f.mine(&bob);
+        let change = locked.vout[1].address(&f.params).unwrap();
+        f.bc.add_block_with_tx(vec![locked]).unwrap();
+
+        // the change confirmed at height 3 can be spent 2 blocks later
+        let relative = Locks::new(0, Some(relative_blocks(2)));
+        let aged = Transaction::new_UTXO(&change, &bob, 10, relative, &CoinSelector::default(), &mut f.ws, &f.bc).unwrap();
+        assert!(f.bc.add_block_with_tx(vec![aged.clone()]).is_err());
+        f.mine(&bob);
+        f.bc.add_block_with_tx(vec![aged]).unwrap();
+        assert_eq!(f.bc.verify_chain(None, 4).unwrap().first_bad_height, None);
+    }
+
+    #[test]
+    fn test_data_outputs() {
+        let mut f = Fixture::new(Network::Regtest);
+        let alice = f.alice.clone();
+        let key = f.ws.signing_key(&alice).unwrap();
+
+        assert!(Transaction::new_data(&alice, &[7; MAX_NULL_DATA_SIZE + 1], &f.bc).is_err());
+        let mut tx = Transaction::new_data(&alice, &[7; 32], &f.bc).unwrap();
+        f.bc.sign_transacton(&mut tx, &key).unwrap();
+        f.bc.add_block_with_tx(vec![tx.clone()]).unwrap();
+        let first = f.bc.get_tip_hash();
+
+        // the same digest anchored again still reports the earliest block
+        let mut again = Transaction::new_data(&alice, &[7; 32], &f.bc).unwrap();
+        f.bc.sign_transacton(&mut again, &key).unwrap();
+        f.bc.add_block_with_tx(vec![again]).unwrap();
+        let (block, found) = f.bc.find_data(&[7; 32]).unwrap();
+        assert_eq!(block.get_hash(), first);
+        assert_eq!(found.id, tx.id);
+        assert!(f.bc.find_data(&[8; 32]).is_none());
+
+        // the data output is never spendable and the value stays with alice
+        assert!(tx.vout[1].script_pub_key.is_unspendable());
+        assert_eq!(f.bc.find_UTXO(&alice).iter().map(|o| o.value).sum::<i32>(), f.params.subsidy);
+        let mut utxos = HashMap::new();
+        for height in 0..=2 {
+            apply_utxos(&mut utxos, &f.bc.get_block_by_height(height).unwrap(), f.params.subsidy, true).unwrap();
+        }
+        assert!(utxos.values().all(|out| out.data().is_none()));
+        assert_eq!(f.bc.verify_chain(None, 4).unwrap().first_bad_height, None);
+    }
+
+    #[test]
+    fn test_send_many() {
+        let mut f = Fixture::new(Network::Regtest);
+        let alice = f.alice.clone();
+        let bob = f.ws.create_wallet().unwrap();
+        let carol = f.ws.create_wallet().unwrap();
+
+        let selector = CoinSelector::default();
+        let recipients = vec![(bob.clone(), 30), (carol.clone(), 20), (bob.clone(), 5)];
+        let tx = Transaction::new_many(&alice, &recipients, Locks::default(), &selector, &mut f.ws, &f.bc).unwrap();
+        assert_eq!(tx.vout.len(), 4);
+        let change = tx.vout[3].address(&f.params).unwrap();
+        assert!(f.ws.is_change(&change));
+        f.bc.add_block_with_tx(vec![tx]).unwrap();
+        let balance = |address: &str| f.bc.find_UTXO(address).iter().map(|out| out.value).sum::<i32>();
+        assert_eq!((balance(&alice), balance(&change), balance(&bob), balance(&carol)), (0, 45, 35, 20));
+
+        let too_much = vec![(bob.clone(), 40), (carol.clone(), 10)];
+        assert!(Transaction::new_many(&change, &too_much, Locks::default(), &selector, &mut f.ws, &f.bc).is_err());
+        let negative = vec![(bob.clone(), 40), (carol.clone(), -10)];
+        assert!(Transaction::new_many(&change, &negative, Locks::default(), &selector, &mut f.ws, &f.bc).is_err());
+        assert!(Transaction::new_many(&change, &[], Locks::default(), &selector, &mut f.ws, &f.bc).is_err());
+
+        // an exact payment needs no change address
+        let addresses = f.ws.get_all_addresses().len();
+        let exact = Transaction::new_many(&change, &[(bob.clone(), 45)], Locks::default(), &selector, &mut f.ws, &f.bc).unwrap();
+        assert_eq!(exact.vout.len(), 1);
+        assert_eq!(f.ws.get_all_addresses().len(), addresses);
+
+        // createrawtx may run on a watching wallet, its change goes back to from unless given
+        let unsigned = Transaction::new_unsigned(&change, &carol, 5, Locks::default(), &selector, None, &f.bc).unwrap();
+        assert_eq!(unsigned.vout[1].address(&f.params).unwrap(), change);
+        let cold = Address::new(f.params.address_version, vec![6; 20]);
+        let unsigned = Transaction::new_unsigned(&change, &carol, 5, Locks::default(), &selector, Some(&cold), &f.bc).unwrap();
+        assert_eq!(unsigned.vout[1].address(&f.params).unwrap(), cold.to_string());
+    }
+
+    #[test]
+    fn test_sweep_and_consolidate() {
+        let mut f = Fixture::new(Network::Regtest);
+        let alice = f.alice.clone();
+        let bob = f.ws.create_wallet().unwrap();
+        let balance = |bc: &Blockchain, address: &str| bc.find_UTXO(address).iter().map(|out| out.value).sum::<i32>();
+
+        // split alice's coinbase into 5 outputs of bob's
+        let recipients = vec![(bob.clone(), 10), (bob.clone(), 20), (bob.clone(), 1), (bob.clone(), 30), (bob.clone(), 39)];
+        let tx = Transaction::new_many(&alice, &recipients, Locks::default(), &CoinSelector::default(), &mut f.ws, &f.bc).unwrap();
+        f.bc.add_block_with_tx(vec![tx]).unwrap();
+
+        // the 1 coin output is not worth its fee, the rest merge smallest first in pairs
+        let selector = CoinSelector::new(Default::default(), 1);
+        let txs = Transaction::new_consolidations(&bob, 2, None, &selector, &f.ws, &f.bc).unwrap();
+        assert_eq!(txs.len(), 2);
+        assert_eq!(txs[0].vout[0].value, 28);
+        assert_eq!(txs[1].vout[0].value, 67);
+        f.bc.add_block_with_tx(txs).unwrap();
+        assert_eq!(f.bc.find_UTXO(&bob).len(), 3);
+        assert_eq!(balance(&f.bc, &bob), 96);
+        assert!(Transaction::new_consolidations(&bob, 5, Some(20), &selector, &f.ws, &f.bc).unwrap().is_empty());
+
+        let sweep = Transaction::new_sweep(&bob, &alice, &selector, &f.ws, &f.bc).unwrap();
+        assert_eq!(sweep.vin.len(), 2);
+        f.bc.add_block_with_tx(vec![sweep]).unwrap();
+        assert_eq!(balance(&f.bc, &alice), 93);
+        assert_eq!(balance(&f.bc, &bob), 1);
+        assert!(Transaction::new_sweep(&bob, &alice, &selector, &f.ws, &f.bc).is_err());
+        assert_eq!(f.bc.verify_chain(None, 4).unwrap().first_bad_height, None);
+    }
+
+    #[test]
+    fn test_send_from_wallet() {
+        // the coinbases mined to bob and the watched address are spendable at once
+        let mut f = Fixture::with_params(ChainParams { coinbase_maturity: 0, ..ChainParams::temporary(Network::Regtest) });
+        let alice = f.alice.clone();
+        let bob = f.ws.create_wallet().unwrap();
+        let watched = Address::new(f.params.address_version, vec![4; 20]);
+        f.ws.add_watch_only(&watched, Vec::new()).unwrap();
+        let carol = Address::new(f.params.address_version, vec![5; 20]).to_string();
+        f.mine(&bob);
+        f.mine(&watched.to_string());
+
+        // 150 needs the coins of both alice and bob, but never the watched ones
+        let selector = CoinSelector::default();
+        let tx = Transaction::new_from_wallet(&[(carol.clone(), 150)], Locks::default(), &selector, &mut f.ws, &f.bc).unwrap();
+        assert_eq!(tx.vin.len(), 2);
+        f.bc.add_block_with_tx(vec![tx.clone()]).unwrap();
+        let change = tx.vout[1].address(&f.params).unwrap();
+        assert!(f.ws.is_change(&change));
+        let balance = |address: &str| f.bc.find_UTXO(address).iter().map(|out| out.value).sum::<i32>();
+        assert_eq!((balance(&alice), balance(&bob), balance(&change)), (0, 0, 50));
+        assert_eq!(balance(&carol), 150);
+
+        assert!(Transaction::new_from_wallet(&[(carol, 51)], Locks::default(), &selector, &mut f.ws, &f.bc).is_err());
+        assert_eq!(f.bc.verify_chain(None, 4).unwrap().first_bad_height, None);
+    }
+
+    #[test]
+    fn test_coinbase_maturity() {
+        let mut f = Fixture::with_params(ChainParams { coinbase_maturity: 3, ..ChainParams::temporary(Network::Regtest) });
+        let alice = f.alice.clone();
+        let bob = f.ws.create_wallet().unwrap();
+        let selector = CoinSelector::default();
+
+        // the genesis coinbase is below the activation height and can be spent at once
+        assert_eq!(f.bc.find_mature_outputs_of(std::slice::from_ref(&alice)).len(), 1);
+        let early = Transaction::new_UTXO(&alice, &bob, 10, Locks::default(), &selector, &mut f.ws, &f.bc).unwrap();
+        f.bc.add_block_with_tx(vec![early]).unwrap();
+
+        // bob's coinbases at heights 2 and 3 can be spent from heights 5 and 6 on
+        f.mine(&bob);
+        f.mine(&bob);
+        assert_eq!(f.bc.find_mature_outputs_of(std::slice::from_ref(&bob)).len(), 1);
+        assert!(Transaction::new_UTXO(&bob, &alice, 50, Locks::default(), &selector, &mut f.ws, &f.bc).is_err());
+
+        // built by hand, the early spend is refused by validation
+        let coinbase = f.bc.get_block_by_height(2).unwrap().get_transactions()[0].id.clone();
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput { txid: coinbase, vout: 0, script_sig: Script::new(), sequence: SEQUENCE_FINAL }],
+            vout: vec![TXOutput::new(100, &alice.parse().unwrap())],
+            lock_time: 0,
+        };
+        f.bc.sign_transacton(&mut tx, &f.ws.signing_key(&bob).unwrap()).unwrap();
+        let err = f.bc.add_block_with_tx(vec![tx.clone()]).unwrap_err();
+        assert!(err.to_string().contains("matures at height 5"));
+        f.mine(&alice);
+        assert_eq!(f.bc.find_mature_outputs_of(std::slice::from_ref(&bob)).len(), 2);
+        f.bc.add_block_with_tx(vec![tx]).unwrap();
+        assert_eq!(f.bc.verify_chain(None, 4).unwrap().first_bad_height, None);
+
+        // every network holds coinbases back, only the genesis one predates the rule
+        for network in [Network::Main, Network::Test, Network::Regtest] {
+            let params = network.params();
+            assert_eq!((params.coinbase_maturity, params.coinbase_maturity_height), (COINBASE_MATURITY, 1));
+        }
+    }
+
+    #[test]
+    fn test_coinbase_commitment() {
+        let mut f = Fixture::new(Network::Main);
+        let alice = f.alice.clone();
+
+        // the same reward to the same address no longer collides
+        let a = f.bc.next_coinbase(&alice, "", &[]).unwrap();
+        let b = f.bc.next_coinbase(&alice, "", &[]).unwrap();
+        assert_ne!(a.id, b.id);
+        assert_eq!(a.coinbase_commitment().unwrap().0, 1);
+        let regtest = Transaction::new_coinbase(alice.clone(), String::new(), 50, Some((7, 0))).unwrap();
+        assert_eq!(regtest.coinbase_commitment(), Some((7, 0)));
+        assert!(Transaction::new_coinbase(alice.clone(), "x".repeat(81), 50, Some((1, 0))).is_err());
+
+        // a coinbase must commit to the height of its block
+        let wrong = Transaction::new_coinbase(alice.clone(), String::new(), 50, Some((5, 0))).unwrap();
+        let err = f.bc.add_block_with_tx(vec![wrong]).unwrap_err();
+        assert!(err.to_string().contains("commits to height 5 in a block at height 1"));
+        f.bc.add_block_with_tx(vec![a]).unwrap();
+        assert_eq!(f.bc.verify_chain(None, 4).unwrap().first_bad_height, None);
+    }
+
+    #[test]
+    fn test_coinbase_commitment_on_forks() {
+        let mut f = Fixture::new(Network::Regtest);
+        let alice = f.alice.clone();
+        let bob = f.ws.create_wallet().unwrap();
+        let mut fork = f.genesis_only();
+        f.mine(&alice);
+        let cbtx = fork.next_coinbase(&bob, "", &[]).unwrap();
+        fork.add_block_with_tx(vec![cbtx]).unwrap();
+
+        // the same reward at the same height differs between the forks, and stays reproducible on each
+        let a = f.bc.next_coinbase(&alice, "", &[]).unwrap();
+        let b = fork.next_coinbase(&alice, "", &[]).unwrap();
+        assert_eq!(a.coinbase_commitment().unwrap().0, b.coinbase_commitment().unwrap().0);
+        assert_ne!(a.id, b.id);
+        assert_eq!(f.bc.next_coinbase(&alice, "", &[]).unwrap().id, a.id);
+    }
+
+    #[test]
+    fn test_old_format_chain() {
+        // blocks 1 and 2 were mined before coinbases carried a commitment
+        let params = ChainParams { coinbase_commitment_height: 3, ..ChainParams::regtest() };
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut bc = Blockchain::open(db, &params).unwrap();
+        for height in 1..=4 {
+            let cbtx = bc.next_coinbase(REGTEST_GENESIS_ADDRESS, &format!("regtest block {}", height), &[]).unwrap();
+            assert_eq!(cbtx.coinbase_commitment().is_some(), height >= 3);
+            bc.add_block_with_tx(vec![cbtx]).unwrap();
+        }
+        assert_eq!(bc.verify_chain(None, 4).unwrap().first_bad_height, None);
+
+        // past the activation height an old-format coinbase is refused
+        let old = Transaction::new_coinbase(String::from(REGTEST_GENESIS_ADDRESS), String::new(), params.subsidy, None).unwrap();
+        let err = bc.add_block_with_tx(vec![old]).unwrap_err();
+        assert!(err.to_string().contains("does not commit to a height"), "{}", err);
+    }
+}
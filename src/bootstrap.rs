@@ -0,0 +1,183 @@
+use crate::block::{Block, MAX_BLOCK_SIZE};
+use crate::blockchain::{apply_utxos, Blockchain};
+use crate::errors::Result;
+use bincode::{deserialize, serialize};
+use failure::format_err;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+// A bootstrap file starts with MAGIC, the format version, the network magic and the block count,
+// followed by the main chain in height order. Each block is a little-endian u32
+// length, the bincode encoded block and its 4-byte double-SHA256 checksum.
+const MAGIC: &[u8; 4] = b"BCRB";
+const VERSION: u32 = 1;
+
+/// ImportSummary reports what an import did
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportSummary {
+    pub total: u64,
+    pub imported: u64,
+    pub skipped: u64,
+}
+
+fn checksum(data: &[u8]) -> [u8; 4] {
+    let first = Sha256::digest(data);
+    let second = Sha256::digest(first);
+    let mut sum = [0; 4];
+    sum.copy_from_slice(&second[0..4]);
+    sum
+}
+
+/// ExportChain writes the main chain to a bootstrap file and returns the number of blocks
+pub fn export_chain(bc: &Blockchain, path: &str) -> Result<u64> {
+    let mut blocks: Vec<Block> = bc.iter().collect();
+    blocks.reverse();
+
+    let mut w = BufWriter::new(File::create(path)?);
+    w.write_all(MAGIC)?;
+    w.write_all(&VERSION.to_le_bytes())?;
+    w.write_all(&bc.get_params().magic)?;
+    w.write_all(&(blocks.len() as u64).to_le_bytes())?;
+    for block in &blocks {
+        let data = serialize(block)?;
+        if data.len() > MAX_BLOCK_SIZE {
+            return Err(format_err!("block {} is larger than {} bytes", block.get_hash(), MAX_BLOCK_SIZE));
+        }
+        w.write_all(&(data.len() as u32).to_le_bytes())?;
+        w.write_all(&data)?;
+        w.write_all(&checksum(&data))?;
+    }
+    w.flush()?;
+    Ok(blocks.len() as u64)
+}
+
+/// ImportChain validates the blocks of a bootstrap file, replaying their unspent outputs,
+/// and appends the ones not stored yet, so an interrupted import can simply be run again
+pub fn import_chain<F>(bc: &mut Blockchain, path: &str, mut progress: F) -> Result<ImportSummary>
+where
+    F: FnMut(u64, u64),
+{
+    let mut r = BufReader::new(File::open(path)?);
+
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(format_err!("{} is not a bootstrap file", path));
+    }
+    let mut buf4 = [0; 4];
+    r.read_exact(&mut buf4)?;
+    let version = u32::from_le_bytes(buf4);
+    if version != VERSION {
+        return Err(format_err!("unsupported bootstrap version {}", version));
+    }
+    r.read_exact(&mut buf4)?;
+    bc.get_params().check_magic(&buf4, path)?;
+    let mut buf8 = [0; 8];
+    r.read_exact(&mut buf8)?;
+    let total = u64::from_le_bytes(buf8);
+
+    let mut summary = ImportSummary {
+        total,
+        imported: 0,
+        skipped: 0,
+    };
+    let subsidy = bc.get_params().subsidy;
+    let mut utxos = HashMap::new();
+    for n in 0..total {
+        r.read_exact(&mut buf4)?;
+        let len = u32::from_le_bytes(buf4) as usize;
+        if len > MAX_BLOCK_SIZE {
+            return Err(format_err!("block {} of {} claims {} bytes, more than {}", n, path, len, MAX_BLOCK_SIZE));
+        }
+        let mut data = vec![0; len];
+        r.read_exact(&mut data)?;
+        r.read_exact(&mut buf4)?;
+        if buf4 != checksum(&data) {
+            return Err(format_err!("checksum mismatch in block {} of {}", n, path));
+        }
+        let block: Block = deserialize(&data)?;
+
+        if block.get_height() == 0 {
+            if let Ok(genesis) = bc.get_block_by_height(0) {
+                if genesis.get_hash() != block.get_hash() {
+                    return Err(format_err!("{} belongs to a different chain", path));
+                }
+            }
+        }
+
+        if bc.get_block(&block.get_hash()).is_ok() {
+            apply_utxos(&mut utxos, &block, subsidy, false).map_err(|e| format_err!("{}", e))?;
+            summary.skipped += 1;
+        } else {
+            bc.validate_block(&block, &mut utxos)?;
+            bc.add_block(block)?;
+            summary.imported += 1;
+        }
+        progress(n + 1, total);
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::blockchain::Fixture;
+    use crate::chainparams::Network;
+    use crate::script::Script;
+    use crate::transaction::Transaction;
+    use crate::tx::{TXInput, TXOutput, SEQUENCE_FINAL};
+
+    #[test]
+    fn test_export_import() {
+        let mut f = Fixture::new(Network::Regtest);
+        let alice = f.alice.clone();
+        for _ in 0..3 {
+            f.mine(&alice);
+        }
+        let path = format!("{}/bootstrap.dat", f.params.data_dir);
+        assert_eq!(export_chain(&f.bc, &path).unwrap(), 4);
+
+        // the genesis block is already there
+        let mut copy = f.genesis_only();
+        let summary = import_chain(&mut copy, &path, |_, _| {}).unwrap();
+        assert_eq!((summary.total, summary.imported, summary.skipped), (4, 3, 1));
+        assert_eq!(copy.get_tip_hash(), f.bc.get_tip_hash());
+        let summary = import_chain(&mut copy, &path, |_, _| {}).unwrap();
+        assert_eq!(summary.skipped, 4);
+
+        // an inflating block mined on top is refused, its checksum is fine
+        let coinbase = f.bc.get_block_by_height(0).unwrap().get_transactions()[0].id.clone();
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput { txid: coinbase, vout: 0, script_sig: Script::new(), sequence: SEQUENCE_FINAL }],
+            vout: vec![TXOutput::new(1_000_000, &alice.parse().unwrap())],
+            lock_time: 0,
+        };
+        f.bc.sign_transacton(&mut tx, &f.ws.signing_key(&alice).unwrap()).unwrap();
+        let block = Block::new_block(vec![tx], f.bc.get_tip_hash(), None, 4, &f.params).unwrap();
+        f.bc.add_block(block).unwrap();
+        export_chain(&f.bc, &path).unwrap();
+        let err = import_chain(&mut copy, &path, |_, _| {}).unwrap_err();
+        assert!(err.to_string().contains("spends more than its inputs"));
+
+        // a flipped byte fails the checksum, a huge length is refused before reading it
+        let mut data = std::fs::read(&path).unwrap();
+        let last = data.len() - 10;
+        data[last] ^= 1;
+        std::fs::write(&path, &data).unwrap();
+        assert!(import_chain(&mut copy, &path, |_, _| {}).unwrap_err().to_string().contains("checksum"));
+        data[20..24].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&path, &data).unwrap();
+        assert!(import_chain(&mut copy, &path, |_, _| {}).unwrap_err().to_string().contains("claims"));
+        assert_eq!(copy.get_best_height().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(checksum(b"hello"), [0x95, 0x95, 0xc9, 0xdf]);
+        assert_ne!(checksum(b"hello"), checksum(b"hellp"));
+    }
+}
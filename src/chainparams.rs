@@ -0,0 +1,288 @@
+use crate::errors::Result;
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+/// TARGET_HEXS is the number of leading zero hex digits a mainnet block hash needs
+pub const TARGET_HEXS: usize = 4;
+
+/// COINBASE_MATURITY is the number of confirmations a coinbase needs before it can be spent
+pub const COINBASE_MATURITY: i32 = 100;
+
+/// REGTEST_GENESIS_TIMESTAMP is the fixed time of the regtest genesis block
+pub const REGTEST_GENESIS_TIMESTAMP: u128 = 1_700_000_000_000;
+
+/// MOCK_BLOCK_INTERVAL is how far a block mined on a mock clock is timestamped after its parent
+pub const MOCK_BLOCK_INTERVAL: u128 = 600_000;
+
+/// REGTEST_GENESIS_ADDRESS receives the regtest genesis reward, nobody holds its key
+pub const REGTEST_GENESIS_ADDRESS: &str = "rVaC7MfSLBzmbK9f1byCeRUmR3h2VUPcwu";
+
+/// Clock supplies the timestamps of newly mined blocks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Clock {
+    /// the wall-clock time
+    System,
+    /// a fixed time in milliseconds since the epoch, blocks are stamped no earlier than
+    /// MOCK_BLOCK_INTERVAL after their parent so time still moves
+    Mock(u128),
+}
+
+impl Clock {
+    /// Now returns the current time in milliseconds since the epoch
+    pub fn now(&self) -> Result<u128> {
+        match self {
+            Clock::System => Ok(SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_millis()),
+            Clock::Mock(t) => Ok(*t),
+        }
+    }
+
+    /// BlockTime returns the timestamp of a new block on top of a parent with a timestamp
+    pub fn block_time(&self, prev_timestamp: Option<u128>) -> Result<u128> {
+        match (self, prev_timestamp) {
+            (Clock::Mock(t), Some(prev)) => Ok((*t).max(prev + MOCK_BLOCK_INTERVAL)),
+            _ => self.now(),
+        }
+    }
+}
+
+/// Network names one of the chains this node can run on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Network {
+    Main,
+    Test,
+    Regtest,
+}
+
+impl Network {
+    /// Params returns the settings of the network
+    pub fn params(&self) -> ChainParams {
+        match self {
+            Network::Main => ChainParams::main(),
+            Network::Test => ChainParams::testnet(),
+            Network::Regtest => ChainParams::regtest(),
+        }
+    }
+
+    /// FromMagic finds the network a magic value belongs to
+    pub fn from_magic(magic: &[u8]) -> Option<Network> {
+        [Network::Main, Network::Test, Network::Regtest]
+            .into_iter()
+            .find(|n| n.params().magic[..] == *magic)
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.params().name)
+    }
+}
+
+impl FromStr for Network {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Network> {
+        match s {
+            "main" => Ok(Network::Main),
+            "test" => Ok(Network::Test),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(format_err!("unknown network '{}'", s)),
+        }
+    }
+}
+
+/// ChainParams holds the settings a chain is stored, mined and validated with
+#[derive(Debug, Clone)]
+pub struct ChainParams {
+    pub network: Network,
+    pub name: &'static str,
+    pub data_dir: String,
+    /// version byte prefixed to the pubkey hash of an address
+    pub address_version: u8,
+    /// version byte prefixed to the script hash of a multisig address
+    pub script_version: u8,
+    /// version byte prefixed to an exported private key
+    pub secret_key_version: u8,
+    /// marks block stores, wallet stores, bootstrap files and wire messages
+    pub magic: [u8; 4],
+    /// port startnode listens on when none is given
+    pub default_port: u16,
+    pub subsidy: i32,
+    /// blocks on top of a coinbase before its outputs can be spent
+    pub coinbase_maturity: i32,
+    /// height of the first coinbase that has to mature. It is 1 everywhere, so only the genesis
+    /// reward spends at once: chains created before the rule spent it in their first send, and
+    /// verifychain has to keep accepting them
+    pub coinbase_maturity_height: i32,
+    /// height of the first coinbase committing to its height and an extra nonce, older ones only carry data
+    pub coinbase_commitment_height: i32,
+    /// BIP44 coin type of the HD wallet paths
+    pub bip44_coin_type: u32,
+    pub target_hexs: usize,
+    /// when set the genesis block is fixed: this timestamp, paid to REGTEST_GENESIS_ADDRESS
+    pub genesis_timestamp: Option<u128>,
+    pub clock: Clock,
+}
+
+impl ChainParams {
+    pub fn main() -> ChainParams {
+        ChainParams {
+            network: Network::Main,
+            name: "main",
+            data_dir: String::from("data"),
+            address_version: 0x00,
+            script_version: 0x05,
+            secret_key_version: 0x80,
+            magic: [0xf9, 0xbe, 0xb4, 0xd9],
+            default_port: 8333,
+            subsidy: 100,
+            coinbase_maturity: COINBASE_MATURITY,
+            // the genesis reward predates the rule
+            coinbase_maturity_height: 1,
+            coinbase_commitment_height: 1,
+            bip44_coin_type: 0,
+            target_hexs: TARGET_HEXS,
+            genesis_timestamp: None,
+            clock: Clock::System,
+        }
+    }
+
+    pub fn testnet() -> ChainParams {
+        ChainParams {
+            network: Network::Test,
+            name: "test",
+            data_dir: String::from("data/testnet"),
+            address_version: 0x6f,
+            script_version: 0xc4,
+            secret_key_version: 0xef,
+            magic: [0x0b, 0x11, 0x09, 0x07],
+            default_port: 18333,
+            subsidy: 100,
+            coinbase_maturity: COINBASE_MATURITY,
+            // the genesis reward predates the rule
+            coinbase_maturity_height: 1,
+            coinbase_commitment_height: 1,
+            bip44_coin_type: 1,
+            target_hexs: TARGET_HEXS - 1,
+            genesis_timestamp: None,
+            clock: Clock::System,
+        }
+    }
+
+    /// Regtest is a local chain for tests: no real proof of work, a fixed genesis
+    /// block and a mock clock, so the same commands always give the same hashes
+    pub fn regtest() -> ChainParams {
+        ChainParams {
+            network: Network::Regtest,
+            name: "regtest",
+            data_dir: String::from("data/regtest"),
+            // not testnet's, so addresses and keys of the two cannot be mixed up
+            address_version: 0x7b,
+            script_version: 0x3c,
+            secret_key_version: 0xf0,
+            magic: [0xfa, 0xbf, 0xb5, 0xda],
+            default_port: 18444,
+            subsidy: 100,
+            coinbase_maturity: COINBASE_MATURITY,
+            // the genesis reward predates the rule
+            coinbase_maturity_height: 1,
+            coinbase_commitment_height: 1,
+            bip44_coin_type: 1,
+            target_hexs: 0,
+            genesis_timestamp: Some(REGTEST_GENESIS_TIMESTAMP),
+            clock: Clock::Mock(REGTEST_GENESIS_TIMESTAMP),
+        }
+    }
+
+    /// CheckMagic fails when a stored marker belongs to another network
+    pub fn check_magic(&self, magic: &[u8], what: &str) -> Result<()> {
+        if magic == self.magic {
+            return Ok(());
+        }
+        match Network::from_magic(magic) {
+            Some(other) => Err(format_err!("{} belongs to the {} network, not {}", what, other, self.network)),
+            None => Err(format_err!("{} belongs to an unknown network", what)),
+        }
+    }
+
+    /// ExtraNonce returns the extra nonce of a new coinbase on top of a block, on a mock clock it
+    /// comes from the parent's hash so regtest blocks stay reproducible but differ between forks
+    pub fn extra_nonce(&self, prev_block_hash: &str) -> u64 {
+        use rand::RngCore;
+        match self.clock {
+            Clock::Mock(_) => {
+                let digest = Sha256::digest(prev_block_hash.as_bytes());
+                u64::from_le_bytes(digest[..8].try_into().unwrap())
+            }
+            Clock::System => rand::rngs::OsRng.next_u64(),
+        }
+    }
+
+    /// CoinbaseCommitment returns the height and extra nonce a new coinbase at a height, on top of
+    /// a block, commits to, none below coinbase_commitment_height
+    pub fn coinbase_commitment(&self, height: i32, prev_block_hash: &str) -> Option<(i32, u64)> {
+        (height >= self.coinbase_commitment_height).then(|| (height, self.extra_nonce(prev_block_hash)))
+    }
+
+    pub fn blocks_path(&self) -> String {
+        format!("{}/blocks", self.data_dir)
+    }
+
+    pub fn wallets_path(&self) -> String {
+        format!("{}/wallets", self.data_dir)
+    }
+
+    /// AgentPath is the socket of the agent keeping the key of an unlocked wallet,
+    /// in a directory of its own the agent makes private
+    pub fn agent_path(&self) -> String {
+        format!("{}/agent/wallet.sock", self.data_dir)
+    }
+
+    /// Temporary returns the params of a network with a fresh data directory, for tests
+    #[cfg(test)]
+    pub fn temporary(network: Network) -> ChainParams {
+        use rand::RngCore;
+        let dir = std::env::temp_dir().join(format!(
+            "blockchain-rust-{}-{:016x}",
+            network,
+            rand::rngs::OsRng.next_u64()
+        ));
+        ChainParams {
+            data_dir: dir.to_string_lossy().into_owned(),
+            ..network.params()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mock_clock() {
+        assert_eq!(Clock::Mock(42).now().unwrap(), 42);
+        assert_eq!(Clock::Mock(42).block_time(None).unwrap(), 42);
+        assert_eq!(Clock::Mock(42).block_time(Some(40)).unwrap(), 40 + MOCK_BLOCK_INTERVAL);
+        let later = 42 + 2 * MOCK_BLOCK_INTERVAL;
+        assert_eq!(Clock::Mock(later).block_time(Some(40)).unwrap(), later);
+        assert!(Clock::System.now().unwrap() > REGTEST_GENESIS_TIMESTAMP);
+    }
+
+    #[test]
+    fn test_networks() {
+        for network in [Network::Main, Network::Test, Network::Regtest] {
+            let params = network.params();
+            assert_eq!(params.network, network);
+            assert_eq!(Network::from_str(params.name).unwrap(), network);
+            assert_eq!(Network::from_magic(&params.magic), Some(network));
+        }
+        let main = ChainParams::main();
+        assert!(main.check_magic(&main.magic, "store").is_ok());
+        assert!(main.check_magic(&ChainParams::regtest().magic, "store").is_err());
+    }
+}
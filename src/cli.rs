@@ -1,18 +1,310 @@
-use super::*;
-use clap::{arg, Command};
-use crate::blockchain::Blockchain;
+use clap::{arg, Arg, ArgGroup, ArgMatches, Command};
+use crate::agent;
+use crate::blockchain::{describe_lock_time, Blockchain, ChainCheck};
+use crate::coinselect::{CoinSelector, Strategy};
+use crate::chainparams::{ChainParams, Clock, Network};
 use crate::errors::Result;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
 use std::process::exit;
-use bincode::deserialize;
+use crate::address::{Address, AddressFormat};
 use crate::block::Block;
-use crate::transaction::Transaction;
+use crate::bootstrap::{export_chain, import_chain, ImportSummary};
+use crate::htlc::{generate_secret, hash_secret, HtlcScript};
+use crate::multisig::MultisigScript;
+use crate::psbt::{decode_signed, PartialTransaction, TxEncoding};
+use crate::script::Script;
+use crate::server::Server;
+use crate::transaction::{parse_recipients, relative_blocks, relative_seconds, Locks, Transaction};
+use crate::tx::{TXInput, TXOutput};
 use crate::wallet::Wallets;
+use serde::Serialize;
+use failure::format_err;
+use sha2::{Digest, Sha256};
 
-pub struct Cli{}
+/// OutputFormat selects how command results are written to stdout
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+pub struct Cli{
+    format: OutputFormat,
+    params: ChainParams,
+}
+
+#[derive(Serialize)]
+struct ChainReport {
+    blocks: Vec<Block>,
+}
+
+#[derive(Serialize)]
+struct AddressReport {
+    address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mnemonic: Option<String>,
+}
+
+#[derive(Serialize)]
+struct MnemonicReport {
+    mnemonic: String,
+}
+
+#[derive(Serialize)]
+struct PrivKeyReport {
+    address: String,
+    privkey: String,
+}
+
+#[derive(Serialize)]
+struct ImportReport {
+    address: String,
+    watchonly: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    balance: Option<i32>,
+}
+
+#[derive(Serialize)]
+struct RestoreReport {
+    addresses: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct AddressListReport {
+    addresses: Vec<String>,
+    watchonly: Vec<String>,
+    change: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct WalletBalanceReport {
+    balance: i32,
+    immature_balance: i32,
+    watchonly_balance: i32,
+    addresses: usize,
+}
+
+#[derive(Serialize)]
+struct BalanceReport {
+    address: String,
+    balance: i32,
+    immature: i32,
+    watchonly: bool,
+}
+
+#[derive(Serialize)]
+struct HistoryEntry {
+    txid: String,
+    block_height: i32,
+    address: String,
+    category: &'static str,
+    amount: i32,
+    watchonly: bool,
+}
+
+#[derive(Serialize)]
+struct HistoryReport {
+    transactions: Vec<HistoryEntry>,
+}
+
+#[derive(Serialize)]
+struct CreateReport {
+    genesis_hash: String,
+}
+
+#[derive(Serialize)]
+struct SendReport {
+    txid: String,
+    block_hash: String,
+}
+
+#[derive(Serialize)]
+struct BlockReport {
+    hash: String,
+    prev_block_hash: String,
+    height: i32,
+    timestamp: u128,
+    nonce: i32,
+    confirmations: i32,
+    tx_count: usize,
+    size: usize,
+    txids: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct InputReport {
+    #[serde(flatten)]
+    input: TXInput,
+    prev_out: Option<OutputReport>,
+}
+
+#[derive(Serialize)]
+struct OutputReport {
+    value: i32,
+    script_pub_key: Script,
+    asm: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+}
+
+impl OutputReport {
+    fn new(out: &TXOutput, params: &ChainParams) -> OutputReport {
+        OutputReport {
+            value: out.value,
+            script_pub_key: out.script_pub_key.clone(),
+            asm: out.script_pub_key.to_asm(),
+            kind: out.script_pub_key.template().name(),
+            address: out.address(params),
+        }
+    }
+
+    // 标准脚本显示地址, 其他脚本显示汇编
+    fn text(&self) -> String {
+        match &self.address {
+            Some(address) => address.clone(),
+            None => format!("{} ({})", self.asm, self.kind),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TxReport {
+    txid: String,
+    coinbase: bool,
+    size: usize,
+    fee: i32,
+    block_hash: String,
+    block_height: i32,
+    confirmations: i32,
+    lock_time: u32,
+    inputs: Vec<InputReport>,
+    outputs: Vec<OutputReport>,
+}
+
+#[derive(Serialize)]
+struct VerifyReport {
+    #[serde(flatten)]
+    check: ChainCheck,
+    rolled_back: bool,
+}
+
+#[derive(Serialize)]
+struct ExportReport {
+    file: String,
+    blocks: u64,
+}
+
+#[derive(Serialize)]
+struct GenerateReport {
+    address: String,
+    blocks: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ValidateAddressReport {
+    address: String,
+    isvalid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    base58: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cashaddr: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pubkey_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    isscript: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ismine: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pubkey: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct WalletLockReport {
+    encrypted: bool,
+    unlocked_until: Option<u128>,
+}
+
+#[derive(Serialize)]
+struct MultisigReport {
+    address: String,
+    redeem_script: String,
+}
+
+#[derive(Serialize)]
+struct HtlcReport {
+    address: String,
+    redeem_script: String,
+    txid: String,
+    hash: String,
+    lock_time: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SendManyReport {
+    txid: String,
+    block_hash: String,
+    recipients: usize,
+    amount: i32,
+}
+
+#[derive(Serialize)]
+struct MergeReport {
+    txids: Vec<String>,
+    block_hash: Option<String>,
+    inputs: usize,
+    amount: i32,
+    fee: i32,
+}
+
+#[derive(Serialize)]
+struct AnchorReport {
+    hash: String,
+    txid: String,
+    block_hash: String,
+}
+
+#[derive(Serialize)]
+struct FindAnchorReport {
+    hash: String,
+    txid: String,
+    block_hash: String,
+    block_height: i32,
+    timestamp: u128,
+}
+
+#[derive(Serialize)]
+struct SecretReport {
+    secret: String,
+}
+
+#[derive(Serialize)]
+struct RawTxReport {
+    psbt: String,
+    complete: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signed: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tx: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ErrorReport {
+    error: String,
+}
 
 impl Cli {
     pub fn new() -> Result<Cli> {
-        Ok(Cli {})
+        Ok(Cli {
+            format: OutputFormat::Text,
+            params: ChainParams::main(),
+        })
     }
 
     pub fn run(&mut self) -> Result<()> {
@@ -20,146 +312,1567 @@ impl Cli {
             .version("0.1")
             .author("penry")
             .about("blockchain in rust: a simple blockchain for learning")
+            .arg(arg!(--format <FORMAT> "'output format: text or json'")
+                .value_parser(["text", "json"])
+                .default_value("text")
+                .global(true)
+            )
+            .arg(arg!(--network <NETWORK>"'the chain to use: main, test or regtest'")
+                .value_parser(["main", "test", "regtest"])
+                .default_value("main")
+                .global(true)
+            )
+            .arg(arg!(--regtest "'use the local regression test chain, same as --network regtest'").global(true))
+            .arg(arg!(--mocktime <MS>"'earliest timestamp in milliseconds for new regtest blocks, which still move ten minutes past their parent'")
+                .value_parser(clap::value_parser!(u128))
+                .global(true)
+            )
             .subcommand(Command::new("printchain").about("print all the chain blocks"))
-            .subcommand(Command::new("createwallet").about("create a wallet"))
-            .subcommand(Command::new("listaddresses").about("list all addresses"))
+            .subcommand(Command::new("getblock")
+                .about("show a single block by hash or height")
+                .arg(arg!([HASH]"'The hash of the block'"))
+                .arg(arg!(--height <N>"'The height of the block on the main chain'")
+                    .value_parser(clap::value_parser!(i32))
+                )
+                .group(ArgGroup::new("block").args(["HASH", "height"]).required(true))
+            )
+            .subcommand(Command::new("gettx")
+                .about("show a single transaction")
+                .arg(arg!(<TXID>"'The id of the transaction'"))
+            )
+            .subcommand(Command::new("verifychain")
+                .about("re-validate the stored blocks")
+                .arg(arg!(--depth <N>"'How many blocks from the tip to check, default all'")
+                    .value_parser(clap::value_parser!(usize))
+                )
+                .arg(arg!(--level <LEVEL>"'How thorough the check is, 0-4'")
+                    .value_parser(clap::value_parser!(u8).range(0..=4))
+                    .default_value("3")
+                )
+                .arg(arg!(--rollback "'Move the tip back to the last good block if a bad one is found'"))
+                .after_help("A regtest chain mined before coinbases committed to their height, or spending a coinbase \
+                    before it matured, fails at level 2 or 3 and has to be mined again.")
+            )
+            .subcommand(Command::new("exportchain")
+                .about("write the chain to a bootstrap file")
+                .arg(arg!(<FILE>"'The bootstrap file to write'"))
+            )
+            .subcommand(Command::new("importchain")
+                .about("validate and append the blocks of a bootstrap file")
+                .arg(arg!(<FILE>"'The bootstrap file to read'"))
+            )
+            .subcommand(Command::new("generate")
+                .about("mine blocks immediately, regtest only")
+                .arg(arg!(<N>"'The number of blocks to mine'")
+                    .value_parser(clap::value_parser!(u32))
+                )
+                .arg(arg!(--to <ADDRESS>"'The address the block rewards go to, default a new wallet address'"))
+                .arg(arg!(--"coinbase-message" <TEXT>"'The data each coinbase carries after its height and extra nonce'"))
+                .arg(addr_format_arg())
+            )
+            .subcommand(Command::new("createwallet").about("create a wallet")
+                .arg(addr_format_arg())
+            )
+            .subcommand(Command::new("restorewallet")
+                .about("restore a wallet from its mnemonic, finding the used addresses on the chain")
+                .arg(arg!(<MNEMONIC>"'The mnemonic words'").num_args(1..))
+                .arg(arg!(--"gap-limit" <N>"'Stop after this many unused addresses in a row'")
+                    .value_parser(clap::value_parser!(u32))
+                    .default_value("20")
+                )
+                .arg(addr_format_arg())
+            )
+            .subcommand(Command::new("dumpmnemonic").about("show the mnemonic the wallet addresses are derived from"))
+            .subcommand(Command::new("dumpprivkey")
+                .about("show the private key of a wallet address")
+                .arg(arg!(<ADDRESS>"'The address to export the key of'"))
+            )
+            .subcommand(Command::new("importprivkey")
+                .about("add a private key exported with dumpprivkey to the wallet")
+                .arg(arg!(<KEY>"'The private key'"))
+                .arg(arg!(--rescan "'scan the chain for the balance of the key'"))
+                .arg(addr_format_arg())
+            )
+            .subcommand(Command::new("importaddress")
+                .about("watch an address or public key without its private key")
+                .arg(arg!(<ADDRESS>"'The address, or the public key in hex'"))
+                .arg(arg!(--rescan "'scan the chain for the balance of the address'"))
+                .arg(addr_format_arg())
+            )
+            .subcommand(Command::new("listtransactions")
+                .about("list the transactions of the wallet addresses, oldest first")
+                .arg(addr_format_arg())
+            )
+            .subcommand(Command::new("listaddresses").about("list all addresses")
+                .arg(addr_format_arg())
+            )
+            .subcommand(Command::new("validateaddress")
+                .about("check an address and show its pubkey hash")
+                .arg(arg!(<ADDRESS>"'The address to check'"))
+                .arg(addr_format_arg())
+            )
+            .subcommand(Command::new("encryptwallet")
+                .about("encrypt the private keys of the wallet with a passphrase asked for on the terminal")
+            )
+            .subcommand(Command::new("walletpassphrase")
+                .about("unlock an encrypted wallet for signing, the passphrase is asked for on the terminal")
+                .arg(arg!(<TIMEOUT>"'Seconds the wallet stays unlocked, at most 3600'")
+                    .value_parser(clap::value_parser!(u64).range(1..=agent::MAX_UNLOCK_TIMEOUT))
+                )
+            )
+            .subcommand(Command::new("walletlock").about("lock an encrypted wallet again"))
+            .subcommand(Command::new(agent::AGENT_COMMAND)
+                .hide(true)
+                .arg(arg!(<SOCKET>))
+                .arg(arg!(<TIMEOUT>).value_parser(clap::value_parser!(u64)))
+            )
+            .subcommand(Command::new("createmultisig")
+                .about("create an M-of-N multisig address and watch it in the wallet")
+                .arg(arg!(<M>"'Number of signatures required'").value_parser(clap::value_parser!(usize)))
+                .arg(arg!(<KEYS>"'Public keys in hex, or addresses of this wallet'").num_args(1..))
+                .arg(addr_format_arg())
+            )
+            .subcommand(Command::new("createhtlc")
+                .about("lock coins to an address that can claim them with a secret, refundable after a lock time")
+                .arg(arg!(<FROM>"'Address paying into the HTLC and getting the refund'"))
+                .arg(arg!(<TO>"'Address that can claim with the secret'"))
+                .arg(arg!(<AMOUNT>"'Amount to lock'").value_parser(clap::value_parser!(i32)))
+                .arg(arg!(--locktime <LOCKTIME>"'block height, or unix time from 500000000 on, the refund opens at'")
+                    .required(true)
+                    .value_parser(clap::value_parser!(u32)))
+                .arg(arg!(--hash <HASH>"'SHA-256 hash in hex of a secret the other party holds, a new secret is made without it'"))
+                .arg(addr_format_arg())
+            )
+            .subcommand(Command::new("claimhtlc")
+                .about("claim the HTLC output of a transaction with its secret")
+                .arg(arg!(<TXID>"'The transaction paying to the HTLC'"))
+                .arg(arg!(<SCRIPT>"'The HTLC redeem script in hex'"))
+                .arg(arg!(<SECRET>"'The secret in hex'"))
+                .arg(arg!(--to <ADDRESS>"'Where the coins go, the HTLC recipient by default'"))
+            )
+            .subcommand(Command::new("refundhtlc")
+                .about("take back the HTLC output of a transaction once its lock time has passed")
+                .arg(arg!(<TXID>"'The transaction paying to the HTLC'"))
+                .arg(arg!(<SCRIPT>"'The HTLC redeem script in hex'"))
+                .arg(arg!(--to <ADDRESS>"'Where the coins go, the HTLC refund address by default'"))
+            )
+            .subcommand(Command::new("extractsecret")
+                .about("find the secret revealed on the chain by a claim of an HTLC")
+                .arg(arg!(<SCRIPT>"'The HTLC redeem script in hex'"))
+            )
+            .subcommand(Command::new("createrawtx")
+                .about("build an unsigned transaction, the from address may be watch-only")
+                .arg(arg!(<FROM>"'Source address'"))
+                .arg(arg!(<TO>"'Destination address'"))
+                .arg(arg!(<AMOUNT>"'Amount to send'").value_parser(clap::value_parser!(i32)))
+                .arg(arg!(--change <ADDRESS>"'Where the change goes, back to FROM by default'"))
+                .arg(encoding_arg())
+                .args(lock_args())
+                .args(coin_select_args())
+            )
+            .subcommand(Command::new("signrawtx")
+                .about("sign the inputs of a transaction from createrawtx the wallet has keys for")
+                .arg(arg!(<PSBT>"'The partially signed transaction, hex or base64'"))
+                .arg(encoding_arg())
+            )
+            .subcommand(Command::new("sendrawtx")
+                .about("put a completely signed transaction in a new block")
+                .arg(arg!(<TX>"'The signed transaction, hex or base64'"))
+                .arg(encoding_arg())
+            )
+            .subcommand(Command::new("sendmany")
+                .about("pay many addresses in one transaction with a single change output")
+                .arg(arg!(<FROM>"'Source wallet address'"))
+                .arg(arg!(<RECIPIENTS>"'JSON map or CSV file of address to amount, or an inline JSON map'"))
+                .args(lock_args())
+                .args(coin_select_args())
+            )
+            .subcommand(Command::new("sweep")
+                .about("send every output of an address, less the fees, to another address")
+                .arg(arg!(<FROM>"'Source wallet address'"))
+                .arg(arg!(<TO>"'Destination address'"))
+                .arg(fee_arg())
+            )
+            .subcommand(Command::new("consolidate")
+                .about("merge the small outputs of an address back to it, smallest first")
+                .arg(arg!(<ADDRESS>"'Wallet address to consolidate'"))
+                .arg(arg!(--"max-inputs" <N>"'most inputs spent by one transaction'")
+                    .value_parser(clap::value_parser!(usize))
+                    .default_value("50"))
+                .arg(arg!(--below <VALUE>"'only merge outputs worth less than this'")
+                    .value_parser(clap::value_parser!(i32)))
+                .arg(fee_arg())
+            )
+            .subcommand(Command::new("anchor")
+                .about("timestamp a file by putting its SHA-256 hash in a data output")
+                .arg(arg!(<FILE>"'The file to anchor'"))
+                .arg(arg!(<FROM>"'Wallet address spending an output to itself to carry the hash'"))
+            )
+            .subcommand(Command::new("findanchor")
+                .about("find the block a hash was first anchored in")
+                .arg(arg!(<HASH>"'The anchored hash in hex'"))
+            )
             .subcommand(Command::new("reindex").about("reindex UTXO"))
             .subcommand(Command::new("getbalance")
                 .about("get balance in the blochain")
-                .arg(arg!(<ADDRESS>"'The Address it get balance for'"))
+                .arg(arg!([ADDRESS]"'The Address it get balance for, the whole wallet if left out'"))
+                .arg(addr_format_arg())
             )
             .subcommand(Command::new("startnode")
                 .about("start the node server")
-                .arg(arg!(<PORT>"'the port server bind to locally'"))
+                .arg(arg!([PORT]"'the port server bind to locally, the network's default port if left out'")
+                    .value_parser(clap::value_parser!(u16)))
             )
             .subcommand(Command::new("create").about("Create new blochain")
-                .arg(arg!(<ADDRESS>"'The address to send gensis block reqward to' "))
+                .arg(arg!([ADDRESS]"'The address to send gensis block reqward to, left out on regtest' "))
             )
             .subcommand(
                 Command::new("send")
                     .about("send  in the blockchain")
-                    .arg(arg!(<FROM>" 'Source wallet address'"))
-                    .arg(arg!(<TO>" 'Destination wallet address'"))
-                    .arg(arg!(<AMOUNT>" 'Destination wallet address'"))
-                    .arg(arg!(-m --mine " 'the from address mine immediately'")),
+                    .override_usage("blockchain-rust send [OPTIONS] <FROM> <TO> <AMOUNT>\n       blockchain-rust send --from-wallet [OPTIONS] <TO> <AMOUNT>")
+                    // clap can only leave out the positional right before the last one, so FROM TO AMOUNT
+                    // are taken together and FROM is told apart by their count
+                    .arg(arg!(<ADDRESSES>"'Source wallet address, destination address and amount to send'")
+                        .num_args(2..=3)
+                        .value_names(["FROM", "TO", "AMOUNT"]))
+                    .arg(arg!(--"from-wallet" "'spend from every address of the wallet, signing each input with its key, FROM is left out'"))
+                    .arg(arg!(--data <HEX>"'data in hex to carry in an unspendable output, at most 80 bytes'"))
+                    .args(lock_args())
+                    .args(coin_select_args()),
             )
             .get_matches();
 
-        
+        if let Some(format) = matches.get_one::<String>("format") {
+            if format == "json" {
+                self.format = OutputFormat::Json;
+            }
+        }
+        if let Some(network) = matches.get_one::<String>("network") {
+            self.params = network.parse::<Network>()?.params();
+        }
+        if matches.get_flag("regtest") {
+            self.params = Network::Regtest.params();
+        }
+
+        let result = match matches.get_one::<u128>("mocktime") {
+            Some(_) if self.params.network != Network::Regtest => {
+                Err(format_err!("--mocktime can only be used with --regtest or --network regtest"))
+            }
+            Some(t) => {
+                self.params.clock = Clock::Mock(*t);
+                self.dispatch(&matches)
+            }
+            None => self.dispatch(&matches),
+        };
+        if let Err(e) = result {
+            if self.format == OutputFormat::Json {
+                self.output(&ErrorReport { error: e.to_string() }, String::new())?;
+                exit(1)
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn dispatch(&self, matches: &clap::ArgMatches) -> Result<()> {
+        if matches.subcommand_matches("printchain").is_some() {
+            let blocks = cmd_print_chain(&self.params)?;
+            let text = blocks.iter().map(|b| block_text(b, &self.params)).collect::<Vec<String>>().join("\n");
+            self.output(&ChainReport { blocks }, text)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("getblock") {
+            let report = cmd_get_block(
+                &self.params,
+                matches.get_one::<String>("HASH").map(|s| s.as_str()),
+                matches.get_one::<i32>("height").copied(),
+            )?;
+            let text = format!(
+                "Block {}\nHash: {}\nPrev. hash: {}\nTimestamp: {}\nNonce: {}\nConfirmations: {}\nTransactions: {}\nSize: {} bytes\n{}",
+                report.height,
+                report.hash,
+                report.prev_block_hash,
+                report.timestamp,
+                report.nonce,
+                report.confirmations,
+                report.tx_count,
+                report.size,
+                report.txids.join("\n"),
+            );
+            self.output(&report, text)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("gettx") {
+            if let Some(txid) = matches.get_one::<String>("TXID") {
+                let report = cmd_get_tx(&self.params, txid)?;
+                let mut text = format!(
+                    "Transaction {}\nBlock: {} (height {})\nConfirmations: {}\nSize: {} bytes\nFee: {}\n",
+                    report.txid,
+                    report.block_hash,
+                    report.block_height,
+                    report.confirmations,
+                    report.size,
+                    report.fee,
+                );
+                if report.lock_time > 0 {
+                    text += &format!("Locked until: {}\n", describe_lock_time(report.lock_time));
+                }
+                for i in &report.inputs {
+                    match &i.prev_out {
+                        Some(out) => text += &format!(
+                            "  in:  {}:{} {} ({} from {})\n",
+                            i.input.txid, i.input.vout, i.input.script_sig, out.value, out.text()
+                        ),
+                        None => text += &format!("  in:  coinbase {}\n", i.input.script_sig),
+                    }
+                }
+                for out in &report.outputs {
+                    text += &format!("  out: {} -> {}\n", out.value, out.text());
+                }
+                self.output(&report, text)?;
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("verifychain") {
+            let report = cmd_verify_chain(
+                &self.params,
+                matches.get_one::<usize>("depth").copied(),
+                *matches.get_one::<u8>("level").unwrap(),
+                matches.get_flag("rollback"),
+            )?;
+            let text = match (&report.check.first_bad_height, &report.check.last_good_hash) {
+                (None, _) => format!("chain is valid, {} blocks checked", report.check.checked),
+                (Some(height), Some(hash)) if report.rolled_back => format!(
+                    "bad block at height {}: {}\ntip rolled back to {}",
+                    height, report.check.reason.clone().unwrap_or_default(), hash
+                ),
+                (Some(height), Some(hash)) => format!(
+                    "bad block at height {}: {}\nlast good block: {}, run with --rollback to reset the tip to it",
+                    height, report.check.reason.clone().unwrap_or_default(), hash
+                ),
+                (Some(height), None) => format!(
+                    "bad block at height {}: {}\nno good block to roll back to",
+                    height, report.check.reason.clone().unwrap_or_default()
+                ),
+            };
+            self.output(&report, text)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("exportchain") {
+            if let Some(file) = matches.get_one::<String>("FILE") {
+                let blocks = cmd_export_chain(&self.params, file)?;
+                let text = format!("exported {} blocks to {}", blocks, file);
+                self.output(&ExportReport { file: file.clone(), blocks }, text)?;
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("startnode") {
+            let port = matches.get_one::<u16>("PORT").copied().unwrap_or(self.params.default_port);
+            cmd_start_node(&self.params, port)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("importchain") {
+            if let Some(file) = matches.get_one::<String>("FILE") {
+                let summary = cmd_import_chain(&self.params, file, self.format == OutputFormat::Text)?;
+                let text = format!(
+                    "imported {} blocks, {} already present",
+                    summary.imported, summary.skipped
+                );
+                self.output(&summary, text)?;
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("generate") {
+            let report = cmd_generate(
+                &self.params,
+                *matches.get_one::<u32>("N").unwrap(),
+                matches.get_one::<String>("to").map(|s| s.as_str()),
+                matches.get_one::<String>("coinbase-message").map(|s| s.as_str()),
+            )?;
+            let report = GenerateReport {
+                address: self.show_address(matches, &report.address)?,
+                blocks: report.blocks,
+            };
+            let text = report.blocks.join("\n");
+            self.output(&report, text)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("createwallet") {
+            let mut report = cmd_create_wallet(&self.params)?;
+            report.address = self.show_address(matches, &report.address)?;
+            let mut text = format!("address: {}", report.address);
+            if let Some(mnemonic) = &report.mnemonic {
+                text += &format!(
+                    "\nnew wallet seed, write down this mnemonic to restore the wallet:\n{}",
+                    mnemonic
+                );
+            }
+            self.output(&report, text)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("restorewallet") {
+            let mnemonic = matches
+                .get_many::<String>("MNEMONIC")
+                .unwrap()
+                .cloned()
+                .collect::<Vec<String>>()
+                .join(" ");
+            let addresses = cmd_restore_wallet(&self.params, &mnemonic, *matches.get_one::<u32>("gap-limit").unwrap())?
+                .iter()
+                .map(|address| self.show_address(matches, address))
+                .collect::<Result<Vec<String>>>()?;
+            let text = format!("restored {} addresses up to the last used one:\n{}", addresses.len(), addresses.join("\n"));
+            self.output(&RestoreReport { addresses }, text)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("dumpprivkey") {
+            if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                let report = cmd_dump_priv_key(&self.params, address)?;
+                self.output(&report, report.privkey.clone())?;
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("importprivkey") {
+            if let Some(key) = matches.get_one::<String>("KEY") {
+                let mut report = cmd_import_priv_key(&self.params, key, matches.get_flag("rescan"))?;
+                report.address = self.show_address(matches, &report.address)?;
+                let mut text = format!("imported address: {}", report.address);
+                if let Some(balance) = report.balance {
+                    text += &format!("\nbalance: {}", balance);
+                }
+                self.output(&report, text)?;
+            }
+        }
+
+        if matches.subcommand_matches("dumpmnemonic").is_some() {
+            let mnemonic = unlocked_wallets(&self.params)?.mnemonic()?;
+            self.output(&MnemonicReport { mnemonic: mnemonic.clone() }, mnemonic)?;
+        }
+
+        if matches.subcommand_matches("encryptwallet").is_some() {
+            let report = cmd_encrypt_wallet(&self.params)?;
+            let text = String::from("wallet encrypted, unlock it with walletpassphrase to send");
+            self.output(&report, text)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("walletpassphrase") {
+            let timeout = *matches.get_one::<u64>("TIMEOUT").unwrap();
+            let report = cmd_wallet_passphrase(&self.params, timeout)?;
+            let text = format!("wallet unlocked for {} seconds", timeout);
+            self.output(&report, text)?;
+        }
+
+        if matches.subcommand_matches("walletlock").is_some() {
+            let report = cmd_wallet_lock(&self.params)?;
+            self.output(&report, String::from("wallet locked"))?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches(agent::AGENT_COMMAND) {
+            let mut key = String::new();
+            std::io::stdin().read_line(&mut key)?;
+            let socket = matches.get_one::<String>("SOCKET").unwrap();
+            agent::serve(socket, key.trim(), *matches.get_one::<u64>("TIMEOUT").unwrap(), |until| {
+                println!("{}", until);
+                Ok(std::io::stdout().flush()?)
+            })?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("createmultisig") {
+            let keys: Vec<String> = matches.get_many::<String>("KEYS").unwrap().cloned().collect();
+            let mut report = cmd_create_multisig(&self.params, *matches.get_one::<usize>("M").unwrap(), &keys)?;
+            report.address = self.show_address(matches, &report.address)?;
+            let text = format!("address: {}\nredeem script: {}", report.address, report.redeem_script);
+            self.output(&report, text)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("createhtlc") {
+            let mut report = cmd_create_htlc(
+                &self.params,
+                matches.get_one::<String>("FROM").unwrap(),
+                matches.get_one::<String>("TO").unwrap(),
+                *matches.get_one::<i32>("AMOUNT").unwrap(),
+                *matches.get_one::<u32>("locktime").unwrap(),
+                matches.get_one::<String>("hash").map(|s| s.as_str()),
+            )?;
+            report.address = self.show_address(matches, &report.address)?;
+            let mut text = format!(
+                "address: {}\nredeem script: {}\ntxid: {}\nhash: {}\nrefund after: {}",
+                report.address,
+                report.redeem_script,
+                report.txid,
+                report.hash,
+                describe_lock_time(report.lock_time),
+            );
+            if let Some(secret) = &report.secret {
+                text += &format!("\nsecret: {}", secret);
+            }
+            self.output(&report, text)?;
+        }
+
+        for (command, claim) in [("claimhtlc", true), ("refundhtlc", false)] {
+            if let Some(matches) = matches.subcommand_matches(command) {
+                let report = cmd_spend_htlc(
+                    &self.params,
+                    matches.get_one::<String>("TXID").unwrap(),
+                    matches.get_one::<String>("SCRIPT").unwrap(),
+                    if claim { matches.get_one::<String>("SECRET").map(|s| s.as_str()) } else { None },
+                    matches.get_one::<String>("to").map(|s| s.as_str()),
+                )?;
+                let text = format!("success! txid: {}", report.txid);
+                self.output(&report, text)?;
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("extractsecret") {
+            let report = cmd_extract_secret(&self.params, matches.get_one::<String>("SCRIPT").unwrap())?;
+            self.output(&report, report.secret.clone())?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("sendmany") {
+            let report = cmd_send_many(
+                &self.params,
+                matches.get_one::<String>("FROM").unwrap(),
+                matches.get_one::<String>("RECIPIENTS").unwrap(),
+                locks(matches)?,
+                &coin_selector(matches)?,
+            )?;
+            let text = format!(
+                "success! paid {} to {} recipients\ntxid: {}",
+                report.amount, report.recipients, report.txid
+            );
+            self.output(&report, text)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("sweep") {
+            let report = cmd_sweep(
+                &self.params,
+                matches.get_one::<String>("FROM").unwrap(),
+                matches.get_one::<String>("TO").unwrap(),
+                &coin_selector(matches)?,
+            )?;
+            let text = format!(
+                "swept {} outputs worth {} (fee {})\ntxid: {}",
+                report.inputs, report.amount, report.fee, report.txids[0]
+            );
+            self.output(&report, text)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("consolidate") {
+            let report = cmd_consolidate(
+                &self.params,
+                matches.get_one::<String>("ADDRESS").unwrap(),
+                *matches.get_one::<usize>("max-inputs").unwrap(),
+                matches.get_one::<i32>("below").copied(),
+                &coin_selector(matches)?,
+            )?;
+            let mut text = format!(
+                "merged {} outputs into {} worth {} (fee {})",
+                report.inputs, report.txids.len(), report.amount, report.fee
+            );
+            for txid in &report.txids {
+                text += &format!("\ntxid: {}", txid);
+            }
+            self.output(&report, text)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("anchor") {
+            let report = cmd_anchor(
+                &self.params,
+                matches.get_one::<String>("FILE").unwrap(),
+                matches.get_one::<String>("FROM").unwrap(),
+            )?;
+            let text = format!("anchored {}\ntxid: {}\nblock: {}", report.hash, report.txid, report.block_hash);
+            self.output(&report, text)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("findanchor") {
+            let report = cmd_find_anchor(&self.params, matches.get_one::<String>("HASH").unwrap())?;
+            let text = format!(
+                "{} anchored in transaction {}\nBlock: {} (height {})\nTimestamp: {}",
+                report.hash, report.txid, report.block_hash, report.block_height, report.timestamp
+            );
+            self.output(&report, text)?;
+        }
 
-        if let Some(_) = matches.subcommand_matches("printchain") {
-            println!("printchain...");
-            cmd_print_chain()?;
+        if let Some(matches) = matches.subcommand_matches("createrawtx") {
+            let report = cmd_create_raw_tx(
+                &self.params,
+                matches.get_one::<String>("FROM").unwrap(),
+                (matches.get_one::<String>("TO").unwrap(), *matches.get_one::<i32>("AMOUNT").unwrap()),
+                matches.get_one::<String>("change").map(|s| s.as_str()),
+                locks(matches)?,
+                &coin_selector(matches)?,
+                encoding(matches)?,
+            )?;
+            self.output(&report, report.psbt.clone())?;
         }
 
-        if let Some(_) = matches.subcommand_matches("createwallet") {
-            println!("address: {}", cmd_create_wallet()?);
+        if let Some(matches) = matches.subcommand_matches("signrawtx") {
+            if let Some(psbt) = matches.get_one::<String>("PSBT") {
+                let report = cmd_sign_raw_tx(&self.params, psbt, encoding(matches)?)?;
+                let text = match &report.tx {
+                    Some(tx) => format!("signed {} inputs, complete:\n{}", report.signed.unwrap_or(0), tx),
+                    None => format!("signed {} inputs, more signatures needed:\n{}", report.signed.unwrap_or(0), report.psbt),
+                };
+                self.output(&report, text)?;
+            }
         }
 
-        if let Some(_) = matches.subcommand_matches("listaddresses") {
-            cmd_list_address()?;
+        if let Some(matches) = matches.subcommand_matches("sendrawtx") {
+            if let Some(tx) = matches.get_one::<String>("TX") {
+                let report = cmd_send_raw_tx(&self.params, tx, encoding(matches)?)?;
+                let text = format!("success! txid: {}", report.txid);
+                self.output(&report, text)?;
+            }
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("create") {
+        if let Some(matches) = matches.subcommand_matches("validateaddress") {
             if let Some(address) = matches.get_one::<String>("ADDRESS") {
-                cmd_create_blockchain(address)?;
+                let mut report = cmd_validate_address(&self.params, address)?;
+                if report.isvalid {
+                    report.address = self.show_address(matches, &report.address)?;
+                }
+                let text = match (&report.pubkey_hash, &report.error) {
+                    (Some(hash), _) => format!(
+                        "{} is valid\npubkey hash: {}\nin wallet: {}",
+                        report.address,
+                        hash,
+                        report.ismine.unwrap_or(false)
+                    ),
+                    (None, error) => format!(
+                        "{} is not valid: {}",
+                        report.address,
+                        error.clone().unwrap_or_default()
+                    ),
+                };
+                self.output(&report, text)?;
             }
         }
 
+        if let Some(matches) = matches.subcommand_matches("listaddresses") {
+            let ws = Wallets::new(&self.params)?;
+            let mut addresses = Vec::new();
+            let mut watchonly = Vec::new();
+            let mut change = Vec::new();
+            let mut lines = Vec::new();
+            for address in cmd_list_address(&self.params)? {
+                let shown = self.show_address(matches, &address)?;
+                if ws.is_watch_only(&address) {
+                    lines.push(format!("{} (watch-only)", shown));
+                    watchonly.push(shown.clone());
+                } else if ws.is_change(&address) {
+                    lines.push(format!("{} (change)", shown));
+                    change.push(shown.clone());
+                } else {
+                    lines.push(shown.clone());
+                }
+                addresses.push(shown);
+            }
+            let text = format!("addresses: \n{}", lines.join("\n"));
+            self.output(&AddressListReport { addresses, watchonly, change }, text)?;
+        }
 
-        if let Some(ref matches) = matches.subcommand_matches("getbalance") {
+        if let Some(matches) = matches.subcommand_matches("importaddress") {
             if let Some(address) = matches.get_one::<String>("ADDRESS") {
-                let balance = cmd_get_balance(address)?;
-                println!("Balance: {}\n", balance);
+                let mut report = cmd_import_address(&self.params, address, matches.get_flag("rescan"))?;
+                report.address = self.show_address(matches, &report.address)?;
+                let mut text = format!("watching address: {}", report.address);
+                if let Some(balance) = report.balance {
+                    text += &format!("\nbalance: {}", balance);
+                }
+                self.output(&report, text)?;
             }
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("send") {
-            let from = if let Some(address) = matches.get_one::<String>("FROM") {
-                address
-            } else {
-                println!("from not supply!: usage");
-                exit(1)
-            };
+        if let Some(matches) = matches.subcommand_matches("listtransactions") {
+            let mut transactions = cmd_list_transactions(&self.params)?;
+            let mut text = String::new();
+            for entry in transactions.iter_mut() {
+                entry.address = self.show_address(matches, &entry.address)?;
+                text += &format!(
+                    "{} {:>8} {:>6} {} (height {}){}\n",
+                    entry.txid,
+                    entry.category,
+                    entry.amount,
+                    entry.address,
+                    entry.block_height,
+                    if entry.watchonly { " watch-only" } else { "" },
+                );
+            }
+            self.output(&HistoryReport { transactions }, text.trim_end().to_string())?;
+        }
 
-            let to = if let Some(address) = matches.get_one::<String>("TO") {
-                address
-            } else {
-                println!("from not supply!: usage");
-                exit(1)
-            };
+        if let Some(matches) = matches.subcommand_matches("create") {
+            let address = matches.get_one::<String>("ADDRESS").map(|s| s.as_str());
+            let genesis_hash = cmd_create_blockchain(&self.params, address)?;
+            let text = format!("create blockchain, genesis block: {}", genesis_hash);
+            self.output(&CreateReport { genesis_hash }, text)?;
+        }
 
-            let amount: i32 = if let Some(amount) = matches.get_one::<String>("AMOUNT") {
-                amount.parse()?
-            } else {
-                println!("from not supply!: usage");
-                exit(1)
-            };
 
-            if matches.contains_id("mine") {
-                cmd_send(from, to, amount, true)?;
+        if let Some(matches) = matches.subcommand_matches("getbalance") {
+            if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                let (balance, immature) = cmd_get_balance(&self.params, address)?;
+                let watchonly = Wallets::new(&self.params)?
+                    .is_watch_only(&Address::parse(address, &self.params)?.to_string());
+                let address = self.show_address(matches, address)?;
+                let mut text = if watchonly {
+                    format!("Balance of '{}' (watch-only): {}", address, balance)
+                } else {
+                    format!("Balance of '{}': {}", address, balance)
+                };
+                if immature > 0 {
+                    text += &format!("\nImmature: {}", immature);
+                }
+                self.output(&BalanceReport { address, balance, immature, watchonly }, text)?;
             } else {
-                cmd_send(from, to, amount, false)?;
+                let report = cmd_get_wallet_balance(&self.params)?;
+                let mut text = format!("Wallet balance: {}", report.balance);
+                if report.immature_balance > 0 {
+                    text += &format!("\nImmature: {}", report.immature_balance);
+                }
+                if report.watchonly_balance > 0 {
+                    text += &format!("\nWatch-only: {}", report.watchonly_balance);
+                }
+                self.output(&report, text)?;
             }
         }
+
+        if let Some(matches) = matches.subcommand_matches("send") {
+            let args: Vec<&str> = matches.get_many::<String>("ADDRESSES").unwrap().map(|s| s.as_str()).collect();
+            let (from, to, amount) = match (matches.get_flag("from-wallet"), args.as_slice()) {
+                (false, [from, to, amount]) => (Some(*from), *to, *amount),
+                (true, [to, amount]) => (None, *to, *amount),
+                (false, _) => return Err(format_err!("send takes FROM TO AMOUNT, or TO AMOUNT with --from-wallet")),
+                (true, _) => return Err(format_err!("send --from-wallet takes TO AMOUNT, FROM is left out")),
+            };
+            let amount: i32 = amount.parse().map_err(|_| format_err!("invalid amount {}", amount))?;
+            let data = matches.get_one::<String>("data").map(|s| s.as_str());
+            let report = cmd_send(&self.params, from, to, amount, locks(matches)?, &coin_selector(matches)?, data)?;
+            let text = format!("success! txid: {}", report.txid);
+            self.output(&report, text)?;
+        }
         Ok(())
     }
+
+    /// ShowAddress writes an address in the format chosen with --addr-format
+    fn show_address(&self, matches: &ArgMatches, address: &str) -> Result<String> {
+        let format: AddressFormat = matches
+            .get_one::<String>("addr-format")
+            .map(|f| f.parse())
+            .unwrap_or(Ok(AddressFormat::Base58))?;
+        Address::parse(address, &self.params)?.encode(format, &self.params)
+    }
+
+    /// Output writes a command result as a single JSON object or as plain text
+    fn output<T: Serialize>(&self, report: &T, text: String) -> Result<()> {
+        match self.format {
+            OutputFormat::Json => println!("{}", serde_json::to_string(report)?),
+            OutputFormat::Text => println!("{}", text),
+        }
+        Ok(())
+    }
+}
+
+fn addr_format_arg() -> Arg {
+    arg!(--"addr-format" <FORMAT>"'how addresses are shown: base58 or cashaddr'")
+        .value_parser(["base58", "cashaddr"])
+        .default_value("base58")
+}
+
+/// unlocked_wallets loads the wallet store for a command using its keys, an encrypted one
+/// takes the key the agent of walletpassphrase keeps and fails while the wallet is locked
+fn unlocked_wallets(params: &ChainParams) -> Result<Wallets> {
+    let mut ws = Wallets::new(params)?;
+    if ws.is_encrypted() {
+        match agent::key(params) {
+            Some((key, _)) if ws.unlock_with_key(key).is_ok() => {}
+            _ => return Err(format_err!("Wallet is locked, run walletpassphrase <timeout> first")),
+        }
+    }
+    Ok(ws)
+}
+
+/// read_passphrase asks for a passphrase on the terminal without echoing it
+fn read_passphrase(prompt: &str) -> Result<String> {
+    Ok(rpassword::prompt_password(prompt)?)
 }
 
-fn cmd_send(from: &str, to: &str, amount: i32, mine_now: bool) -> Result<()> {
-    let mut bc = Blockchain::new()?;
+fn encoding_arg() -> Arg {
+    arg!(--encoding <ENCODING>"'how transactions are read and written: hex or base64'")
+        .value_parser(["hex", "base64"])
+        .default_value("base64")
+}
+
+fn encoding(matches: &ArgMatches) -> Result<TxEncoding> {
+    matches.get_one::<String>("encoding").unwrap().parse()
+}
 
-    let tx = Transaction::new_UTXO(from,to,amount,&bc)?;
+fn lock_args() -> [Arg; 3] {
+    [
+        arg!(--locktime <LOCKTIME>"'block height, or unix time from 500000000 on, the transaction cannot be mined before'")
+            .value_parser(clap::value_parser!(u32)),
+        arg!(--"relative-height" <BLOCKS>"'confirmations each spent output needs before it can be spent'")
+            .value_parser(clap::value_parser!(u16)),
+        arg!(--"relative-time" <SECONDS>"'age each spent output needs before it can be spent, in steps of 512 seconds'")
+            .value_parser(clap::value_parser!(u32))
+            .conflicts_with("relative-height"),
+    ]
+}
+
+fn locks(matches: &ArgMatches) -> Result<Locks> {
+    let sequence = match (
+        matches.get_one::<u16>("relative-height"),
+        matches.get_one::<u32>("relative-time"),
+    ) {
+        (Some(blocks), _) => Some(relative_blocks(*blocks)),
+        (None, Some(seconds)) => Some(relative_seconds(*seconds)?),
+        (None, None) => None,
+    };
+    Ok(Locks::new(matches.get_one::<u32>("locktime").copied().unwrap_or(0), sequence))
+}
+
+fn fee_arg() -> Arg {
+    arg!(--"fee-per-input" <FEE>"'fee paid for every input spent'")
+        .value_parser(clap::value_parser!(i32).range(0..))
+        .default_value("0")
+}
 
-    bc.add_block_with_tx(vec![tx]);
-    
-    println!("success!");
-    Ok(())
+fn coin_select_args() -> [Arg; 2] {
+    [
+        arg!(--"coin-select" <STRATEGY>"'how inputs are chosen: largest-first, smallest-first, bnb or random'")
+            .default_value("largest-first"),
+        fee_arg(),
+    ]
 }
 
-fn cmd_create_blockchain(address: &str) -> Result<()> {
-    let address = String::from(address);
-    let bc = Blockchain::create_blockchain(address)?;
-    println!("create blockchain");
-    Ok(())
+fn coin_selector(matches: &ArgMatches) -> Result<CoinSelector> {
+    let strategy = match matches.try_get_one::<String>("coin-select") {
+        Ok(Some(strategy)) => strategy.parse()?,
+        _ => Strategy::default(),
+    };
+    Ok(CoinSelector::new(strategy, *matches.get_one::<i32>("fee-per-input").unwrap()))
 }
 
-fn cmd_create_wallet() -> Result<String> {
-    let mut ws = Wallets::new()?;
-    let address = ws.create_wallet();
+fn cmd_create_multisig(params: &ChainParams, required: usize, keys: &[String]) -> Result<MultisigReport> {
+    let mut ws = Wallets::new(params)?;
+    let mut public_keys = Vec::new();
+    for key in keys {
+        let public_key = match hex::decode(key) {
+            Ok(public_key) => public_key,
+            Err(_) => {
+                let address = Address::parse(key, params)?.to_string();
+                match ws.get_wallet(&address) {
+                    Some(wallet) if !wallet.get_public_key().is_empty() => wallet.get_public_key().to_vec(),
+                    _ => return Err(format_err!("the public key of {} is not in the wallet", address)),
+                }
+            }
+        };
+        public_keys.push(public_key);
+    }
+    let script = MultisigScript::new(required, public_keys)?;
+    let redeem_script = hex::encode(script.to_bytes());
+    let address = ws.add_multisig(script)?;
     ws.save_all()?;
-    Ok(address)
+    Ok(MultisigReport { address, redeem_script })
+}
+
+fn cmd_create_htlc(
+    params: &ChainParams,
+    from: &str,
+    to: &str,
+    amount: i32,
+    lock_time: u32,
+    hash: Option<&str>,
+) -> Result<HtlcReport> {
+    let (secret, hash) = match hash {
+        Some(hash) => (None, hex::decode(hash)?),
+        None => {
+            let secret = generate_secret();
+            (Some(hex::encode(&secret)), hash_secret(&secret))
+        }
+    };
+    let htlc = HtlcScript::new(hash, &Address::parse(to, params)?, &Address::parse(from, params)?, lock_time)?;
+    let address = htlc.address(params).to_string();
+    let report = cmd_send(params, Some(from), &address, amount, Locks::default(), &CoinSelector::default(), None)?;
+    Ok(HtlcReport {
+        address,
+        redeem_script: hex::encode(htlc.to_bytes()),
+        txid: report.txid,
+        hash: hex::encode(htlc.hash()),
+        lock_time,
+        secret,
+    })
 }
 
-fn cmd_get_balance(address: &str) -> Result<i32> {
+/// cmd_spend_htlc claims an HTLC output when given the secret and refunds it otherwise
+fn cmd_spend_htlc(params: &ChainParams, txid: &str, script: &str, secret: Option<&str>, to: Option<&str>) -> Result<SendReport> {
+    let htlc = HtlcScript::from_bytes(&hex::decode(script)?)?;
+    let secret = secret.map(hex::decode).transpose()?;
+    let owner = match secret {
+        Some(_) => htlc.recipient(params),
+        None => htlc.refund(params),
+    };
+    let to = match to {
+        Some(to) => Address::parse(to, params)?,
+        None => owner.clone(),
+    };
+    let key = unlocked_wallets(params)?.signing_key(&owner.to_string())?;
 
-    let bc = Blockchain::new()?;
-    let address = String::from(address);
-    let utxos = bc.find_UTXO(&address);
+    let mut bc = Blockchain::new(params)?;
+    let funding = bc.find_transacton(txid)?;
+    let tx = htlc.spend(&funding, &to, &key, secret.as_deref(), params)?;
+    let txid = tx.id.clone();
+    commit_block(vec![tx], &mut bc)?;
+    Ok(SendReport {
+        txid,
+        block_hash: bc.get_tip_hash(),
+    })
+}
+
+fn cmd_extract_secret(params: &ChainParams, script: &str) -> Result<SecretReport> {
+    let htlc = HtlcScript::from_bytes(&hex::decode(script)?)?;
+    let bc = Blockchain::new(params)?;
+    match htlc.find_secret(&bc) {
+        Some(secret) => Ok(SecretReport { secret: hex::encode(secret) }),
+        None => Err(format_err!("the HTLC has not been claimed on this chain")),
+    }
+}
+
+/// CommitWithWallet saves the wallet, then puts a transaction it built in a new block and returns its id
+fn commit_with_wallet(tx: Transaction, wallets: &Wallets, bc: &mut Blockchain) -> Result<String> {
+    // 先保存找零地址, 区块写入后失败也不会丢失它的密钥
+    wallets.save_all()?;
+    let txid = tx.id.clone();
+    commit_block(vec![tx], bc)?;
+    Ok(txid)
+}
+
+/// CommitBlock puts transactions in a new block, every command but generate commits through it.
+/// Such a block mints nothing: when the transactions leave fees a coinbase pays them back
+/// to the address the first input spends from, so no fee is burned
+fn commit_block(mut txs: Vec<Transaction>, bc: &mut Blockchain) -> Result<()> {
+    if bc.block_fees(&txs)? > 0 {
+        let vin = &txs[0].vin[0];
+        let miner = bc
+            .find_transacton(&vin.txid)?
+            .vout
+            .get(vin.vout as usize)
+            .and_then(|out| out.address(bc.get_params()))
+            .ok_or_else(|| format_err!("no address to pay the fees of {} to", txs[0].id))?;
+        let cbtx = bc.fee_coinbase(&miner, &txs)?;
+        txs.insert(0, cbtx);
+    }
+    bc.add_block_with_tx(txs)
+}
+
+fn cmd_send_many(
+    params: &ChainParams,
+    from: &str,
+    recipients: &str,
+    locks: Locks,
+    selector: &CoinSelector,
+) -> Result<SendManyReport> {
+    let recipients = if recipients.trim_start().starts_with('{') {
+        parse_recipients(recipients)?
+    } else {
+        parse_recipients(&std::fs::read_to_string(recipients)?)?
+    };
+    let mut bc = Blockchain::new(params)?;
+    let mut wallets = unlocked_wallets(params)?;
+    let tx = Transaction::new_many(from, &recipients, locks, selector, &mut wallets, &bc)?;
+    let txid = commit_with_wallet(tx, &wallets, &mut bc)?;
+    Ok(SendManyReport {
+        txid,
+        block_hash: bc.get_tip_hash(),
+        recipients: recipients.len(),
+        amount: recipients.iter().map(|(_, amount)| amount).sum(),
+    })
+}
+
+fn cmd_sweep(params: &ChainParams, from: &str, to: &str, selector: &CoinSelector) -> Result<MergeReport> {
+    let mut bc = Blockchain::new(params)?;
+    let tx = Transaction::new_sweep(from, to, selector, &unlocked_wallets(params)?, &bc)?;
+    let report = merge_report(std::slice::from_ref(&tx), selector)?;
+    commit_block(vec![tx], &mut bc)?;
+    Ok(MergeReport {
+        block_hash: Some(bc.get_tip_hash()),
+        ..report
+    })
+}
+
+fn cmd_consolidate(
+    params: &ChainParams,
+    address: &str,
+    max_inputs: usize,
+    below: Option<i32>,
+    selector: &CoinSelector,
+) -> Result<MergeReport> {
+    let mut bc = Blockchain::new(params)?;
+    let txs = Transaction::new_consolidations(address, max_inputs, below, selector, &unlocked_wallets(params)?, &bc)?;
+    let mut report = merge_report(&txs, selector)?;
+    if !txs.is_empty() {
+        commit_block(txs, &mut bc)?;
+        report.block_hash = Some(bc.get_tip_hash());
+    }
+    Ok(report)
+}
+
+fn merge_report(txs: &[Transaction], selector: &CoinSelector) -> Result<MergeReport> {
+    let inputs: usize = txs.iter().map(|tx| tx.vin.len()).sum();
+    let amount = txs.iter().map(|tx| tx.output_value()).sum::<i64>();
+    Ok(MergeReport {
+        txids: txs.iter().map(|tx| tx.id.clone()).collect(),
+        block_hash: None,
+        inputs,
+        amount: i32::try_from(amount).map_err(|_| format_err!("the outputs add up to more than {}", i32::MAX))?,
+        fee: selector.fee_for(inputs)?,
+    })
+}
+
+fn cmd_anchor(params: &ChainParams, file: &str, from: &str) -> Result<AnchorReport> {
+    let hash = Sha256::digest(std::fs::read(file)?).to_vec();
+    let mut bc = Blockchain::new(params)?;
+    let key = unlocked_wallets(params)?.signing_key(&Address::parse(from, params)?.to_string())?;
+    let mut tx = Transaction::new_data(from, &hash, &bc)?;
+    bc.sign_transacton(&mut tx, &key)?;
+    let txid = tx.id.clone();
+    commit_block(vec![tx], &mut bc)?;
+    Ok(AnchorReport {
+        hash: hex::encode(hash),
+        txid,
+        block_hash: bc.get_tip_hash(),
+    })
+}
+
+fn cmd_find_anchor(params: &ChainParams, hash: &str) -> Result<FindAnchorReport> {
+    let bc = Blockchain::new(params)?;
+    let (block, tx) = bc
+        .find_data(&hex::decode(hash)?)
+        .ok_or_else(|| format_err!("{} is not anchored on the chain", hash))?;
+    Ok(FindAnchorReport {
+        hash: hash.to_lowercase(),
+        txid: tx.id,
+        block_hash: block.get_hash(),
+        block_height: block.get_height(),
+        timestamp: block.get_timestamp(),
+    })
+}
+
+fn cmd_create_raw_tx(
+    params: &ChainParams,
+    from: &str,
+    (to, amount): (&str, i32),
+    change: Option<&str>,
+    locks: Locks,
+    selector: &CoinSelector,
+    encoding: TxEncoding,
+) -> Result<RawTxReport> {
+    let bc = Blockchain::new(params)?;
+    let change = change.map(|change| Address::parse(change, params)).transpose()?;
+    let tx = Transaction::new_unsigned(from, to, amount, locks, selector, change.as_ref(), &bc)?;
+    let mut ptx = PartialTransaction::new(tx, &bc)?;
+    ptx.add_scripts(&Wallets::new(params)?);
+    Ok(RawTxReport {
+        psbt: ptx.encode(params, encoding)?,
+        complete: false,
+        signed: None,
+        tx: None,
+    })
+}
+
+fn cmd_sign_raw_tx(params: &ChainParams, psbt: &str, encoding: TxEncoding) -> Result<RawTxReport> {
+    let mut ptx = PartialTransaction::decode(psbt, encoding, params)?;
+    let signed = ptx.sign(&unlocked_wallets(params)?)?;
+    let complete = ptx.is_complete();
+    Ok(RawTxReport {
+        psbt: ptx.encode(params, encoding)?,
+        complete,
+        signed: Some(signed),
+        tx: if complete { Some(ptx.finalize()?.encode(encoding)?) } else { None },
+    })
+}
+
+fn cmd_send_raw_tx(params: &ChainParams, tx: &str, encoding: TxEncoding) -> Result<SendReport> {
+    let tx = decode_signed(tx, encoding, params)?;
+    let mut bc = Blockchain::new(params)?;
+    let txid = tx.id.clone();
+    commit_block(vec![tx], &mut bc)?;
+    Ok(SendReport {
+        txid,
+        block_hash: bc.get_tip_hash(),
+    })
+}
+
+fn cmd_send(
+    params: &ChainParams,
+    from: Option<&str>,
+    to: &str,
+    amount: i32,
+    locks: Locks,
+    selector: &CoinSelector,
+    data: Option<&str>,
+) -> Result<SendReport> {
+    let mut bc = Blockchain::new(params)?;
+
+    let mut wallets = unlocked_wallets(params)?;
+    let mut tx = match from {
+        Some(from) => Transaction::new_UTXO(from,to,amount,locks,selector,&mut wallets,&bc)?,
+        None => Transaction::new_from_wallet(&[(to.to_string(), amount)], locks, selector, &mut wallets, &bc)?,
+    };
+    if let Some(data) = data {
+        // the data output changes what the inputs sign, so sign again
+        tx.add_data(&hex::decode(data)?)?;
+        bc.sign_with_wallet(&mut tx, &wallets)?;
+    }
+    let txid = commit_with_wallet(tx, &wallets, &mut bc)?;
+
+    Ok(SendReport {
+        txid,
+        block_hash: bc.get_tip_hash(),
+    })
+}
+
+fn cmd_get_block(params: &ChainParams, hash: Option<&str>, height: Option<i32>) -> Result<BlockReport> {
+    let bc = Blockchain::new(params)?;
+    let block = match (hash, height) {
+        (Some(hash), _) => bc.get_block(hash)?,
+        (None, Some(height)) => bc.get_block_by_height(height)?,
+        (None, None) => return Err(format_err!("a block hash or height is required")),
+    };
+
+    Ok(BlockReport {
+        hash: block.get_hash(),
+        prev_block_hash: block.get_prev_hash(),
+        height: block.get_height(),
+        timestamp: block.get_timestamp(),
+        nonce: block.get_nonce(),
+        confirmations: bc.get_best_height()? - block.get_height() + 1,
+        tx_count: block.get_transactions().len(),
+        size: bincode::serialized_size(&block)? as usize,
+        txids: block.get_transactions().iter().map(|tx| tx.id.clone()).collect(),
+    })
+}
+
+fn cmd_get_tx(params: &ChainParams, txid: &str) -> Result<TxReport> {
+    let bc = Blockchain::new(params)?;
+    let tx = bc.find_transacton(txid)?;
+    let block = bc.find_transaction_block(txid)?;
+
+    let mut inputs = Vec::new();
+    let mut input_value = 0;
+    for input in &tx.vin {
+        let prev_out = if tx.is_coinbase() {
+            None
+        } else {
+            let prev_tx = bc.find_transacton(&input.txid)?;
+            let out = prev_tx.vout.get(input.vout as usize).cloned().ok_or_else(|| {
+                format_err!("Output {}:{} is not found", input.txid, input.vout)
+            })?;
+            input_value += out.value;
+            Some(OutputReport::new(&out, params))
+        };
+        inputs.push(InputReport {
+            input: input.clone(),
+            prev_out,
+        });
+    }
+
+    let output_value: i32 = tx.vout.iter().map(|out| out.value).sum();
+    Ok(TxReport {
+        txid: tx.id.clone(),
+        coinbase: tx.is_coinbase(),
+        size: bincode::serialized_size(&tx)? as usize,
+        fee: if tx.is_coinbase() { 0 } else { input_value - output_value },
+        block_hash: block.get_hash(),
+        block_height: block.get_height(),
+        confirmations: bc.get_best_height()? - block.get_height() + 1,
+        lock_time: tx.lock_time,
+        inputs,
+        outputs: tx.vout.iter().map(|out| OutputReport::new(out, params)).collect(),
+    })
+}
+
+fn cmd_verify_chain(params: &ChainParams, depth: Option<usize>, level: u8, rollback: bool) -> Result<VerifyReport> {
+    let mut bc = Blockchain::new(params)?;
+    let check = bc.verify_chain(depth, level)?;
+
+    let mut rolled_back = false;
+    if rollback && check.first_bad_height.is_some() {
+        if let Some(hash) = &check.last_good_hash {
+            bc.rollback_to(hash)?;
+            rolled_back = true;
+        }
+    }
+    Ok(VerifyReport { check, rolled_back })
+}
+
+fn cmd_start_node(params: &ChainParams, port: u16) -> Result<()> {
+    let bc = Blockchain::new(params)?;
+    let server = Server::bind(bc, port)?;
+    eprintln!("{} node listening on port {}", params.network, server.port()?);
+    server.run()
+}
+
+fn cmd_export_chain(params: &ChainParams, file: &str) -> Result<u64> {
+    let bc = Blockchain::new(params)?;
+    export_chain(&bc, file)
+}
 
-    println!("{:?}",utxos);
+fn cmd_import_chain(params: &ChainParams, file: &str, show_progress: bool) -> Result<ImportSummary> {
+    let mut bc = Blockchain::new(params)?;
+    import_chain(&mut bc, file, |done, total| {
+        if show_progress && (done % 100 == 0 || done == total) {
+            eprintln!("imported {}/{} blocks", done, total);
+        }
+    })
+}
+
+fn cmd_generate(params: &ChainParams, n: u32, to: Option<&str>, message: Option<&str>) -> Result<GenerateReport> {
+    if params.network != Network::Regtest {
+        return Err(format_err!("generate is only available on regtest"));
+    }
+    let address = match to {
+        Some(address) => Address::parse(address, params)?.to_string(),
+        None => cmd_create_wallet(params)?.address,
+    };
+
+    let mut bc = Blockchain::new(params)?;
+    let mut blocks = Vec::new();
+    for _ in 0..n {
+        let data = match message {
+            Some(message) => message.to_string(),
+            None => format!("regtest block {}", bc.get_best_height()? + 1),
+        };
+        let cbtx = bc.next_coinbase(&address, &data, &[])?;
+        bc.add_block_with_tx(vec![cbtx])?;
+        blocks.push(bc.get_tip_hash());
+    }
+    Ok(GenerateReport { address, blocks })
+}
+
+fn cmd_create_blockchain(params: &ChainParams, address: Option<&str>) -> Result<String> {
+    let address = address.map(|a| Address::parse(a, params)).transpose()?.map(|a| a.to_string());
+    let bc = Blockchain::create_blockchain(address, params)?;
+    Ok(bc.get_tip_hash())
+}
+
+fn cmd_create_wallet(params: &ChainParams) -> Result<AddressReport> {
+    let mut ws = unlocked_wallets(params)?;
+    let new_seed = !ws.has_seed();
+    let address = ws.create_wallet()?;
+    ws.save_all()?;
+    let mnemonic = if new_seed { Some(ws.mnemonic()?) } else { None };
+    Ok(AddressReport { address, mnemonic })
+}
+
+fn cmd_dump_priv_key(params: &ChainParams, address: &str) -> Result<PrivKeyReport> {
+    let address = Address::parse(address, params)?.to_string();
+    let ws = unlocked_wallets(params)?;
+    let privkey = ws.dump_priv_key(&address)?;
+    Ok(PrivKeyReport { address, privkey })
+}
+
+fn cmd_import_priv_key(params: &ChainParams, key: &str, rescan: bool) -> Result<ImportReport> {
+    let mut ws = unlocked_wallets(params)?;
+    let address = ws.import_priv_key(key)?;
+    ws.save_all()?;
+    let balance = if rescan { Some(cmd_get_balance(params, &address)?.0) } else { None };
+    Ok(ImportReport { address, watchonly: false, balance })
+}
+
+fn cmd_import_address(params: &ChainParams, address: &str, rescan: bool) -> Result<ImportReport> {
+    let (parsed, public_key) = match hex::decode(address) {
+        Ok(public_key) if public_key.len() == 32 => {
+            (Address::from_pub_key(&public_key, params.address_version), public_key)
+        }
+        _ => (Address::parse(address, params)?, Vec::new()),
+    };
+    let mut ws = Wallets::new(params)?;
+    let address = ws.add_watch_only(&parsed, public_key)?;
+    ws.save_all()?;
+    let balance = if rescan { Some(cmd_get_balance(params, &address)?.0) } else { None };
+    Ok(ImportReport { address: address.clone(), watchonly: ws.is_watch_only(&address), balance })
+}
+
+/// ListTransactions returns per transaction the net amount each wallet address received or sent
+fn cmd_list_transactions(params: &ChainParams) -> Result<Vec<HistoryEntry>> {
+    let ws = Wallets::new(params)?;
+    let bc = Blockchain::new(params)?;
+    let mut blocks: Vec<Block> = bc.iter().collect();
+    blocks.reverse();
+
+    let mut outputs: HashMap<(String, i32), TXOutput> = HashMap::new();
+    let mut entries = Vec::new();
+    for block in &blocks {
+        for tx in block.get_transactions() {
+            let mut amounts: BTreeMap<String, i32> = BTreeMap::new();
+            if !tx.is_coinbase() {
+                for vin in &tx.vin {
+                    if let Some(out) = outputs.get(&(vin.txid.clone(), vin.vout)) {
+                        *amounts.entry(out.address(params).unwrap_or_default()).or_default() -= out.value;
+                    }
+                }
+            }
+            for (index, out) in tx.vout.iter().enumerate() {
+                match out.address(params) {
+                    Some(address) if ws.get_wallet(&address).is_some() => {
+                        outputs.insert((tx.id.clone(), index as i32), out.clone());
+                        *amounts.entry(address).or_default() += out.value;
+                    }
+                    _ => {}
+                }
+            }
+            // change paid back to the wallet reduces what the sending address spent
+            if let Some(sender) = amounts.iter().find(|(_, amount)| **amount < 0).map(|(a, _)| a.clone()) {
+                let change: Vec<String> = amounts.keys().filter(|a| ws.is_change(a)).cloned().collect();
+                for address in change {
+                    let value = amounts.remove(&address).unwrap_or(0);
+                    *amounts.entry(sender.clone()).or_default() += value;
+                }
+            }
+            for (address, amount) in amounts {
+                let category = match amount {
+                    0 => continue,
+                    _ if tx.is_coinbase() => "generate",
+                    a if a > 0 => "receive",
+                    _ => "send",
+                };
+                entries.push(HistoryEntry {
+                    txid: tx.id.clone(),
+                    block_height: block.get_height(),
+                    watchonly: ws.is_watch_only(&address),
+                    address,
+                    category,
+                    amount,
+                });
+            }
+        }
+    }
+    Ok(entries)
+}
+
+fn cmd_restore_wallet(params: &ChainParams, mnemonic: &str, gap_limit: u32) -> Result<Vec<String>> {
+    let used = Blockchain::new(params)?.find_used_addresses();
+    let mut ws = unlocked_wallets(params)?;
+    let addresses = ws.restore(mnemonic, gap_limit, |address| used.contains(address))?;
+    ws.save_all()?;
+    Ok(addresses)
+}
+
+fn cmd_encrypt_wallet(params: &ChainParams) -> Result<WalletLockReport> {
+    let mut ws = Wallets::new(params)?;
+    if ws.is_encrypted() {
+        return Err(format_err!("Wallet is already encrypted"));
+    }
+    let passphrase = read_passphrase("new wallet passphrase: ")?;
+    if passphrase.is_empty() {
+        return Err(format_err!("the passphrase cannot be empty"));
+    }
+    if read_passphrase("repeat the passphrase: ")? != passphrase {
+        return Err(format_err!("the passphrases do not match"));
+    }
+    ws.encrypt(&passphrase)?;
+    Ok(WalletLockReport { encrypted: true, unlocked_until: None })
+}
+
+fn cmd_wallet_passphrase(params: &ChainParams, timeout: u64) -> Result<WalletLockReport> {
+    let mut ws = Wallets::new(params)?;
+    if !ws.is_encrypted() {
+        return Err(format_err!("Wallet is not encrypted"));
+    }
+    let key = ws.unlock(&read_passphrase("wallet passphrase: ")?)?;
+    let until = agent::start(params, &key, timeout)?;
+    Ok(WalletLockReport { encrypted: true, unlocked_until: Some(until) })
+}
+
+fn cmd_wallet_lock(params: &ChainParams) -> Result<WalletLockReport> {
+    agent::lock(params)?;
+    Ok(WalletLockReport { encrypted: Wallets::new(params)?.is_encrypted(), unlocked_until: None })
+}
+
+/// CmdGetBalance returns the spendable balance of an address and what it holds in immature coinbases
+fn cmd_get_balance(params: &ChainParams, address: &str) -> Result<(i32, i32)> {
+    let address = Address::parse(address, params)?.to_string();
+    let bc = Blockchain::new(params)?;
+    let utxos = bc.find_UTXO(&address);
 
     let mut balance = 0;
     for out in utxos {
         balance += out.value;
     }
-    println!("Balance of '{}'; {}", address,balance);
-    Ok(balance)
+    let mature: i32 = bc
+        .find_mature_outputs_of(&[address])
+        .iter()
+        .map(|(_, _, out)| out.value)
+        .sum();
+    Ok((mature, balance - mature))
 }
-fn cmd_print_chain() -> Result<()> {
-    let bc = Blockchain::new()?;
-    for b in bc.iter() {
-        println!("{:#?}", b);
+
+fn cmd_get_wallet_balance(params: &ChainParams) -> Result<WalletBalanceReport> {
+    let ws = Wallets::new(params)?;
+    let bc = Blockchain::new(params)?;
+    let addresses = ws.get_all_addresses();
+    let mature: HashSet<(String, i32)> = bc
+        .find_mature_outputs_of(&addresses)
+        .into_iter()
+        .map(|(txid, vout, _)| (txid, vout))
+        .collect();
+
+    let mut report = WalletBalanceReport {
+        balance: 0,
+        immature_balance: 0,
+        watchonly_balance: 0,
+        addresses: addresses.len(),
+    };
+    for (txid, vout, out) in bc.find_unspent_outputs_of(&addresses) {
+        match out.address(params) {
+            Some(address) if ws.is_watch_only(&address) => report.watchonly_balance += out.value,
+            _ if mature.contains(&(txid, vout)) => report.balance += out.value,
+            _ => report.immature_balance += out.value,
+        }
     }
-    Ok(())
+    Ok(report)
 }
 
-fn cmd_list_address() -> Result<()> {
-    let ws = Wallets::new()?;
-    let addresses = ws.get_all_addresses();
-    println!("addresses: ");
-    for ad in addresses {
-        println!("{}", ad);
+fn cmd_print_chain(params: &ChainParams) -> Result<Vec<Block>> {
+    let bc = Blockchain::new(params)?;
+    Ok(bc.iter().collect())
+}
+
+fn cmd_validate_address(params: &ChainParams, address: &str) -> Result<ValidateAddressReport> {
+    match Address::parse(address, params) {
+        Ok(parsed) => {
+            let ws = Wallets::new(params)?;
+            Ok(ValidateAddressReport {
+                address: parsed.to_string(),
+                isvalid: true,
+                base58: Some(parsed.to_string()),
+                cashaddr: Some(parsed.encode(AddressFormat::CashAddr, params)?),
+                pubkey_hash: Some(parsed.pub_key_hash().iter().map(|b| format!("{:02x}", b)).collect()),
+                isscript: Some(parsed.is_script()),
+                ismine: Some(ws.get_wallet(&parsed.to_string()).is_some()),
+                pubkey: ws
+                    .get_wallet(&parsed.to_string())
+                    .map(|w| hex::encode(w.get_public_key()))
+                    .filter(|key| !key.is_empty()),
+                error: None,
+            })
+        }
+        Err(e) => Ok(ValidateAddressReport {
+            address: String::from(address),
+            isvalid: false,
+            base58: None,
+            cashaddr: None,
+            pubkey_hash: None,
+            isscript: None,
+            ismine: None,
+            pubkey: None,
+            error: Some(e.to_string()),
+        }),
+    }
+}
+
+fn cmd_list_address(params: &ChainParams) -> Result<Vec<String>> {
+    let ws = Wallets::new(params)?;
+    Ok(ws.get_all_addresses())
+}
+
+fn block_text(b: &Block, params: &ChainParams) -> String {
+    let mut text = format!(
+        "============ Block {} ============\nHash: {}\nPrev. hash: {}\nTimestamp: {}\nNonce: {}\n",
+        b.get_height(),
+        b.get_hash(),
+        b.get_prev_hash(),
+        b.get_timestamp(),
+        b.get_nonce(),
+    );
+    for tx in b.get_transactions() {
+        text += &format!("Transaction {}\n", tx.id);
+        for input in &tx.vin {
+            text += &format!("  in:  {}:{} {}\n", input.txid, input.vout, input.script_sig);
+        }
+        for out in &tx.vout {
+            text += &format!("  out: {} -> {}\n", out.value, OutputReport::new(out, params).text());
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::blockchain::Fixture;
+    use crate::blockchain::apply_utxos;
+    use crate::store;
+
+    /// supply adds up every unspent output of the chain stored under params
+    fn supply(params: &ChainParams) -> i64 {
+        let bc = Blockchain::new(params).unwrap();
+        let mut utxos = HashMap::new();
+        for height in 0..=bc.get_best_height().unwrap() {
+            apply_utxos(&mut utxos, &bc.get_block_by_height(height).unwrap(), params.subsidy, true).unwrap();
+        }
+        utxos.values().map(|out| out.value as i64).sum()
+    }
+
+    #[test]
+    fn test_fees_are_paid_back() {
+        let f = Fixture::new(Network::Regtest);
+        let (params, alice) = (f.params.clone(), f.alice.clone());
+        f.ws.save_all().unwrap();
+        let bob = cmd_create_wallet(&params).unwrap().address;
+        Blockchain::create_blockchain(None, &params).unwrap();
+        // only generate mints coins, the subsidy of each block
+        cmd_generate(&params, 106, Some(&alice), None).unwrap();
+        let minted = 107 * params.subsidy as i64;
+        assert_eq!(supply(&params), minted);
+
+        let selector = CoinSelector::new(Strategy::LargestFirst, 2);
+        let sweep = cmd_sweep(&params, &alice, &bob, &selector).unwrap();
+        assert!(sweep.fee > 0);
+        check_fee_block(&params, &sweep, minted);
+
+        cmd_generate(&params, 3, Some(&alice), None).unwrap();
+        let minted = minted + 3 * params.subsidy as i64;
+        let consolidate = cmd_consolidate(&params, &alice, 2, None, &selector).unwrap();
+        assert!(consolidate.fee > 0);
+        check_fee_block(&params, &consolidate, minted);
     }
-    Ok(())
-}
\ No newline at end of file
+
+    /// check_fee_block asserts the fees of a command came back to the payer in the coinbase
+    /// of its block, and nothing was minted or burned
+    fn check_fee_block(params: &ChainParams, report: &MergeReport, minted: i64) {
+        let bc = Blockchain::new(params).unwrap();
+        let block = bc.get_block(report.block_hash.as_ref().unwrap()).unwrap();
+        assert_eq!(block.get_transactions()[0].output_value(), report.fee as i64);
+        assert_eq!(supply(params), minted);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_sign_after_walletlock() {
+        let params = ChainParams::temporary(Network::Regtest);
+        let mut ws = Wallets::new(&params).unwrap();
+        let address = ws.create_wallet().unwrap();
+        ws.save_all().unwrap();
+        ws.encrypt_with("secret", 4).unwrap();
+        let key = Wallets::new(&params).unwrap().unlock("secret").unwrap();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let socket = params.agent_path();
+        let agent = std::thread::spawn(move || {
+            agent::serve(&socket, &hex::encode(key), 60, |until| Ok(tx.send(until)?)).unwrap()
+        });
+        rx.recv().unwrap();
+        assert!(unlocked_wallets(&params).unwrap().signing_key(&address).is_ok());
+
+        cmd_wallet_lock(&params).unwrap();
+        agent.join().unwrap();
+        let err = unlocked_wallets(&params).err().unwrap();
+        assert_eq!(err.to_string(), "Wallet is locked, run walletpassphrase <timeout> first");
+
+        store::close(&params.data_dir).unwrap();
+        std::fs::remove_dir_all(&params.data_dir).unwrap();
+    }
+}
@@ -0,0 +1,280 @@
+use crate::errors::Result;
+use crate::tx::TXOutput;
+use failure::format_err;
+use rand::seq::SliceRandom;
+use std::str::FromStr;
+
+/// BNB_MAX_TRIES bounds the branch-and-bound search before falling back to largest-first
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// Strategy is the order unspent outputs are picked in to fund a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strategy {
+    /// spends the fewest inputs
+    #[default]
+    LargestFirst,
+    /// spends dust first, at the cost of more inputs
+    SmallestFirst,
+    /// searches for inputs matching the amount so no change is needed
+    BranchAndBound,
+    RandomOrder,
+}
+
+impl FromStr for Strategy {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Strategy> {
+        match s {
+            "largest-first" | "largest" => Ok(Strategy::LargestFirst),
+            "smallest-first" | "smallest" => Ok(Strategy::SmallestFirst),
+            "bnb" | "branch-and-bound" => Ok(Strategy::BranchAndBound),
+            "random" => Ok(Strategy::RandomOrder),
+            _ => Err(format_err!(
+                "unknown coin selection {}, expected largest-first, smallest-first, bnb or random",
+                s
+            )),
+        }
+    }
+}
+
+/// CoinSelector chooses the outputs a transaction spends, charging a fee for every input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CoinSelector {
+    pub strategy: Strategy,
+    pub fee_per_input: i32,
+}
+
+/// Selection is the outputs chosen to pay an amount and the fee they cost
+#[derive(Debug, Clone)]
+pub struct Selection {
+    pub coins: Vec<(String, i32, TXOutput)>,
+    pub total: i32,
+    pub fee: i32,
+}
+
+impl Selection {
+    /// Change returns what is left over once the amount and the fee are paid
+    pub fn change(&self, amount: i32) -> i32 {
+        self.total - amount - self.fee
+    }
+}
+
+/// SumValues adds up the values of coins, failing when they do not fit an amount
+pub fn sum_values(coins: &[(String, i32, TXOutput)]) -> Result<i32> {
+    coins
+        .iter()
+        .try_fold(0i32, |sum, (_, _, out)| sum.checked_add(out.value))
+        .ok_or_else(|| format_err!("the outputs add up to more than {}", i32::MAX))
+}
+
+impl CoinSelector {
+    pub fn new(strategy: Strategy, fee_per_input: i32) -> CoinSelector {
+        CoinSelector { strategy, fee_per_input }
+    }
+
+    /// FeeFor returns the fee of spending a number of inputs, failing when it does not fit an amount
+    pub fn fee_for(&self, inputs: usize) -> Result<i32> {
+        i32::try_from(inputs)
+            .ok()
+            .and_then(|inputs| self.fee_per_input.checked_mul(inputs))
+            .ok_or_else(|| format_err!("a fee of {} for {} inputs is more than {}", self.fee_per_input, inputs, i32::MAX))
+    }
+
+    /// Select picks outputs worth at least amount plus the fee of spending them,
+    /// outputs worth no more than their own fee are never picked
+    pub fn select(&self, coins: Vec<(String, i32, TXOutput)>, amount: i32) -> Result<Selection> {
+        let balance: i64 = coins.iter().map(|(_, _, out)| out.value as i64).sum();
+        let mut coins: Vec<_> = coins
+            .into_iter()
+            .filter(|(_, _, out)| out.value > self.fee_per_input)
+            .collect();
+
+        match self.strategy {
+            Strategy::LargestFirst => coins.sort_by_key(|(_, _, out)| -out.value),
+            Strategy::SmallestFirst => coins.sort_by_key(|(_, _, out)| out.value),
+            Strategy::RandomOrder => coins.shuffle(&mut rand::thread_rng()),
+            Strategy::BranchAndBound => {
+                coins.sort_by_key(|(_, _, out)| -out.value);
+                if let Some(selection) = self.branch_and_bound(&coins, amount) {
+                    return Ok(selection);
+                }
+            }
+        }
+
+        let needed = |fee: i32| {
+            amount
+                .checked_add(fee)
+                .ok_or_else(|| format_err!("{} plus a fee of {} is more than {}", amount, fee, i32::MAX))
+        };
+        let mut selection = Selection { coins: Vec::new(), total: 0, fee: 0 };
+        for coin in coins {
+            if selection.total >= needed(selection.fee)? {
+                break;
+            }
+            selection.total = selection
+                .total
+                .checked_add(coin.2.value)
+                .ok_or_else(|| format_err!("the outputs add up to more than {}", i32::MAX))?;
+            selection.coins.push(coin);
+            selection.fee = self.fee_for(selection.coins.len())?;
+        }
+        if selection.total < needed(selection.fee)? {
+            return Err(format_err!(
+                "Not Enough balance: current balance {}, needed {} plus a fee of {} per input",
+                balance,
+                amount,
+                self.fee_per_input
+            ));
+        }
+        Ok(selection)
+    }
+
+    /// SelectAll picks every output worth more than its fee, smallest first
+    pub fn select_all(&self, coins: Vec<(String, i32, TXOutput)>) -> Result<Selection> {
+        let mut coins: Vec<_> = coins
+            .into_iter()
+            .filter(|(_, _, out)| out.value > self.fee_per_input)
+            .collect();
+        if coins.is_empty() {
+            return Err(format_err!("no unspent outputs worth more than their fee of {} per input", self.fee_per_input));
+        }
+        coins.sort_by_key(|(_, _, out)| out.value);
+        let total = sum_values(&coins)?;
+        let fee = self.fee_for(coins.len())?;
+        Ok(Selection { coins, total, fee })
+    }
+
+    /// BranchAndBound looks for coins, sorted largest first, whose value after fees is the amount,
+    /// up to the fee of one input more which is left as fee instead of making change
+    fn branch_and_bound(&self, coins: &[(String, i32, TXOutput)], amount: i32) -> Option<Selection> {
+        // summed as i64, the search may add up more than an amount can hold
+        let effective: Vec<i64> = coins.iter().map(|(_, _, out)| (out.value - self.fee_per_input) as i64).collect();
+        let mut remaining: i64 = effective.iter().sum();
+        let (amount64, upper) = (amount as i64, amount as i64 + self.fee_per_input as i64);
+
+        // 深度优先: 每个 coin 先试选中, 再试不选
+        let mut picked = vec![false; coins.len()];
+        let mut depth = 0;
+        let mut value = 0;
+        let mut tries = 0;
+        loop {
+            tries += 1;
+            if tries > BNB_MAX_TRIES {
+                return None;
+            }
+            let backtrack = if value > upper || value + remaining < amount64 {
+                true
+            } else if value >= amount64 {
+                break;
+            } else {
+                depth == coins.len()
+            };
+
+            if backtrack {
+                // undo the last coin still picked and try the branch without it
+                while depth > 0 && !picked[depth - 1] {
+                    depth -= 1;
+                    remaining += effective[depth];
+                }
+                if depth == 0 {
+                    return None;
+                }
+                picked[depth - 1] = false;
+                value -= effective[depth - 1];
+            } else {
+                remaining -= effective[depth];
+                picked[depth] = true;
+                value += effective[depth];
+                depth += 1;
+            }
+        }
+
+        let coins: Vec<_> = coins
+            .iter()
+            .zip(picked)
+            .filter(|(_, picked)| *picked)
+            .map(|(coin, _)| coin.clone())
+            .collect();
+        let total = sum_values(&coins).ok()?;
+        Some(Selection { coins, total, fee: total - amount })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::address::Address;
+
+    fn coins(values: &[i32]) -> Vec<(String, i32, TXOutput)> {
+        let address = Address::new(0x00, vec![1; 20]);
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| (format!("{:064x}", i), 0, TXOutput::new(*value, &address)))
+            .collect()
+    }
+
+    fn values(selection: &Selection) -> Vec<i32> {
+        let mut values: Vec<i32> = selection.coins.iter().map(|(_, _, out)| out.value).collect();
+        values.sort();
+        values
+    }
+
+    #[test]
+    fn test_strategies() {
+        let utxos = coins(&[5, 40, 1, 25, 100, 3]);
+
+        let largest = CoinSelector::new(Strategy::LargestFirst, 0).select(utxos.clone(), 110).unwrap();
+        assert_eq!(values(&largest), vec![40, 100]);
+        assert_eq!(largest.change(110), 30);
+
+        let smallest = CoinSelector::new(Strategy::SmallestFirst, 0).select(utxos.clone(), 30).unwrap();
+        assert_eq!(values(&smallest), vec![1, 3, 5, 25]);
+
+        let exact = CoinSelector::new(Strategy::BranchAndBound, 0).select(utxos.clone(), 68).unwrap();
+        assert_eq!(values(&exact), vec![3, 25, 40]);
+        assert_eq!(exact.change(68), 0);
+
+        let random = CoinSelector::new(Strategy::RandomOrder, 0).select(utxos.clone(), 150).unwrap();
+        assert!(random.total >= 150);
+        assert!(CoinSelector::default().select(utxos, 175).is_err());
+    }
+
+    #[test]
+    fn test_fee_per_input() {
+        let utxos = coins(&[5, 40, 1, 25, 100, 3]);
+
+        // dust worth no more than its fee is skipped
+        let selector = CoinSelector::new(Strategy::SmallestFirst, 3);
+        let selection = selector.select(utxos.clone(), 20).unwrap();
+        assert_eq!(values(&selection), vec![5, 25]);
+        assert_eq!(selection.fee, 6);
+        assert_eq!(selection.change(20), 4);
+
+        // an exact match may overpay by up to one input's fee instead of making change
+        let selector = CoinSelector::new(Strategy::BranchAndBound, 2);
+        let selection = selector.select(utxos.clone(), 62).unwrap();
+        assert_eq!(values(&selection), vec![5, 25, 40]);
+        assert_eq!(selection.fee, 8);
+        assert_eq!(selection.change(62), 0);
+
+        // without a match within the window bnb falls back to largest-first
+        let selection = CoinSelector::new(Strategy::BranchAndBound, 2).select(coins(&[10, 10]), 3).unwrap();
+        assert_eq!(selection.change(3), 5);
+
+        assert!(CoinSelector::new(Strategy::LargestFirst, 10).select(utxos.clone(), 150).is_err());
+        // an amount and fee past i32::MAX fail instead of overflowing
+        for strategy in [Strategy::LargestFirst, Strategy::BranchAndBound] {
+            let err = CoinSelector::new(strategy, 1).select(utxos.clone(), i32::MAX).unwrap_err();
+            assert!(err.to_string().contains("more than"));
+        }
+        // outputs or fees adding up past i32::MAX fail as well
+        let big = coins(&[i32::MAX - 1, i32::MAX - 1, 5]);
+        assert!(CoinSelector::new(Strategy::SmallestFirst, 1).select(big.clone(), i32::MAX - 10).is_err());
+        assert!(CoinSelector::new(Strategy::LargestFirst, 0).select_all(big.clone()).is_err());
+        assert!(CoinSelector::new(Strategy::LargestFirst, i32::MAX / 2).fee_for(3).is_err());
+        assert!(sum_values(&big).is_err());
+        assert_eq!("bnb".parse::<Strategy>().unwrap(), Strategy::BranchAndBound);
+        assert!("fifo".parse::<Strategy>().is_err());
+    }
+}
@@ -0,0 +1,108 @@
+use crate::errors::Result;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use failure::format_err;
+use rand::RngCore;
+use rand_core::OsRng;
+use serde::{Deserialize, Serialize};
+
+/// SCRYPT_LOG_N makes each key derivation use 32 MiB of memory
+pub const SCRYPT_LOG_N: u8 = 15;
+
+const CHECK_DATA: &[u8] = b"blockchain-rust wallet";
+const NONCE_LEN: usize = 12;
+
+/// KeyCrypter holds the scrypt settings of an encrypted wallet store and a
+/// value encrypted with its key, so a passphrase can be checked
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KeyCrypter {
+    salt: Vec<u8>,
+    log_n: u8,
+    r: u32,
+    p: u32,
+    check: Vec<u8>,
+}
+
+impl KeyCrypter {
+    /// New derives a key from a passphrase with a fresh salt and returns it with the crypter
+    pub fn new(passphrase: &str, log_n: u8) -> Result<(KeyCrypter, [u8; 32])> {
+        let mut salt = vec![0; 16];
+        OsRng.fill_bytes(&mut salt);
+        let mut crypter = KeyCrypter {
+            salt,
+            log_n,
+            r: 8,
+            p: 1,
+            check: Vec::new(),
+        };
+        let key = crypter.derive_key(passphrase)?;
+        crypter.check = encrypt(&key, CHECK_DATA, b"check")?;
+        Ok((crypter, key))
+    }
+
+    fn derive_key(&self, passphrase: &str) -> Result<[u8; 32]> {
+        let params = scrypt::Params::new(self.log_n, self.r, self.p, 32)
+            .map_err(|e| format_err!("invalid scrypt parameters: {}", e))?;
+        let mut key = [0; 32];
+        scrypt::scrypt(passphrase.as_bytes(), &self.salt, &params, &mut key)
+            .map_err(|e| format_err!("cannot derive wallet key: {}", e))?;
+        Ok(key)
+    }
+
+    /// Unlock derives the key of a passphrase, failing when it is not the wallet's
+    pub fn unlock(&self, passphrase: &str) -> Result<[u8; 32]> {
+        let key = self.derive_key(passphrase)?;
+        self.check_key(&key)?;
+        Ok(key)
+    }
+
+    /// CheckKey fails when a key does not belong to this crypter
+    pub fn check_key(&self, key: &[u8; 32]) -> Result<()> {
+        match decrypt(key, &self.check, b"check") {
+            Ok(data) if data == CHECK_DATA => Ok(()),
+            _ => Err(format_err!("The wallet passphrase entered was incorrect")),
+        }
+    }
+}
+
+/// Encrypt seals data with ChaCha20-Poly1305, returning the random nonce followed by the ciphertext
+pub fn encrypt(key: &[u8; 32], data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let mut nonce = [0; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: data, aad })
+        .map_err(|_| format_err!("encryption failed"))?;
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    Ok(sealed)
+}
+
+/// Decrypt opens data sealed by encrypt, failing if it was changed or the key is wrong
+pub fn decrypt(key: &[u8; 32], sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < NONCE_LEN {
+        return Err(format_err!("encrypted data is too short"));
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    cipher
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| format_err!("decryption failed"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let (crypter, key) = KeyCrypter::new("secret", 4).unwrap();
+        assert_eq!(crypter.unlock("secret").unwrap(), key);
+        assert!(crypter.unlock("wrong").is_err());
+
+        let sealed = encrypt(&key, b"private key", b"address").unwrap();
+        assert_eq!(decrypt(&key, &sealed, b"address").unwrap(), b"private key");
+        assert!(decrypt(&key, &sealed, b"other address").is_err());
+        assert!(decrypt(&[0; 32], &sealed, b"address").is_err());
+    }
+}
@@ -0,0 +1,117 @@
+use crate::errors::Result;
+use bip39::Mnemonic;
+use ed25519_dalek::SigningKey;
+use failure::format_err;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use rand_core::OsRng;
+use sha2::Sha512;
+
+/// HARDENED marks a hardened child index, the only kind SLIP-10 allows for ed25519
+pub const HARDENED: u32 = 0x8000_0000;
+
+/// MNEMONIC_WORDS is the length of a new mnemonic, 128 bits of entropy
+const MNEMONIC_WORDS: usize = 12;
+
+/// GenerateMnemonic returns a new BIP39 mnemonic sentence
+pub fn generate_mnemonic() -> Result<String> {
+    let mut entropy = [0; MNEMONIC_WORDS / 3 * 4];
+    OsRng.fill_bytes(&mut entropy);
+    let mnemonic = Mnemonic::from_entropy(&entropy)
+        .map_err(|e| format_err!("cannot create mnemonic: {}", e))?;
+    Ok(mnemonic.to_string())
+}
+
+/// MnemonicToSeed checks a mnemonic sentence and returns its BIP39 seed
+pub fn mnemonic_to_seed(phrase: &str) -> Result<[u8; 64]> {
+    let mnemonic = Mnemonic::parse(phrase).map_err(|e| format_err!("invalid mnemonic: {}", e))?;
+    Ok(mnemonic.to_seed(""))
+}
+
+/// ExtendedKey is an ed25519 private key with the chain code its children are derived with
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtendedKey {
+    key: [u8; 32],
+    chain_code: [u8; 32],
+}
+
+impl ExtendedKey {
+    /// FromSeed returns the SLIP-10 master key of a seed
+    pub fn from_seed(seed: &[u8]) -> ExtendedKey {
+        ExtendedKey::from_hmac(b"ed25519 seed", seed)
+    }
+
+    fn from_hmac(key: &[u8], data: &[u8]) -> ExtendedKey {
+        let mut mac = Hmac::<Sha512>::new_from_slice(key).expect("HMAC takes keys of any size");
+        mac.update(data);
+        let i = mac.finalize().into_bytes();
+        // 左32字节为私钥, 右32字节为链码
+        let mut ext = ExtendedKey {
+            key: [0; 32],
+            chain_code: [0; 32],
+        };
+        ext.key.copy_from_slice(&i[..32]);
+        ext.chain_code.copy_from_slice(&i[32..]);
+        ext
+    }
+
+    /// DeriveChild returns the hardened child key at an index
+    pub fn derive_child(&self, index: u32) -> ExtendedKey {
+        let mut data = vec![0];
+        data.extend_from_slice(&self.key);
+        data.extend_from_slice(&(index | HARDENED).to_be_bytes());
+        ExtendedKey::from_hmac(&self.chain_code, &data)
+    }
+
+    /// DerivePath walks a path of hardened indexes from this key
+    pub fn derive_path(&self, path: &[u32]) -> ExtendedKey {
+        path.iter().fold(self.clone(), |key, index| key.derive_child(*index))
+    }
+
+    pub fn signing_key(&self) -> SigningKey {
+        SigningKey::from_bytes(&self.key)
+    }
+}
+
+/// AccountPath returns the BIP44 path m/44'/coin'/0'/change'/index', all levels hardened
+pub fn account_path(coin_type: u32, change: bool, index: u32) -> [u32; 5] {
+    [44, coin_type, 0, change as u32, index]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_slip10_vector() {
+        // SLIP-10 test vector 1 for ed25519
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let master = ExtendedKey::from_seed(&seed);
+        assert_eq!(
+            hex::encode(master.key),
+            "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7"
+        );
+        assert_eq!(
+            hex::encode(master.chain_code),
+            "90046a93de5380a72b5e45010748567d5ea02bbf6522f979e05c0d8d8ca9fffb"
+        );
+        let child = master.derive_path(&[0]);
+        assert_eq!(
+            hex::encode(child.key),
+            "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3"
+        );
+        assert_eq!(
+            hex::encode(child.chain_code),
+            "8b59aa11380b624e81507a27fedda59fea6d0b779a778918a2fd3590e16e9c69"
+        );
+    }
+
+    #[test]
+    fn test_mnemonic() {
+        let phrase = generate_mnemonic().unwrap();
+        assert_eq!(phrase.split(' ').count(), MNEMONIC_WORDS);
+        assert_eq!(mnemonic_to_seed(&phrase).unwrap(), mnemonic_to_seed(&phrase).unwrap());
+        assert!(mnemonic_to_seed("not a mnemonic").is_err());
+        assert_eq!(account_path(1, false, 3), [44, 1, 0, 0, 3]);
+    }
+}
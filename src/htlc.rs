@@ -0,0 +1,340 @@
+use crate::address::Address;
+use crate::blockchain::Blockchain;
+use crate::chainparams::ChainParams;
+use crate::errors::Result;
+use crate::script::{
+    Instruction, Script, OP_CHECKLOCKTIMEVERIFY, OP_CHECKSIG, OP_DROP, OP_DUP, OP_ELSE, OP_ENDIF, OP_EQUALVERIFY,
+    OP_HASH160, OP_IF, OP_SHA256, OP_SIZE,
+};
+use crate::transaction::Transaction;
+use crate::tx::{TXInput, TXOutput, SEQUENCE_FINAL};
+use ed25519_dalek::SigningKey;
+use failure::format_err;
+use rand::RngCore;
+use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+
+/// SECRET_LEN is the size of an HTLC preimage, the script refuses any other
+pub const SECRET_LEN: usize = 32;
+
+/// HtlcScript pays to the recipient who reveals the preimage of a hash,
+/// or back to the sender once the lock time has passed:
+///
+/// OP_IF OP_SIZE 32 OP_EQUALVERIFY OP_SHA256 <hash> OP_EQUALVERIFY OP_DUP OP_HASH160 <recipient>
+/// OP_ELSE <lock_time> OP_CHECKLOCKTIMEVERIFY OP_DROP OP_DUP OP_HASH160 <refund>
+/// OP_ENDIF OP_EQUALVERIFY OP_CHECKSIG
+#[derive(Debug, Clone, PartialEq)]
+pub struct HtlcScript {
+    hash: Vec<u8>,
+    recipient: Vec<u8>,
+    refund: Vec<u8>,
+    lock_time: u32,
+}
+
+/// GenerateSecret returns a random preimage
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0; SECRET_LEN];
+    OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+pub fn hash_secret(secret: &[u8]) -> Vec<u8> {
+    Sha256::digest(secret).to_vec()
+}
+
+impl HtlcScript {
+    pub fn new(hash: Vec<u8>, recipient: &Address, refund: &Address, lock_time: u32) -> Result<HtlcScript> {
+        if hash.len() != 32 {
+            return Err(format_err!("the hash lock must be a 32 byte SHA-256 hash"));
+        }
+        if recipient.is_script() || refund.is_script() {
+            return Err(format_err!("the recipient and refund addresses must be key addresses"));
+        }
+        if lock_time == 0 {
+            return Err(format_err!("an HTLC needs a lock time for the refund"));
+        }
+        Ok(HtlcScript {
+            hash,
+            recipient: recipient.pub_key_hash().to_vec(),
+            refund: refund.pub_key_hash().to_vec(),
+            lock_time,
+        })
+    }
+
+    pub fn hash(&self) -> &[u8] {
+        &self.hash
+    }
+
+    pub fn recipient(&self, params: &ChainParams) -> Address {
+        Address::new(params.address_version, self.recipient.clone())
+    }
+
+    pub fn refund(&self, params: &ChainParams) -> Address {
+        Address::new(params.address_version, self.refund.clone())
+    }
+
+    pub fn to_script(&self) -> Script {
+        Script::new()
+            .push_opcode(OP_IF)
+            .push_opcode(OP_SIZE)
+            .push_int(SECRET_LEN as i64)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_SHA256)
+            .push_data(&self.hash)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_DUP)
+            .push_opcode(OP_HASH160)
+            .push_data(&self.recipient)
+            .push_opcode(OP_ELSE)
+            .push_int(self.lock_time as i64)
+            .push_opcode(OP_CHECKLOCKTIMEVERIFY)
+            .push_opcode(OP_DROP)
+            .push_opcode(OP_DUP)
+            .push_opcode(OP_HASH160)
+            .push_data(&self.refund)
+            .push_opcode(OP_ENDIF)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_CHECKSIG)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_script().as_bytes().to_vec()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<HtlcScript> {
+        let script = Script::from_bytes(data.to_vec());
+        let ins: Vec<Instruction> = script.instructions().collect::<Result<_>>()?;
+        use Instruction::{Op, Push};
+        let (hash, recipient, lock_time, refund) = match ins.as_slice() {
+            [
+                Op(OP_IF), Op(OP_SIZE), _, Op(OP_EQUALVERIFY), Op(OP_SHA256), Push(hash), Op(OP_EQUALVERIFY),
+                Op(OP_DUP), Op(OP_HASH160), Push(recipient),
+                Op(OP_ELSE), lock_time, Op(OP_CHECKLOCKTIMEVERIFY), Op(OP_DROP),
+                Op(OP_DUP), Op(OP_HASH160), Push(refund),
+                Op(OP_ENDIF), Op(OP_EQUALVERIFY), Op(OP_CHECKSIG),
+            ] => (hash, recipient, lock_time, refund),
+            _ => return Err(format_err!("not an HTLC script")),
+        };
+        let lock_time = lock_time
+            .as_int()
+            .and_then(|n| u32::try_from(n).ok())
+            .ok_or_else(|| format_err!("not an HTLC script"))?;
+        let htlc = HtlcScript {
+            hash: hash.to_vec(),
+            recipient: recipient.to_vec(),
+            refund: refund.to_vec(),
+            lock_time,
+        };
+        if htlc.to_bytes() != data || htlc.hash.len() != 32 || htlc.recipient.len() != 20 || htlc.refund.len() != 20 {
+            return Err(format_err!("not an HTLC script"));
+        }
+        Ok(htlc)
+    }
+
+    pub fn address(&self, params: &ChainParams) -> Address {
+        Address::from_script(&self.to_bytes(), params)
+    }
+
+    /// FindOutput returns the index and output of a transaction paying to the HTLC
+    pub fn find_output(&self, tx: &Transaction, params: &ChainParams) -> Result<(i32, TXOutput)> {
+        let script_pub_key = Script::from_address(&self.address(params));
+        tx.vout
+            .iter()
+            .position(|out| out.script_pub_key == script_pub_key)
+            .map(|index| (index as i32, tx.vout[index].clone()))
+            .ok_or_else(|| format_err!("Transaction {} does not pay to the HTLC", tx.id))
+    }
+
+    /// Spend builds a transaction moving the HTLC output of a funding transaction to an address:
+    /// a claim by the recipient when the secret is given, else a refund that can be mined
+    /// once the lock time has passed
+    pub fn spend(
+        &self,
+        funding: &Transaction,
+        to: &Address,
+        key: &SigningKey,
+        secret: Option<&[u8]>,
+        params: &ChainParams,
+    ) -> Result<Transaction> {
+        let (vout, prev_out) = self.find_output(funding, params)?;
+        if let Some(secret) = secret {
+            if hash_secret(secret) != self.hash {
+                return Err(format_err!("the secret does not match the hash lock"));
+            }
+        }
+        let (lock_time, sequence) = match secret {
+            Some(_) => (0, SEQUENCE_FINAL),
+            None => (self.lock_time, SEQUENCE_FINAL - 1),
+        };
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: funding.id.clone(),
+                vout,
+                script_sig: Script::new(),
+                sequence,
+            }],
+            vout: vec![TXOutput::new(prev_out.value, to)],
+            lock_time,
+        };
+        tx.sign_input(0, key, &prev_out)?;
+        // 解锁脚本: <签名> <公钥> <原像> OP_1 <HTLC脚本>, 退款时为 <签名> <公钥> OP_0 <HTLC脚本>
+        let script_sig = tx.vin[0].script_sig.clone();
+        let script_sig = match secret {
+            Some(secret) => script_sig.push_data(secret).push_int(1),
+            None => script_sig.push_int(0),
+        };
+        tx.vin[0].script_sig = script_sig.push_data(&self.to_bytes());
+        tx.set_id()?;
+        Ok(tx)
+    }
+
+    /// FindSecret looks on the chain for a claim of the HTLC and returns the secret it revealed
+    pub fn find_secret(&self, bc: &Blockchain) -> Option<Vec<u8>> {
+        let redeem = self.to_bytes();
+        for block in bc.iter() {
+            for tx in block.get_transactions() {
+                for vin in &tx.vin {
+                    match vin.script_sig.pushes().as_deref() {
+                        Some([_, _, secret, _, script]) if *script == redeem && hash_secret(secret) == self.hash => {
+                            return Some(secret.clone());
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::blockchain::Fixture;
+    use crate::coinselect::CoinSelector;
+    use crate::chainparams::Network;
+    use crate::transaction::Locks;
+
+    /// Party holds a wallet and a chain in one data dir
+    struct Party {
+        fixture: Fixture,
+        address: String,
+    }
+
+    impl Party {
+        /// New creates a chain whose coins belong to a fresh wallet address
+        fn new(network: Network) -> Party {
+            let fixture = Fixture::new(network);
+            let address = fixture.alice.clone();
+            Party { fixture, address }
+        }
+
+        fn params(&self) -> &ChainParams {
+            &self.fixture.params
+        }
+
+        fn new_address(&mut self) -> Address {
+            self.fixture.ws.create_wallet().unwrap().parse().unwrap()
+        }
+
+        /// Lock pays an amount into an HTLC and returns the funding transaction
+        fn lock(&mut self, htlc: &HtlcScript, amount: i32) -> Transaction {
+            let to = htlc.address(self.params()).to_string();
+            let fixture = &mut self.fixture;
+            let tx = Transaction::new_UTXO(&self.address, &to, amount, Locks::default(), &CoinSelector::default(), &mut fixture.ws, &fixture.bc).unwrap();
+            fixture.bc.add_block_with_tx(vec![tx.clone()]).unwrap();
+            // the rest of the coins moved to a fresh change address
+            if let Some(change) = tx.vout.get(1).and_then(|out| out.address(self.params())) {
+                self.address = change;
+            }
+            tx
+        }
+
+        fn spend(&mut self, htlc: &HtlcScript, funding: &Transaction, owner: &Address, secret: Option<&[u8]>) -> Result<()> {
+            let key = self.fixture.ws.signing_key(&owner.to_string()).unwrap();
+            let tx = htlc.spend(funding, owner, &key, secret, self.params())?;
+            self.fixture.bc.add_block_with_tx(vec![tx])
+        }
+
+        fn balance(&self, address: &Address) -> i32 {
+            self.fixture.bc.find_UTXO(&address.to_string()).iter().map(|out| out.value).sum()
+        }
+    }
+
+    #[test]
+    fn test_htlc_script() {
+        let params = ChainParams::regtest();
+        let version = params.address_version;
+        let (a, b) = (Address::new(version, vec![1; 20]), Address::new(version, vec![2; 20]));
+        let htlc = HtlcScript::new(hash_secret(b"secret"), &a, &b, 600_000_000).unwrap();
+        assert_eq!(HtlcScript::from_bytes(&htlc.to_bytes()).unwrap(), htlc);
+        assert_eq!(htlc.recipient(&params), a);
+        assert!(htlc.address(&params).is_script());
+        assert!(HtlcScript::from_bytes(&[0x63]).is_err());
+        assert!(HtlcScript::new(vec![1; 20], &a, &b, 10).is_err());
+        assert!(HtlcScript::new(hash_secret(b"secret"), &a, &b, 0).is_err());
+        assert!(HtlcScript::new(hash_secret(b"secret"), &htlc.address(&params), &b, 10).is_err());
+    }
+
+    #[test]
+    fn test_claim_and_refund() {
+        let mut chain = Party::new(Network::Regtest);
+        let (recipient, refund) = (chain.new_address(), chain.address.parse().unwrap());
+        let secret = generate_secret();
+        let height = chain.fixture.bc.get_best_height().unwrap() as u32;
+        let htlc = HtlcScript::new(hash_secret(&secret), &recipient, &refund, height + 3).unwrap();
+
+        let funding = chain.lock(&htlc, 40);
+        assert!(chain.spend(&htlc, &funding, &recipient, Some(&generate_secret())).is_err());
+        assert!(chain.spend(&htlc, &funding, &refund, None).is_err());
+        // the recipient's key with the secret, not just the secret, claims
+        let key = chain.fixture.ws.signing_key(&refund.to_string()).unwrap();
+        let stolen = htlc.spend(&funding, &refund, &key, Some(&secret), chain.params()).unwrap();
+        assert!(chain.fixture.bc.add_block_with_tx(vec![stolen]).is_err());
+        chain.spend(&htlc, &funding, &recipient, Some(&secret)).unwrap();
+        assert_eq!(chain.balance(&recipient), 40);
+
+        // a second HTLC nobody claims goes back after the lock time
+        let unclaimed = HtlcScript::new(hash_secret(&generate_secret()), &recipient, &refund, height + 4).unwrap();
+        let funding = chain.lock(&unclaimed, 25);
+        let before = chain.balance(&refund);
+        assert!(chain.spend(&unclaimed, &funding, &refund, None).is_err());
+        chain.fixture.mine(&recipient.to_string());
+        chain.spend(&unclaimed, &funding, &refund, None).unwrap();
+        assert_eq!(chain.balance(&refund), before + 25);
+        assert_eq!(chain.fixture.bc.verify_chain(None, 4).unwrap().first_bad_height, None);
+    }
+
+    #[test]
+    fn test_atomic_swap() {
+        // alice has coins on regtest and wants bob's coins on testnet
+        let mut regtest = Party::new(Network::Regtest);
+        let mut testnet = Party::new(Network::Test);
+        let alice_regtest: Address = regtest.address.parse().unwrap();
+        let bob_testnet: Address = testnet.address.parse().unwrap();
+        let bob_regtest = regtest.new_address();
+        let alice_testnet = testnet.new_address();
+
+        // alice locks first with the longer timeout, bob locks to the same hash
+        let secret = generate_secret();
+        let height = regtest.fixture.bc.get_best_height().unwrap() as u32;
+        let alice_htlc = HtlcScript::new(hash_secret(&secret), &bob_regtest, &alice_regtest, height + 20).unwrap();
+        let alice_funding = regtest.lock(&alice_htlc, 30);
+
+        let hash = HtlcScript::from_bytes(&alice_htlc.to_bytes()).unwrap().hash().to_vec();
+        let height = testnet.fixture.bc.get_best_height().unwrap() as u32;
+        let bob_htlc = HtlcScript::new(hash, &alice_testnet, &bob_testnet, height + 10).unwrap();
+        let bob_funding = testnet.lock(&bob_htlc, 20);
+
+        // alice claims on testnet, revealing the secret bob then claims with on regtest
+        assert_eq!(bob_htlc.find_secret(&testnet.fixture.bc), None);
+        testnet.spend(&bob_htlc, &bob_funding, &alice_testnet, Some(&secret)).unwrap();
+        let revealed = bob_htlc.find_secret(&testnet.fixture.bc).unwrap();
+        regtest.spend(&alice_htlc, &alice_funding, &bob_regtest, Some(&revealed)).unwrap();
+
+        assert_eq!(testnet.balance(&alice_testnet), 20);
+        assert_eq!(regtest.balance(&bob_regtest), 30);
+        assert!(regtest.spend(&alice_htlc, &alice_funding, &alice_regtest, None).is_err());
+    }
+}
@@ -1,17 +1,29 @@
 use crate::errors::Result;
 use crate::cli::Cli;
 
+mod address;
+mod agent;
 mod block;
+mod bootstrap;
+mod coinselect;
+mod chainparams;
+mod crypter;
+mod hd;
 mod blockchain;
 mod errors;
+mod script;
+mod server;
+mod store;
 mod tx;
+mod psbt;
+mod multisig;
+mod htlc;
 mod transaction;
 mod cli;
 mod wallet;
 
 fn main() ->Result<()> {
     let mut cli = Cli::new()?;
-    println!("main run.....");
     cli.run()?;
     Ok(())
 }
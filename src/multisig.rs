@@ -0,0 +1,103 @@
+use crate::address::Address;
+use crate::chainparams::ChainParams;
+use crate::errors::Result;
+use crate::script::{Script, ScriptTemplate, OP_CHECKMULTISIG};
+use ed25519_dalek::VerifyingKey;
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+
+/// MAX_KEYS is the largest N of an M-of-N script
+pub const MAX_KEYS: usize = 16;
+
+/// MultisigScript locks an output to M valid signatures by N public keys,
+/// its address is the hash of the script OP_M <key>... OP_N OP_CHECKMULTISIG
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MultisigScript {
+    required: u8,
+    public_keys: Vec<[u8; 32]>,
+}
+
+impl MultisigScript {
+    pub fn new(required: usize, public_keys: Vec<Vec<u8>>) -> Result<MultisigScript> {
+        if public_keys.is_empty() || public_keys.len() > MAX_KEYS {
+            return Err(format_err!("a multisig script takes 1 to {} public keys", MAX_KEYS));
+        }
+        if required == 0 || required > public_keys.len() {
+            return Err(format_err!(
+                "required signatures must be between 1 and {}",
+                public_keys.len()
+            ));
+        }
+        let mut keys: Vec<[u8; 32]> = Vec::new();
+        for key in public_keys {
+            let key: [u8; 32] = key
+                .as_slice()
+                .try_into()
+                .map_err(|_| format_err!("public key {} is not 32 bytes", hex::encode(&key)))?;
+            VerifyingKey::from_bytes(&key)
+                .map_err(|_| format_err!("{} is not a valid public key", hex::encode(key)))?;
+            if keys.contains(&key) {
+                return Err(format_err!("public key {} is listed twice", hex::encode(key)));
+            }
+            keys.push(key);
+        }
+        Ok(MultisigScript {
+            required: required as u8,
+            public_keys: keys,
+        })
+    }
+
+    pub fn required(&self) -> usize {
+        self.required as usize
+    }
+
+    pub fn public_keys(&self) -> &[[u8; 32]] {
+        &self.public_keys
+    }
+
+    pub fn to_script(&self) -> Script {
+        let mut script = Script::new().push_int(self.required as i64);
+        for key in &self.public_keys {
+            script = script.push_data(key);
+        }
+        script.push_int(self.public_keys.len() as i64).push_opcode(OP_CHECKMULTISIG)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_script().as_bytes().to_vec()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<MultisigScript> {
+        match Script::from_bytes(data.to_vec()).template() {
+            ScriptTemplate::Multisig { required, public_keys } => MultisigScript::new(required, public_keys),
+            _ => Err(format_err!("not a multisig script")),
+        }
+    }
+
+    pub fn address(&self, params: &ChainParams) -> Address {
+        Address::from_script(&self.to_bytes(), params)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+    use rand_core::OsRng;
+
+    #[test]
+    fn test_multisig_script() {
+        let keys: Vec<SigningKey> = (0..3).map(|_| SigningKey::generate(&mut OsRng)).collect();
+        let public_keys: Vec<Vec<u8>> = keys.iter().map(|k| k.verifying_key().to_bytes().to_vec()).collect();
+        let script = MultisigScript::new(2, public_keys.clone()).unwrap();
+        assert_eq!(MultisigScript::from_bytes(&script.to_bytes()).unwrap(), script);
+        assert!(script.address(&ChainParams::main()).is_script());
+
+        assert!(MultisigScript::new(0, public_keys.clone()).is_err());
+        assert!(MultisigScript::new(4, public_keys.clone()).is_err());
+        assert!(MultisigScript::new(1, vec![public_keys[0].clone(), public_keys[0].clone()]).is_err());
+
+        assert!(MultisigScript::from_bytes(&[2, 3]).is_err());
+        assert_eq!(script.to_script().to_asm().split(' ').next_back(), Some("OP_CHECKMULTISIG"));
+    }
+}
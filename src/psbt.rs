@@ -0,0 +1,289 @@
+use crate::address::{hash_pub_key, Address};
+use crate::blockchain::Blockchain;
+use crate::chainparams::ChainParams;
+use crate::errors::Result;
+use crate::multisig::MultisigScript;
+use crate::script::Script;
+use crate::transaction::Transaction;
+use crate::tx::TXOutput;
+use crate::wallet::Wallets;
+use base64::Engine;
+use bincode::{deserialize, serialize};
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+// A partial transaction is written as MAGIC, the network magic and the bincode encoded
+// PartialTransaction. A finished transaction is its plain bincode encoding.
+const MAGIC: &[u8; 4] = b"ptx\xff";
+
+/// TxEncoding selects how transactions are written for moving them between machines
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TxEncoding {
+    Hex,
+    Base64,
+}
+
+impl FromStr for TxEncoding {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<TxEncoding> {
+        match s {
+            "hex" => Ok(TxEncoding::Hex),
+            "base64" => Ok(TxEncoding::Base64),
+            _ => Err(format_err!("unknown transaction encoding '{}'", s)),
+        }
+    }
+}
+
+/// EncodeBytes writes data as hex or base64
+pub fn encode_bytes(data: &[u8], encoding: TxEncoding) -> String {
+    match encoding {
+        TxEncoding::Hex => hex::encode(data),
+        TxEncoding::Base64 => base64::engine::general_purpose::STANDARD.encode(data),
+    }
+}
+
+/// DecodeBytes reads data written by encode_bytes, the encoding is never guessed
+/// since an even run of hex digits is valid base64 too
+pub fn decode_bytes(s: &str, encoding: TxEncoding) -> Result<Vec<u8>> {
+    let s = s.trim();
+    match encoding {
+        TxEncoding::Hex => hex::decode(s).map_err(|_| format_err!("transaction is not hex")),
+        TxEncoding::Base64 => base64::engine::general_purpose::STANDARD
+            .decode(s)
+            .map_err(|_| format_err!("transaction is not base64")),
+    }
+}
+
+/// PartialTransaction is a transaction with the outputs its inputs spend,
+/// so it can be signed on a machine without the chain
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PartialTransaction {
+    pub tx: Transaction,
+    pub prev_outs: Vec<TXOutput>,
+}
+
+impl PartialTransaction {
+    /// New looks up the outputs spent by a transaction
+    pub fn new(tx: Transaction, bc: &Blockchain) -> Result<PartialTransaction> {
+        let mut prev_outs = Vec::new();
+        for vin in &tx.vin {
+            let prev_tx = bc.find_transacton(&vin.txid)?;
+            match prev_tx.vout.get(vin.vout as usize) {
+                Some(out) if vin.vout >= 0 => prev_outs.push(out.clone()),
+                _ => return Err(format_err!("Previous output {}:{} does not exist", vin.txid, vin.vout)),
+            }
+        }
+        Ok(PartialTransaction { tx, prev_outs })
+    }
+
+    /// AddScripts puts the multisig scripts the wallet knows into the inputs spending them,
+    /// so co-signers without the script can sign
+    pub fn add_scripts(&mut self, wallets: &Wallets) {
+        for index in 0..self.prev_outs.len() {
+            if self.input_script(index).is_some() {
+                continue;
+            }
+            let address = self.prev_outs[index].address(wallets.get_params());
+            if let Some(script) = address.and_then(|address| wallets.get_script(&address)) {
+                self.tx.vin[index].script_sig = Script::new().push_data(&script.to_bytes());
+            }
+        }
+    }
+
+    /// Sign adds signatures with the keys in the wallet and returns how many it added,
+    /// inputs of other or watch-only addresses are left for another signer
+    pub fn sign(&mut self, wallets: &Wallets) -> Result<usize> {
+        self.add_scripts(wallets);
+        let mut signed = 0;
+        for (index, prev_out) in self.prev_outs.iter().enumerate() {
+            if let Some(script) = self.input_script(index) {
+                for public_key in script.public_keys() {
+                    let address = Address::from_pub_key(public_key, wallets.get_params().address_version).to_string();
+                    match wallets.get_wallet(&address) {
+                        Some(wallet) if !wallet.is_watch_only() => {
+                            let key = wallets.signing_key(&address)?;
+                            if self.tx.sign_multisig_input(index, &key, prev_out, &script)? {
+                                signed += 1;
+                            }
+                        }
+                        _ => continue,
+                    }
+                }
+                continue;
+            }
+            let address = prev_out.address(wallets.get_params()).unwrap_or_default();
+            match wallets.get_wallet(&address) {
+                Some(wallet) if !wallet.is_watch_only() => {
+                    let key = wallets.signing_key(&address)?;
+                    self.tx.sign_input(index, &key, prev_out)?;
+                    signed += 1;
+                }
+                _ => continue,
+            }
+        }
+        self.tx.set_id()?;
+        Ok(signed)
+    }
+
+    /// InputScript returns the multisig script carried by an input spending a multisig output
+    fn input_script(&self, index: usize) -> Option<MultisigScript> {
+        let redeem = self.tx.vin[index].script_sig.pushes()?.pop()?;
+        if self.prev_outs[index].script_pub_key != Script::new_script_hash(&hash_pub_key(&redeem)) {
+            return None;
+        }
+        MultisigScript::from_bytes(&redeem).ok()
+    }
+
+    /// IsComplete tells whether every input carries valid signatures
+    pub fn is_complete(&self) -> bool {
+        (0..self.tx.vin.len()).all(|index| self.tx.verify_input(index, &self.prev_outs[index]).unwrap_or(false))
+    }
+
+    /// Finalize returns the signed transaction, failing while inputs are unsigned
+    pub fn finalize(self) -> Result<Transaction> {
+        if !self.is_complete() {
+            return Err(format_err!("Transaction {} is not completely signed", self.tx.id));
+        }
+        Ok(self.tx)
+    }
+
+    pub fn encode(&self, params: &ChainParams, encoding: TxEncoding) -> Result<String> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&params.magic);
+        data.extend(serialize(self)?);
+        Ok(encode_bytes(&data, encoding))
+    }
+
+    pub fn decode(s: &str, encoding: TxEncoding, params: &ChainParams) -> Result<PartialTransaction> {
+        let data = decode_bytes(s, encoding)?;
+        if data.len() < 8 || &data[..4] != MAGIC {
+            return Err(format_err!("not a partially signed transaction"));
+        }
+        params.check_magic(&data[4..8], "partially signed transaction")?;
+        let ptx: PartialTransaction = deserialize(&data[8..])?;
+        if ptx.prev_outs.len() != ptx.tx.vin.len() {
+            return Err(format_err!("partially signed transaction does not list every spent output"));
+        }
+        Ok(ptx)
+    }
+}
+
+/// DecodeSigned reads a finished transaction, either a complete partial transaction or a raw one
+pub fn decode_signed(s: &str, encoding: TxEncoding, params: &ChainParams) -> Result<Transaction> {
+    let data = decode_bytes(s, encoding)?;
+    if data.starts_with(MAGIC) {
+        return PartialTransaction::decode(s, encoding, params)?.finalize();
+    }
+    Transaction::decode(s, encoding)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::blockchain::Fixture;
+    use crate::chainparams::Network;
+    use crate::tx::{TXInput, SEQUENCE_FINAL};
+
+    #[test]
+    fn test_encoding_is_not_guessed() {
+        // valid hex and valid base64 at once, read as asked
+        assert_eq!(decode_bytes("deadbeef", TxEncoding::Hex).unwrap(), vec![0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(decode_bytes("deadbeef", TxEncoding::Base64).unwrap(), vec![0x75, 0xe6, 0x9d, 0x6d, 0xe7, 0x9f]);
+        assert!(decode_bytes("AQID+w==", TxEncoding::Hex).is_err());
+        assert_eq!(decode_bytes(" 0102 ", TxEncoding::Hex).unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_sign_offline() {
+        let mut f = Fixture::new(Network::Test);
+        let alice = f.alice.clone();
+        let bob = f.ws.create_wallet().unwrap();
+        let (params, ws) = (&f.params, &f.ws);
+        let coins = |to: &str| Transaction::new_coinbase(to.to_string(), String::new(), 50, Some((1, 0))).unwrap();
+        let (a, b) = (coins(&alice), coins(&bob));
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![
+                TXInput { txid: a.id.clone(), vout: 0, script_sig: Script::new(), sequence: SEQUENCE_FINAL },
+                TXInput { txid: b.id.clone(), vout: 0, script_sig: Script::new(), sequence: SEQUENCE_FINAL },
+            ],
+            vout: vec![TXOutput::new(100, &Address::new(0x6f, vec![1; 20]))],
+            lock_time: 0,
+        };
+        tx.set_id().unwrap();
+        let mut ptx = PartialTransaction { tx, prev_outs: vec![a.vout[0].clone(), b.vout[0].clone()] };
+
+        // a wallet holding only alice's key signs her input
+        let mut only_alice = f.wallets(Network::Test);
+        only_alice.import_priv_key(&ws.dump_priv_key(&alice).unwrap()).unwrap();
+        assert_eq!(ptx.sign(&only_alice).unwrap(), 1);
+        assert!(!ptx.is_complete());
+
+        for encoding in [TxEncoding::Hex, TxEncoding::Base64] {
+            let encoded = ptx.encode(params, encoding).unwrap();
+            assert!(PartialTransaction::decode(&encoded, encoding, &ChainParams::main()).is_err());
+            assert!(decode_signed(&encoded, encoding, params).is_err());
+            let mut decoded = PartialTransaction::decode(&encoded, encoding, params).unwrap();
+            assert_eq!(decoded.sign(ws).unwrap(), 2);
+            let signed = decode_signed(&decoded.encode(params, encoding).unwrap(), encoding, params).unwrap();
+            let raw = Transaction::decode(&signed.encode(encoding).unwrap(), encoding).unwrap();
+            assert_eq!(raw.id, signed.id);
+            let prev_txs = [a.clone(), b.clone()].into_iter().map(|t| (t.id.clone(), t)).collect();
+            assert!(raw.verify(&prev_txs).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_multisig_cosigning() {
+        let f = Fixture::new(Network::Regtest);
+        let signers: Vec<Wallets> = (0..3)
+            .map(|_| {
+                let mut ws = f.wallets(Network::Regtest);
+                ws.create_wallet().unwrap();
+                ws
+            })
+            .collect();
+        let public_keys = signers
+            .iter()
+            .map(|ws| ws.get_wallet(&ws.get_all_addresses()[0]).unwrap().get_public_key().to_vec())
+            .collect();
+        let script = MultisigScript::new(2, public_keys).unwrap();
+
+        // the online wallet only watches the multisig address
+        let mut online = f.wallets(Network::Regtest);
+        let address = online.add_multisig(script.clone()).unwrap();
+        let prev = Transaction::new_coinbase(address.clone(), String::new(), 50, Some((1, 0))).unwrap();
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput { txid: prev.id.clone(), vout: 0, script_sig: Script::new(), sequence: SEQUENCE_FINAL }],
+            vout: vec![TXOutput::new(50, &Address::new(0x6f, vec![1; 20]))],
+            lock_time: 0,
+        };
+        tx.set_id().unwrap();
+        let mut ptx = PartialTransaction { tx, prev_outs: vec![prev.vout[0].clone()] };
+        assert_eq!(ptx.sign(&online).unwrap(), 0);
+
+        assert_eq!(ptx.sign(&signers[2]).unwrap(), 1);
+        assert_eq!(ptx.sign(&signers[2]).unwrap(), 0);
+        assert!(!ptx.is_complete());
+        let prev_txs = [(prev.id.clone(), prev.clone())].into_iter().collect();
+        assert!(!ptx.tx.verify(&prev_txs).unwrap());
+
+        assert_eq!(ptx.sign(&signers[0]).unwrap(), 1);
+        assert!(ptx.is_complete());
+        assert_eq!(ptx.sign(&signers[1]).unwrap(), 0);
+        let tx = ptx.finalize().unwrap();
+        assert!(tx.verify(&prev_txs).unwrap());
+
+        // the same signature twice does not make two signers
+        let mut doubled = tx.clone();
+        let pushes = tx.vin[0].script_sig.pushes().unwrap();
+        doubled.vin[0].script_sig = Script::new()
+            .push_data(&pushes[0])
+            .push_data(&pushes[0])
+            .push_data(&pushes[2]);
+        assert!(!doubled.verify(&prev_txs).unwrap());
+    }
+}
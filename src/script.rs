@@ -0,0 +1,744 @@
+use crate::address::{hash_pub_key, Address};
+use crate::chainparams::ChainParams;
+use crate::errors::Result;
+use crate::tx::SEQUENCE_LOCKTIME_DISABLE_FLAG;
+use failure::format_err;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+pub const OP_0: u8 = 0x00;
+pub const OP_PUSHDATA1: u8 = 0x4c;
+pub const OP_PUSHDATA2: u8 = 0x4d;
+pub const OP_1NEGATE: u8 = 0x4f;
+pub const OP_1: u8 = 0x51;
+pub const OP_16: u8 = 0x60;
+pub const OP_NOP: u8 = 0x61;
+pub const OP_IF: u8 = 0x63;
+pub const OP_NOTIF: u8 = 0x64;
+pub const OP_ELSE: u8 = 0x67;
+pub const OP_ENDIF: u8 = 0x68;
+pub const OP_VERIFY: u8 = 0x69;
+pub const OP_RETURN: u8 = 0x6a;
+pub const OP_DROP: u8 = 0x75;
+pub const OP_DUP: u8 = 0x76;
+pub const OP_SWAP: u8 = 0x7c;
+pub const OP_SIZE: u8 = 0x82;
+pub const OP_EQUAL: u8 = 0x87;
+pub const OP_EQUALVERIFY: u8 = 0x88;
+pub const OP_SHA256: u8 = 0xa8;
+pub const OP_HASH160: u8 = 0xa9;
+pub const OP_CHECKSIG: u8 = 0xac;
+pub const OP_CHECKSIGVERIFY: u8 = 0xad;
+pub const OP_CHECKMULTISIG: u8 = 0xae;
+pub const OP_CHECKLOCKTIMEVERIFY: u8 = 0xb1;
+pub const OP_CHECKSEQUENCEVERIFY: u8 = 0xb2;
+
+// limits keeping the interpreter bounded
+const MAX_SCRIPT_SIZE: usize = 10_000;
+const MAX_ELEMENT_SIZE: usize = 520;
+const MAX_OPS: usize = 201;
+const MAX_STACK_SIZE: usize = 1000;
+const MAX_MULTISIG_KEYS: i64 = 16;
+
+/// MAX_NULL_DATA_SIZE is the most data an OP_RETURN output may carry
+pub const MAX_NULL_DATA_SIZE: usize = 80;
+
+/// LOCKTIME_THRESHOLD separates lock times given as block heights (below) from unix times in seconds
+pub const LOCKTIME_THRESHOLD: i64 = 500_000_000;
+
+fn op_name(op: u8) -> String {
+    let name = match op {
+        OP_0 => "OP_0",
+        OP_PUSHDATA1 => "OP_PUSHDATA1",
+        OP_PUSHDATA2 => "OP_PUSHDATA2",
+        OP_1NEGATE => "OP_1NEGATE",
+        OP_NOP => "OP_NOP",
+        OP_IF => "OP_IF",
+        OP_NOTIF => "OP_NOTIF",
+        OP_ELSE => "OP_ELSE",
+        OP_ENDIF => "OP_ENDIF",
+        OP_VERIFY => "OP_VERIFY",
+        OP_RETURN => "OP_RETURN",
+        OP_DROP => "OP_DROP",
+        OP_DUP => "OP_DUP",
+        OP_SWAP => "OP_SWAP",
+        OP_SIZE => "OP_SIZE",
+        OP_EQUAL => "OP_EQUAL",
+        OP_EQUALVERIFY => "OP_EQUALVERIFY",
+        OP_SHA256 => "OP_SHA256",
+        OP_HASH160 => "OP_HASH160",
+        OP_CHECKSIG => "OP_CHECKSIG",
+        OP_CHECKSIGVERIFY => "OP_CHECKSIGVERIFY",
+        OP_CHECKMULTISIG => "OP_CHECKMULTISIG",
+        OP_CHECKLOCKTIMEVERIFY => "OP_CHECKLOCKTIMEVERIFY",
+        OP_CHECKSEQUENCEVERIFY => "OP_CHECKSEQUENCEVERIFY",
+        OP_1..=OP_16 => return format!("OP_{}", op - OP_1 + 1),
+        _ => return format!("OP_UNKNOWN_{:02x}", op),
+    };
+    String::from(name)
+}
+
+/// Instruction is one step of a script: an opcode or pushed data
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction<'a> {
+    Op(u8),
+    Push(&'a [u8]),
+}
+
+impl Instruction<'_> {
+    /// AsInt reads a number pushed by the instruction
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Instruction::Push(data) => decode_num(data, 5).ok(),
+            Instruction::Op(op @ (OP_1NEGATE | OP_1..=OP_16)) => decode_num(&small_int(*op), 1).ok(),
+            Instruction::Op(_) => None,
+        }
+    }
+}
+
+/// Script is a program of opcodes locking an output (script_pub_key) or
+/// unlocking it (script_sig). It is written as hex in JSON
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Script(Vec<u8>);
+
+/// ScriptTemplate is a standard script form the wallet knows how to show and spend
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptTemplate {
+    PubKeyHash(Vec<u8>),
+    ScriptHash(Vec<u8>),
+    Multisig { required: usize, public_keys: Vec<Vec<u8>> },
+    NullData(Vec<u8>),
+    NonStandard,
+}
+
+impl ScriptTemplate {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScriptTemplate::PubKeyHash(_) => "pubkeyhash",
+            ScriptTemplate::ScriptHash(_) => "scripthash",
+            ScriptTemplate::Multisig { .. } => "multisig",
+            ScriptTemplate::NullData(_) => "nulldata",
+            ScriptTemplate::NonStandard => "nonstandard",
+        }
+    }
+}
+
+impl Script {
+    pub fn new() -> Script {
+        Script(Vec::new())
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Script {
+        Script(data)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn push_opcode(mut self, op: u8) -> Script {
+        self.0.push(op);
+        self
+    }
+
+    /// PushData appends the shortest push of some data
+    pub fn push_data(mut self, data: &[u8]) -> Script {
+        match data.len() {
+            0 => self.0.push(OP_0),
+            n if n < OP_PUSHDATA1 as usize => self.0.push(n as u8),
+            n if n <= 0xff => {
+                self.0.push(OP_PUSHDATA1);
+                self.0.push(n as u8);
+            }
+            n => {
+                self.0.push(OP_PUSHDATA2);
+                self.0.extend_from_slice(&(n as u16).to_le_bytes());
+            }
+        }
+        self.0.extend_from_slice(data);
+        self
+    }
+
+    /// PushInt appends a number, as a small-number opcode when there is one
+    pub fn push_int(self, n: i64) -> Script {
+        match n {
+            0 => self.push_opcode(OP_0),
+            -1 => self.push_opcode(OP_1NEGATE),
+            1..=16 => self.push_opcode(OP_1 + n as u8 - 1),
+            _ => self.push_data(&encode_num(n)),
+        }
+    }
+
+    /// NewPubKeyHash locks to the key of a pubkey hash: OP_DUP OP_HASH160 <hash> OP_EQUALVERIFY OP_CHECKSIG
+    pub fn new_pub_key_hash(hash: &[u8]) -> Script {
+        Script::new()
+            .push_opcode(OP_DUP)
+            .push_opcode(OP_HASH160)
+            .push_data(hash)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_CHECKSIG)
+    }
+
+    /// NewScriptHash locks to a script revealed when spending: OP_HASH160 <hash> OP_EQUAL
+    pub fn new_script_hash(hash: &[u8]) -> Script {
+        Script::new()
+            .push_opcode(OP_HASH160)
+            .push_data(hash)
+            .push_opcode(OP_EQUAL)
+    }
+
+    /// NewNullData returns an unspendable output script carrying data: OP_RETURN <data>
+    pub fn new_null_data(data: &[u8]) -> Script {
+        Script::new().push_opcode(OP_RETURN).push_data(data)
+    }
+
+    /// FromAddress returns the standard locking script of an address
+    pub fn from_address(address: &Address) -> Script {
+        if address.is_script() {
+            Script::new_script_hash(address.pub_key_hash())
+        } else {
+            Script::new_pub_key_hash(address.pub_key_hash())
+        }
+    }
+
+    pub fn instructions(&self) -> Instructions<'_> {
+        Instructions { data: &self.0, pos: 0 }
+    }
+
+    /// Pushes returns the data of a script made only of pushes, None for any other script
+    pub fn pushes(&self) -> Option<Vec<Vec<u8>>> {
+        let mut pushes = Vec::new();
+        for ins in self.instructions() {
+            match ins.ok()? {
+                Instruction::Push(data) => pushes.push(data.to_vec()),
+                Instruction::Op(op @ (OP_1NEGATE | OP_1..=OP_16)) => pushes.push(small_int(op)),
+                Instruction::Op(_) => return None,
+            }
+        }
+        Some(pushes)
+    }
+
+    pub fn is_push_only(&self) -> bool {
+        self.pushes().is_some()
+    }
+
+    /// Template recognises the standard script forms
+    pub fn template(&self) -> ScriptTemplate {
+        let ins: Vec<Instruction> = match self.instructions().collect::<Result<_>>() {
+            Ok(ins) => ins,
+            Err(_) => return ScriptTemplate::NonStandard,
+        };
+        use Instruction::{Op, Push};
+        match ins.as_slice() {
+            [Op(OP_DUP), Op(OP_HASH160), Push(hash), Op(OP_EQUALVERIFY), Op(OP_CHECKSIG)] if hash.len() == 20 => {
+                ScriptTemplate::PubKeyHash(hash.to_vec())
+            }
+            [Op(OP_HASH160), Push(hash), Op(OP_EQUAL)] if hash.len() == 20 => ScriptTemplate::ScriptHash(hash.to_vec()),
+            [Op(OP_RETURN)] => ScriptTemplate::NullData(Vec::new()),
+            [Op(OP_RETURN), Push(data)] => ScriptTemplate::NullData(data.to_vec()),
+            [Op(m @ OP_1..=OP_16), keys @ .., Op(n @ OP_1..=OP_16), Op(OP_CHECKMULTISIG)]
+                if keys.len() == (n - OP_1 + 1) as usize && m <= n =>
+            {
+                let mut public_keys = Vec::new();
+                for key in keys {
+                    match key {
+                        Push(key) if key.len() == 32 => public_keys.push(key.to_vec()),
+                        _ => return ScriptTemplate::NonStandard,
+                    }
+                }
+                ScriptTemplate::Multisig {
+                    required: (m - OP_1 + 1) as usize,
+                    public_keys,
+                }
+            }
+            _ => ScriptTemplate::NonStandard,
+        }
+    }
+
+    /// Address returns the address a standard locking script pays to
+    pub fn address(&self, params: &ChainParams) -> Option<Address> {
+        match self.template() {
+            ScriptTemplate::PubKeyHash(hash) => Some(Address::new(params.address_version, hash)),
+            ScriptTemplate::ScriptHash(hash) => Some(Address::new(params.script_version, hash)),
+            _ => None,
+        }
+    }
+
+    /// IsUnspendable tells whether no unlocking script can spend the script
+    pub fn is_unspendable(&self) -> bool {
+        self.0.first() == Some(&OP_RETURN) || self.0.len() > MAX_SCRIPT_SIZE
+    }
+
+    /// ToAsm writes the script as opcode names and hex data
+    pub fn to_asm(&self) -> String {
+        let mut parts = Vec::new();
+        for ins in self.instructions() {
+            match ins {
+                Ok(Instruction::Push([])) => parts.push(op_name(OP_0)),
+                Ok(Instruction::Push(data)) => parts.push(hex::encode(data)),
+                Ok(Instruction::Op(op)) => parts.push(op_name(op)),
+                Err(_) => {
+                    parts.push(String::from("[error]"));
+                    break;
+                }
+            }
+        }
+        parts.join(" ")
+    }
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_asm())
+    }
+}
+
+impl Serialize for Script {
+    fn serialize<S: Serializer>(&self, s: S) -> std::result::Result<S::Ok, S::Error> {
+        if s.is_human_readable() {
+            s.serialize_str(&hex::encode(&self.0))
+        } else {
+            self.0.serialize(s)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Script {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> std::result::Result<Script, D::Error> {
+        if d.is_human_readable() {
+            let s = String::deserialize(d)?;
+            hex::decode(s).map(Script).map_err(de::Error::custom)
+        } else {
+            Vec::<u8>::deserialize(d).map(Script)
+        }
+    }
+}
+
+/// Instructions walks the instructions of a script, failing on a truncated push
+pub struct Instructions<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = Result<Instruction<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let op = *self.data.get(self.pos)?;
+        self.pos += 1;
+        let (len, header) = match op {
+            OP_0 => return Some(Ok(Instruction::Push(&[]))),
+            n if n < OP_PUSHDATA1 => (n as usize, 0),
+            OP_PUSHDATA1 => match self.data.get(self.pos) {
+                Some(n) => (*n as usize, 1),
+                None => return Some(self.truncated()),
+            },
+            OP_PUSHDATA2 => match self.data.get(self.pos..self.pos + 2) {
+                Some(n) => (u16::from_le_bytes([n[0], n[1]]) as usize, 2),
+                None => return Some(self.truncated()),
+            },
+            _ => return Some(Ok(Instruction::Op(op))),
+        };
+        let start = self.pos + header;
+        match self.data.get(start..start + len) {
+            Some(data) => {
+                self.pos = start + len;
+                Some(Ok(Instruction::Push(data)))
+            }
+            None => Some(self.truncated()),
+        }
+    }
+}
+
+impl Instructions<'_> {
+    fn truncated<'a>(&mut self) -> Result<Instruction<'a>> {
+        self.pos = self.data.len();
+        Err(format_err!("script ends inside a push"))
+    }
+}
+
+fn small_int(op: u8) -> Vec<u8> {
+    match op {
+        OP_1NEGATE => encode_num(-1),
+        _ => encode_num((op - OP_1 + 1) as i64),
+    }
+}
+
+// 脚本数字: 小端序, 最高字节的最高位为符号位
+fn encode_num(n: i64) -> Vec<u8> {
+    if n == 0 {
+        return Vec::new();
+    }
+    let mut abs = n.unsigned_abs();
+    let mut data = Vec::new();
+    while abs > 0 {
+        data.push((abs & 0xff) as u8);
+        abs >>= 8;
+    }
+    if data.last().is_some_and(|b| b & 0x80 != 0) {
+        data.push(if n < 0 { 0x80 } else { 0 });
+    } else if n < 0 {
+        *data.last_mut().unwrap() |= 0x80;
+    }
+    data
+}
+
+fn decode_num(data: &[u8], max_len: usize) -> Result<i64> {
+    if data.len() > max_len {
+        return Err(format_err!("script number is longer than {} bytes", max_len));
+    }
+    let last = match data.last() {
+        Some(last) => *last,
+        None => return Ok(0),
+    };
+    let mut n: i64 = 0;
+    for (i, b) in data.iter().enumerate() {
+        n |= (*b as i64) << (8 * i);
+    }
+    if last & 0x80 != 0 {
+        n &= !(0x80_i64 << (8 * (data.len() - 1)));
+        n = -n;
+    }
+    Ok(n)
+}
+
+fn cast_to_bool(data: &[u8]) -> bool {
+    match data.split_last() {
+        None => false,
+        Some((last, rest)) => rest.iter().any(|b| *b != 0) || (*last != 0 && *last != 0x80),
+    }
+}
+
+fn encode_bool(b: bool) -> Vec<u8> {
+    if b {
+        vec![1]
+    } else {
+        Vec::new()
+    }
+}
+
+/// Checker gives a script what lies outside of it: signatures over the spending
+/// transaction and the lock time and sequence it was built with
+pub trait Checker {
+    fn check_sig(&self, signature: &[u8], public_key: &[u8]) -> bool;
+    fn check_lock_time(&self, lock_time: i64) -> bool;
+    fn check_sequence(&self, sequence: i64) -> bool;
+}
+
+/// VerifyScript runs an unlocking script followed by the locking script it spends,
+/// and the revealed script as well for a script hash output
+pub fn verify_script(script_sig: &Script, script_pub_key: &Script, checker: &dyn Checker) -> Result<()> {
+    if !script_sig.is_push_only() {
+        return Err(format_err!("unlocking script may only push data"));
+    }
+    let mut stack = Vec::new();
+    eval(script_sig, &mut stack, checker)?;
+    let pushed = stack.clone();
+    eval(script_pub_key, &mut stack, checker)?;
+    if !stack.last().is_some_and(|top| cast_to_bool(top)) {
+        return Err(format_err!("script evaluated to false"));
+    }
+
+    if let ScriptTemplate::ScriptHash(_) = script_pub_key.template() {
+        let mut stack = pushed;
+        let redeem = Script::from_bytes(stack.pop().unwrap_or_default());
+        eval(&redeem, &mut stack, checker)?;
+        if !stack.last().is_some_and(|top| cast_to_bool(top)) {
+            return Err(format_err!("redeem script evaluated to false"));
+        }
+    }
+    Ok(())
+}
+
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>> {
+    stack.pop().ok_or_else(|| format_err!("stack underflow"))
+}
+
+fn pop_num(stack: &mut Vec<Vec<u8>>) -> Result<i64> {
+    decode_num(&pop(stack)?, 4)
+}
+
+fn eval(script: &Script, stack: &mut Vec<Vec<u8>>, checker: &dyn Checker) -> Result<()> {
+    if script.0.len() > MAX_SCRIPT_SIZE {
+        return Err(format_err!("script is larger than {} bytes", MAX_SCRIPT_SIZE));
+    }
+    // 条件分支栈: 每层OP_IF是否执行
+    let mut branches: Vec<bool> = Vec::new();
+    let mut ops = 0;
+
+    for ins in script.instructions() {
+        let executing = branches.iter().all(|b| *b);
+        let op = match ins? {
+            Instruction::Push(data) => {
+                if data.len() > MAX_ELEMENT_SIZE {
+                    return Err(format_err!("pushed data is larger than {} bytes", MAX_ELEMENT_SIZE));
+                }
+                if executing {
+                    stack.push(data.to_vec());
+                }
+                continue;
+            }
+            Instruction::Op(op) => op,
+        };
+        if op > OP_16 {
+            ops += 1;
+            if ops > MAX_OPS {
+                return Err(format_err!("script runs more than {} operations", MAX_OPS));
+            }
+        }
+
+        match op {
+            OP_IF | OP_NOTIF => {
+                let mut branch = false;
+                if executing {
+                    branch = cast_to_bool(&pop(stack)?) ^ (op == OP_NOTIF);
+                }
+                branches.push(branch);
+            }
+            OP_ELSE => match branches.last_mut() {
+                Some(branch) => *branch = !*branch,
+                None => return Err(format_err!("OP_ELSE without OP_IF")),
+            },
+            OP_ENDIF => {
+                if branches.pop().is_none() {
+                    return Err(format_err!("OP_ENDIF without OP_IF"));
+                }
+            }
+            _ if !executing => {}
+            OP_1NEGATE | OP_1..=OP_16 => stack.push(small_int(op)),
+            OP_NOP => {}
+            OP_VERIFY => {
+                if !cast_to_bool(&pop(stack)?) {
+                    return Err(format_err!("OP_VERIFY failed"));
+                }
+            }
+            OP_RETURN => return Err(format_err!("OP_RETURN output cannot be spent")),
+            OP_DROP => {
+                pop(stack)?;
+            }
+            OP_DUP => {
+                let top = stack.last().cloned().ok_or_else(|| format_err!("stack underflow"))?;
+                stack.push(top);
+            }
+            OP_SWAP => {
+                let a = pop(stack)?;
+                let b = pop(stack)?;
+                stack.push(a);
+                stack.push(b);
+            }
+            OP_SIZE => {
+                let size = stack.last().map(|top| top.len()).ok_or_else(|| format_err!("stack underflow"))?;
+                stack.push(encode_num(size as i64));
+            }
+            OP_EQUAL | OP_EQUALVERIFY => {
+                let equal = pop(stack)? == pop(stack)?;
+                if op == OP_EQUALVERIFY {
+                    if !equal {
+                        return Err(format_err!("OP_EQUALVERIFY failed"));
+                    }
+                } else {
+                    stack.push(encode_bool(equal));
+                }
+            }
+            OP_SHA256 => {
+                let data = pop(stack)?;
+                stack.push(Sha256::digest(data).to_vec());
+            }
+            OP_HASH160 => {
+                let data = pop(stack)?;
+                stack.push(hash_pub_key(&data));
+            }
+            OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+                let public_key = pop(stack)?;
+                let signature = pop(stack)?;
+                let valid = checker.check_sig(&signature, &public_key);
+                if op == OP_CHECKSIGVERIFY {
+                    if !valid {
+                        return Err(format_err!("OP_CHECKSIGVERIFY failed"));
+                    }
+                } else {
+                    stack.push(encode_bool(valid));
+                }
+            }
+            OP_CHECKMULTISIG => {
+                let n = pop_num(stack)?;
+                if !(0..=MAX_MULTISIG_KEYS).contains(&n) {
+                    return Err(format_err!("OP_CHECKMULTISIG takes 0 to {} keys", MAX_MULTISIG_KEYS));
+                }
+                let keys = (0..n).map(|_| pop(stack)).collect::<Result<Vec<_>>>()?;
+                let m = pop_num(stack)?;
+                if !(0..=n).contains(&m) {
+                    return Err(format_err!("OP_CHECKMULTISIG requires 0 to {} signatures", n));
+                }
+                let signatures = (0..m).map(|_| pop(stack)).collect::<Result<Vec<_>>>()?;
+                // 每个签名须对应一个尚未使用的公钥
+                let mut unused = keys;
+                let mut signed = 0;
+                for sig in &signatures {
+                    if let Some(i) = unused.iter().position(|key| checker.check_sig(sig, key)) {
+                        unused.remove(i);
+                        signed += 1;
+                    }
+                }
+                stack.push(encode_bool(signed >= m));
+            }
+            OP_CHECKLOCKTIMEVERIFY => {
+                let top = stack.last().ok_or_else(|| format_err!("stack underflow"))?;
+                let lock_time = decode_num(top, 5)?;
+                if lock_time < 0 {
+                    return Err(format_err!("negative lock time"));
+                }
+                if !checker.check_lock_time(lock_time) {
+                    return Err(format_err!("lock time {} is not reached", lock_time));
+                }
+            }
+            OP_CHECKSEQUENCEVERIFY => {
+                let top = stack.last().ok_or_else(|| format_err!("stack underflow"))?;
+                let sequence = decode_num(top, 5)?;
+                if sequence < 0 {
+                    return Err(format_err!("negative sequence"));
+                }
+                // 设置了禁用标志时相当于OP_NOP
+                if sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG as i64 == 0 && !checker.check_sequence(sequence) {
+                    return Err(format_err!("relative lock {} is not reached", sequence));
+                }
+            }
+            _ => return Err(format_err!("unknown opcode {}", op_name(op))),
+        }
+
+        if stack.len() > MAX_STACK_SIZE {
+            return Err(format_err!("stack holds more than {} items", MAX_STACK_SIZE));
+        }
+    }
+    if !branches.is_empty() {
+        return Err(format_err!("OP_IF without OP_ENDIF"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// TestChecker accepts the signature "good" by any key and holds the lock time
+    /// and sequence of a spending transaction
+    struct TestChecker {
+        lock_time: i64,
+        sequence: i64,
+    }
+
+    impl Checker for TestChecker {
+        fn check_sig(&self, signature: &[u8], _public_key: &[u8]) -> bool {
+            signature == b"good"
+        }
+
+        fn check_lock_time(&self, lock_time: i64) -> bool {
+            lock_time <= self.lock_time
+        }
+
+        fn check_sequence(&self, sequence: i64) -> bool {
+            sequence <= self.sequence
+        }
+    }
+
+    fn run(script_sig: Script, script_pub_key: Script) -> Result<()> {
+        verify_script(&script_sig, &script_pub_key, &TestChecker { lock_time: 100, sequence: 0 })
+    }
+
+    #[test]
+    fn test_numbers() {
+        for n in [0, 1, -1, 16, 127, 128, -128, 255, 256, 500_000_000, -70_000] {
+            assert_eq!(decode_num(&encode_num(n), 5).unwrap(), n);
+        }
+        assert_eq!(encode_num(128), vec![0x80, 0x00]);
+        assert_eq!(encode_num(-1), vec![0x81]);
+        assert!(!cast_to_bool(&[0, 0x80]));
+        assert!(cast_to_bool(&[0, 1]));
+    }
+
+    #[test]
+    fn test_pub_key_hash() {
+        let key = [7; 32];
+        let script_pub_key = Script::new_pub_key_hash(&hash_pub_key(&key));
+        assert_eq!(script_pub_key.template(), ScriptTemplate::PubKeyHash(hash_pub_key(&key)));
+        assert!(run(Script::new().push_data(b"good").push_data(&key), script_pub_key.clone()).is_ok());
+        assert!(run(Script::new().push_data(b"bad").push_data(&key), script_pub_key.clone()).is_err());
+        assert!(run(Script::new().push_data(b"good").push_data(&[8; 32]), script_pub_key.clone()).is_err());
+        // an unlocking script may not run code
+        let code = Script::new().push_data(b"good").push_data(&key).push_opcode(OP_DROP).push_data(&key);
+        assert!(run(code, script_pub_key).is_err());
+    }
+
+    #[test]
+    fn test_script_hash_and_multisig() {
+        let keys: Vec<Vec<u8>> = (1..=3).map(|i| vec![i; 32]).collect();
+        let mut redeem = Script::new().push_int(2);
+        for key in &keys {
+            redeem = redeem.push_data(key);
+        }
+        let redeem = redeem.push_int(3).push_opcode(OP_CHECKMULTISIG);
+        assert_eq!(
+            redeem.template(),
+            ScriptTemplate::Multisig { required: 2, public_keys: keys.clone() }
+        );
+        let script_pub_key = Script::new_script_hash(&hash_pub_key(redeem.as_bytes()));
+
+        let unlock = |sigs: &[&[u8]]| {
+            sigs.iter().fold(Script::new(), |s, sig| s.push_data(sig)).push_data(redeem.as_bytes())
+        };
+        assert!(run(unlock(&[b"good", b"good"]), script_pub_key.clone()).is_ok());
+        assert!(run(unlock(&[b"good", b"bad"]), script_pub_key.clone()).is_err());
+        assert!(run(unlock(&[b"good"]), script_pub_key.clone()).is_err());
+        let other = Script::new().push_int(1).push_opcode(OP_CHECKMULTISIG);
+        assert!(run(Script::new().push_data(other.as_bytes()), script_pub_key).is_err());
+    }
+
+    #[test]
+    fn test_hash_preimage_and_lock_time() {
+        let secret = b"preimage";
+        let hash = Sha256::digest(secret).to_vec();
+        let script_pub_key = Script::new()
+            .push_opcode(OP_IF)
+            .push_opcode(OP_SHA256)
+            .push_data(&hash)
+            .push_opcode(OP_EQUAL)
+            .push_opcode(OP_ELSE)
+            .push_int(150)
+            .push_opcode(OP_CHECKLOCKTIMEVERIFY)
+            .push_opcode(OP_ENDIF);
+        assert!(run(Script::new().push_data(secret).push_int(1), script_pub_key.clone()).is_ok());
+        assert!(run(Script::new().push_data(b"guess").push_int(1), script_pub_key.clone()).is_err());
+        // the timeout branch needs a lock time of at least 150
+        assert!(run(Script::new().push_int(0), script_pub_key.clone()).is_err());
+        let later = TestChecker { lock_time: 150, sequence: 0 };
+        assert!(verify_script(&Script::new().push_int(0), &script_pub_key, &later).is_ok());
+
+        let relative = Script::new().push_int(10).push_opcode(OP_CHECKSEQUENCEVERIFY);
+        assert!(run(Script::new(), relative.clone()).is_err());
+        let aged = TestChecker { lock_time: 0, sequence: 10 };
+        assert!(verify_script(&Script::new(), &relative, &aged).is_ok());
+        let disabled = Script::new().push_int(SEQUENCE_LOCKTIME_DISABLE_FLAG as i64).push_opcode(OP_CHECKSEQUENCEVERIFY);
+        assert!(run(Script::new(), disabled).is_ok());
+    }
+
+    #[test]
+    fn test_limits_and_null_data() {
+        let data = Script::new_null_data(b"hello");
+        assert_eq!(data.template(), ScriptTemplate::NullData(b"hello".to_vec()));
+        assert!(data.is_unspendable());
+        assert!(run(Script::new(), data).is_err());
+
+        let mut busy = Script::new().push_int(1);
+        for _ in 0..=MAX_OPS {
+            busy = busy.push_opcode(OP_DUP).push_opcode(OP_DROP);
+        }
+        assert!(run(Script::new(), busy).is_err());
+        assert!(run(Script::new(), Script::new().push_int(1).push_opcode(OP_IF)).is_err());
+        assert!(run(Script::new(), Script::from_bytes(vec![0x05, 0x01])).is_err());
+        assert_eq!(
+            Script::new_pub_key_hash(&[0; 20]).to_asm(),
+            format!("OP_DUP OP_HASH160 {} OP_EQUALVERIFY OP_CHECKSIG", "00".repeat(20))
+        );
+    }
+}
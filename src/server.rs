@@ -0,0 +1,139 @@
+use crate::block::MAX_BLOCK_SIZE;
+use crate::blockchain::Blockchain;
+use crate::chainparams::ChainParams;
+use crate::errors::Result;
+use bincode::{deserialize, serialize};
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+// A wire message is the network magic, a little-endian u32 length and the bincode encoded
+// Message. The magic is checked before anything else is read, so a peer of another network
+// is dropped on its first message.
+
+/// Message is what nodes send each other
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Message {
+    /// Version opens a connection and is answered with the other node's own
+    Version { best_height: i32 },
+}
+
+/// SendMessage writes a message marked with the network magic
+pub fn send_message<W: Write>(w: &mut W, params: &ChainParams, msg: &Message) -> Result<()> {
+    let data = serialize(msg)?;
+    w.write_all(&params.magic)?;
+    w.write_all(&(data.len() as u32).to_le_bytes())?;
+    w.write_all(&data)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// ReadMessage reads a message, failing when it belongs to another network
+pub fn read_message<R: Read>(r: &mut R, params: &ChainParams) -> Result<Message> {
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic)?;
+    params.check_magic(&magic, "message")?;
+    let mut len = [0; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    // the largest message to come is a block
+    if len > MAX_BLOCK_SIZE {
+        return Err(format_err!("message of {} bytes is larger than {} bytes", len, MAX_BLOCK_SIZE));
+    }
+    let mut data = vec![0; len];
+    r.read_exact(&mut data)?;
+    Ok(deserialize(&data)?)
+}
+
+/// Server answers the peers of one network
+pub struct Server {
+    listener: TcpListener,
+    bc: Blockchain,
+}
+
+impl Server {
+    /// Bind listens on a local port for peers of the chain's network
+    pub fn bind(bc: Blockchain, port: u16) -> Result<Server> {
+        let listener = TcpListener::bind(("0.0.0.0", port))?;
+        Ok(Server { listener, bc })
+    }
+
+    /// Port returns the port the server listens on
+    pub fn port(&self) -> Result<u16> {
+        Ok(self.listener.local_addr()?.port())
+    }
+
+    /// Run serves one peer after another, a peer that fails is dropped and the next one served
+    pub fn run(&self) -> Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let peer = stream.peer_addr()?;
+            if let Err(e) = self.serve(stream) {
+                eprintln!("dropped peer {}: {}", peer, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn serve(&self, mut stream: TcpStream) -> Result<()> {
+        let params = self.bc.get_params();
+        loop {
+            let msg = match read_message(&mut stream, params) {
+                Ok(msg) => msg,
+                // the peer hung up between messages
+                Err(e) if is_eof(&e) => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            match msg {
+                Message::Version { .. } => {
+                    let best_height = self.bc.get_best_height()?;
+                    send_message(&mut stream, params, &Message::Version { best_height })?;
+                }
+            }
+        }
+    }
+}
+
+fn is_eof(e: &failure::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chainparams::Network;
+    use crate::store;
+
+    #[test]
+    fn test_refuse_other_network() {
+        let params = ChainParams::temporary(Network::Regtest);
+        let bc = Blockchain::create_blockchain(None, &params).unwrap();
+        let server = Server::bind(bc, 0).unwrap();
+        let port = server.port().unwrap();
+        std::thread::spawn(move || server.run());
+
+        let regtest = ChainParams::regtest();
+        let mut peer = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        send_message(&mut peer, &regtest, &Message::Version { best_height: 0 }).unwrap();
+        assert_eq!(read_message(&mut peer, &regtest).unwrap(), Message::Version { best_height: 0 });
+        // peers are served one after another
+        drop(peer);
+
+        // a mainnet peer is hung up on instead of answered
+        let main = ChainParams::main();
+        let mut peer = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        send_message(&mut peer, &main, &Message::Version { best_height: 0 }).unwrap();
+        assert!(read_message(&mut peer, &main).is_err());
+
+        // and a reply from another network is refused on our side too
+        let mut reply = Vec::new();
+        send_message(&mut reply, &regtest, &Message::Version { best_height: 1 }).unwrap();
+        let err = read_message(&mut reply.as_slice(), &main).unwrap_err();
+        assert!(err.to_string().contains("regtest network"), "{}", err);
+
+        store::close(&params.data_dir).unwrap();
+        std::fs::remove_dir_all(&params.data_dir).unwrap();
+    }
+}
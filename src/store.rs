@@ -0,0 +1,40 @@
+use crate::errors::Result;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+/// STORES holds the handle of every sled database the process has opened. sled lets go of the
+/// lock of a dropped handle only once its background threads are done with it, so a store is
+/// opened once and shared instead of being closed and opened again.
+static STORES: OnceLock<Mutex<HashMap<String, sled::Db>>> = OnceLock::new();
+
+fn stores() -> std::sync::MutexGuard<'static, HashMap<String, sled::Db>> {
+    STORES.get_or_init(Default::default).lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Open returns the handle of the store at a path, opening it on first use
+pub fn open(path: &str) -> Result<sled::Db> {
+    let mut stores = stores();
+    if let Some(db) = stores.get(path) {
+        return Ok(db.clone());
+    }
+    let db = sled::open(path)?;
+    stores.insert(path.to_string(), db.clone());
+    Ok(db)
+}
+
+/// Close forgets the stores at and below a path before their directories are moved or removed,
+/// the next open of the path creates a new store
+pub fn close(path: &str) -> Result<()> {
+    let mut stores = stores();
+    let closed: Vec<String> = stores
+        .keys()
+        .filter(|p| p.as_str() == path || p.starts_with(&format!("{}/", path)))
+        .cloned()
+        .collect();
+    for p in closed {
+        if let Some(db) = stores.remove(&p) {
+            db.flush()?;
+        }
+    }
+    Ok(())
+}
@@ -1,87 +1,438 @@
-use super::*;
 use serde::{Deserialize, Serialize};
 use crate::errors::Result;
-use crate::tx::{TXInput,TXOutput,TXOutputs};
+use crate::tx::{
+    TXInput, TXOutput, SEQUENCE_FINAL, SEQUENCE_LOCKTIME_DISABLE_FLAG, SEQUENCE_LOCKTIME_GRANULARITY,
+    SEQUENCE_LOCKTIME_MASK, SEQUENCE_LOCKTIME_TYPE_FLAG,
+};
 use sha2::{Sha256, Digest};
+use crate::address::Address;
 use crate::blockchain::Blockchain;
+use crate::chainparams::ChainParams;
+use crate::coinselect::{sum_values, CoinSelector, Selection};
+use crate::multisig::MultisigScript;
+use crate::psbt::{decode_bytes, encode_bytes, TxEncoding};
+use crate::script::{
+    verify_script, Checker, Instruction, Script, ScriptTemplate, LOCKTIME_THRESHOLD, MAX_NULL_DATA_SIZE,
+};
+use crate::wallet::Wallets;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
 use failure::format_err;
-use log::{debug, error, info};
+use log::error;
+use std::collections::HashMap;
 
 
+/// MAX_COINBASE_DATA_SIZE is the most bytes of data a coinbase input carries after its commitment
+pub const MAX_COINBASE_DATA_SIZE: usize = 80;
+
 /// Transaction represents a Bitcoin transaction
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
     pub id: String,
     pub vin: Vec<TXInput>,
     pub vout: Vec<TXOutput>,
+    /// the block height, or unix time in seconds from LOCKTIME_THRESHOLD on, before which the transaction cannot be mined
+    pub lock_time: u32,
 }
 
-impl Transaction {
+/// Locks are the absolute lock time and the input sequence a new transaction is built with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Locks {
+    pub lock_time: u32,
+    pub sequence: u32,
+}
 
-    pub fn new_UTXO(from: &str,to: &str,amount: i32,bc: &Blockchain) -> Result<Transaction> {
-        let mut vin = Vec::new();
-        let acc_v = bc.find_spendable_outputs(from,amount);
-        if acc_v.0 < amount {
-            error!("Not Enough balance");
-            return Err(format_err!(
-                "Not Enough balance: current balance {}",
-                acc_v.0
-            ));
+impl Default for Locks {
+    fn default() -> Locks {
+        Locks {
+            lock_time: 0,
+            sequence: SEQUENCE_FINAL,
+        }
+    }
+}
+
+impl Locks {
+    /// New returns the locks of a lock time and an optional relative lock sequence,
+    /// a lock time alone needs its inputs not final to take effect
+    pub fn new(lock_time: u32, sequence: Option<u32>) -> Locks {
+        let sequence = match sequence {
+            Some(sequence) => sequence,
+            None if lock_time > 0 => SEQUENCE_FINAL - 1,
+            None => SEQUENCE_FINAL,
+        };
+        Locks { lock_time, sequence }
+    }
+}
+
+/// RelativeBlocks returns the sequence locking an input until its output has that many confirmations
+pub fn relative_blocks(blocks: u16) -> u32 {
+    blocks as u32
+}
+
+/// RelativeSeconds returns the sequence locking an input until its output is that old,
+/// rounded up to 512 second units
+pub fn relative_seconds(seconds: u32) -> Result<u32> {
+    let units = seconds.div_ceil(1 << SEQUENCE_LOCKTIME_GRANULARITY);
+    if units > SEQUENCE_LOCKTIME_MASK {
+        return Err(format_err!(
+            "relative lock of {} seconds is longer than {} seconds",
+            seconds,
+            SEQUENCE_LOCKTIME_MASK << SEQUENCE_LOCKTIME_GRANULARITY
+        ));
+    }
+    Ok(SEQUENCE_LOCKTIME_TYPE_FLAG | units)
+}
+
+/// RecipientOutputs returns the outputs paying the recipients and their total
+fn recipient_outputs(recipients: &[(String, i32)], params: &ChainParams) -> Result<(Vec<TXOutput>, i32)> {
+    if recipients.is_empty() {
+        return Err(format_err!("a transaction needs at least one recipient"));
+    }
+    let mut vout = Vec::new();
+    let mut amount: i32 = 0;
+    for (to, value) in recipients {
+        if *value <= 0 {
+            return Err(format_err!("the amount sent to {} must be positive, got {}", to, value));
+        }
+        amount = amount
+            .checked_add(*value)
+            .ok_or_else(|| format_err!("the amounts sent add up to more than {}", i32::MAX))?;
+        vout.push(TXOutput::new(*value, &Address::parse(to, params)?));
+    }
+    Ok((vout, amount))
+}
+
+/// RecipientEntries is a JSON object read as a list of its entries, so a repeated key is kept
+struct RecipientEntries(Vec<(String, serde_json::Value)>);
+
+impl<'de> Deserialize<'de> for RecipientEntries {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct EntriesVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for EntriesVisitor {
+            type Value = RecipientEntries;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a map of address to amount")
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> std::result::Result<Self::Value, A::Error> {
+                let mut entries = Vec::new();
+                while let Some(entry) = map.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(RecipientEntries(entries))
+            }
         }
 
-        for tx in acc_v.1 {
-            for out in tx.1 {
-                let input = TXInput {
-                    txid: tx.0.clone(),
-                    vout: out,
-                    script_sig: String::from(from)  ,
-                };
-                vin.push(input);
+        deserializer.deserialize_map(EntriesVisitor)
+    }
+}
+
+/// ParseRecipients reads the payments of a transfer from a JSON map of address to amount,
+/// or from CSV lines of address,amount where a header and # comments are skipped.
+/// An address listed twice is rejected in both formats rather than merged or dropped
+pub fn parse_recipients(text: &str) -> Result<Vec<(String, i32)>> {
+    let text = text.trim();
+    let mut recipients: Vec<(String, i32)> = Vec::new();
+    if text.starts_with('{') {
+        let RecipientEntries(entries) = serde_json::from_str(text)?;
+        for (address, amount) in entries {
+            let amount = amount
+                .as_i64()
+                .and_then(|amount| i32::try_from(amount).ok())
+                .ok_or_else(|| format_err!("the amount for {} is not a whole number: {}", address, amount))?;
+            recipients.push((address, amount));
+        }
+    } else {
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (address, amount) = line
+                .split_once(',')
+                .ok_or_else(|| format_err!("line {}: expected address,amount", number + 1))?;
+            match amount.trim().parse() {
+                Ok(amount) => recipients.push((address.trim().to_string(), amount)),
+                Err(_) if number == 0 => continue,
+                Err(_) => return Err(format_err!("line {}: bad amount {}", number + 1, amount.trim())),
             }
         }
+    }
+    if recipients.is_empty() {
+        return Err(format_err!("no recipients given"));
+    }
+    let mut seen = std::collections::HashSet::new();
+    if let Some((address, _)) = recipients.iter().find(|(address, _)| !seen.insert(address)) {
+        return Err(format_err!("{} is listed more than once, put its total in one entry", address));
+    }
+    Ok(recipients)
+}
 
-        let mut vout = vec![TXOutput{
-            value: amount,
-            script_pub_key: String::from(to) ,
-        }];
+impl Transaction {
+
+    #[allow(non_snake_case)]
+    pub fn new_UTXO(
+        from: &str,
+        to: &str,
+        amount: i32,
+        locks: Locks,
+        selector: &CoinSelector,
+        wallets: &mut Wallets,
+        bc: &Blockchain,
+    ) -> Result<Transaction> {
+        Transaction::new_many(from, &[(to.to_string(), amount)], locks, selector, wallets, bc)
+    }
 
-        if acc_v.0 > amount {
-            vout.push(TXOutput{
-                value: acc_v.0-amount,
-                script_pub_key: String::from(from) ,
+    /// NewMany builds and signs one transfer paying every recipient, with a single change output
+    /// to a fresh change address of the wallet
+    pub fn new_many(
+        from: &str,
+        recipients: &[(String, i32)],
+        locks: Locks,
+        selector: &CoinSelector,
+        wallets: &mut Wallets,
+        bc: &Blockchain,
+    ) -> Result<Transaction> {
+        let key = wallets.signing_key(&Address::parse(from, bc.get_params())?.to_string())?;
+        let change = wallets.next_change_address()?;
+        let mut tx = Transaction::new_unsigned_many(from, recipients, locks, selector, Some(&change), bc)?;
+        // 只有真的找零时才占用这个地址
+        if tx.vout.len() > recipients.len() {
+            wallets.new_change_address()?;
+        }
+        bc.sign_transacton(&mut tx, &key)?;
+        Ok(tx)
+    }
+
+    /// NewUnsigned builds a transfer from the unspent outputs of an address without signing it,
+    /// what the inputs hold beyond the amount and their fee goes to the change address if given,
+    /// else back to from
+    pub fn new_unsigned(
+        from: &str,
+        to: &str,
+        amount: i32,
+        locks: Locks,
+        selector: &CoinSelector,
+        change_to: Option<&Address>,
+        bc: &Blockchain,
+    ) -> Result<Transaction> {
+        Transaction::new_unsigned_many(from, &[(to.to_string(), amount)], locks, selector, change_to, bc)
+    }
+
+    /// NewUnsignedMany builds a transfer paying every recipient without signing it,
+    /// the change goes to the change address if given, else back to from
+    pub fn new_unsigned_many(
+        from: &str,
+        recipients: &[(String, i32)],
+        locks: Locks,
+        selector: &CoinSelector,
+        change_to: Option<&Address>,
+        bc: &Blockchain,
+    ) -> Result<Transaction> {
+        let from_address = Address::parse(from, bc.get_params())?;
+        let (vout, amount) = recipient_outputs(recipients, bc.get_params())?;
+        let selection = bc
+            .find_spendable_outputs(&from_address.to_string(), amount, selector)
+            .inspect_err(|_| error!("Not Enough balance"))?;
+        Transaction::fund(vout, amount, &selection, change_to.unwrap_or(&from_address), locks)
+    }
+
+    /// NewFromWallet builds and signs a transfer paying every recipient from the outputs of
+    /// all the addresses the wallet holds keys for, with change to a fresh change address
+    pub fn new_from_wallet(
+        recipients: &[(String, i32)],
+        locks: Locks,
+        selector: &CoinSelector,
+        wallets: &mut Wallets,
+        bc: &Blockchain,
+    ) -> Result<Transaction> {
+        let (vout, amount) = recipient_outputs(recipients, bc.get_params())?;
+        let coins = bc.find_mature_outputs_of(&wallets.get_spendable_addresses());
+        let selection = selector.select(coins, amount)?;
+        let change = wallets.next_change_address()?;
+        let mut tx = Transaction::fund(vout, amount, &selection, &change, locks)?;
+        if tx.vout.len() > recipients.len() {
+            wallets.new_change_address()?;
+        }
+        bc.sign_with_wallet(&mut tx, wallets)?;
+        Ok(tx)
+    }
+
+    /// Fund builds an unsigned transfer of the outputs spending the selection,
+    /// what it holds beyond the amount and the fee goes to the change address
+    fn fund(
+        mut vout: Vec<TXOutput>,
+        amount: i32,
+        selection: &Selection,
+        change_to: &Address,
+        locks: Locks,
+    ) -> Result<Transaction> {
+        let vin = selection
+            .coins
+            .iter()
+            .map(|(txid, vout, _)| TXInput {
+                txid: txid.clone(),
+                vout: *vout,
+                script_sig: Script::new(),
+                sequence: locks.sequence,
             })
+            .collect();
+
+        let change = selection.change(amount);
+        if change > 0 {
+            vout.push(TXOutput::new(change, change_to))
         }
 
         let mut tx = Transaction {
             id: String::new(),
             vin,
             vout,
+            lock_time: locks.lock_time,
         };
         tx.set_id()?;
         Ok(tx)
     }
 
-    pub fn new_coinbase(to: String,mut data: String) ->Result<Transaction>{
-        if data == String::from(""){
+    /// NewSweep builds and signs a transfer of every output of an address, less the input fees, to another
+    pub fn new_sweep(
+        from: &str,
+        to: &str,
+        selector: &CoinSelector,
+        wallets: &Wallets,
+        bc: &Blockchain,
+    ) -> Result<Transaction> {
+        let from = Address::parse(from, bc.get_params())?.to_string();
+        let to = Address::parse(to, bc.get_params())?;
+        let selection = selector.select_all(bc.find_mature_outputs_of(std::slice::from_ref(&from)))?;
+        let mut tx = Transaction::fund(Vec::new(), 0, &selection, &to, Locks::default())?;
+        bc.sign_transacton(&mut tx, &wallets.signing_key(&from)?)?;
+        Ok(tx)
+    }
+
+    /// NewConsolidations builds and signs transfers merging the outputs of an address back to it,
+    /// smallest first in batches of at most max_inputs, optionally only outputs worth less than below
+    pub fn new_consolidations(
+        address: &str,
+        max_inputs: usize,
+        below: Option<i32>,
+        selector: &CoinSelector,
+        wallets: &Wallets,
+        bc: &Blockchain,
+    ) -> Result<Vec<Transaction>> {
+        if max_inputs < 2 {
+            return Err(format_err!("consolidating needs at least 2 inputs per transaction"));
+        }
+        let address = Address::parse(address, bc.get_params())?;
+        let key = wallets.signing_key(&address.to_string())?;
+        let coins: Vec<_> = bc
+            .find_mature_outputs_of(&[address.to_string()])
+            .into_iter()
+            .filter(|(_, _, out)| below.is_none_or(|below| out.value < below))
+            .collect();
+        let selection = match selector.select_all(coins) {
+            Ok(selection) => selection,
+            Err(_) => return Ok(Vec::new()),
+        };
+
+        let mut txs = Vec::new();
+        for batch in selection.coins.chunks(max_inputs) {
+            if batch.len() < 2 {
+                break;
+            }
+            let total = sum_values(batch)?;
+            let fee = selector.fee_for(batch.len())?;
+            let batch = Selection { coins: batch.to_vec(), total, fee };
+            let mut tx = Transaction::fund(Vec::new(), 0, &batch, &address, Locks::default())?;
+            bc.sign_transacton(&mut tx, &key)?;
+            txs.push(tx);
+        }
+        Ok(txs)
+    }
+
+    /// NewData builds a transaction embedding data in an OP_RETURN output,
+    /// it spends an output of an address back to the address
+    pub fn new_data(from: &str, data: &[u8], bc: &Blockchain) -> Result<Transaction> {
+        let from_address = Address::parse(from, bc.get_params())?;
+        let mut tx = Transaction::new_unsigned(from, from, 1, Locks::default(), &CoinSelector::default(), None, bc)?;
+        let value = tx.vout.iter().map(|out| out.value).sum();
+        tx.vout = vec![TXOutput::new(value, &from_address), TXOutput::new_data(data)?];
+        tx.set_id()?;
+        Ok(tx)
+    }
+
+    /// AddData appends an OP_RETURN output to an unsigned transaction
+    pub fn add_data(&mut self, data: &[u8]) -> Result<()> {
+        self.vout.push(TXOutput::new_data(data)?);
+        self.set_id()
+    }
+
+    /// NewCoinbase creates the reward transaction of a block, given a commitment its input starts
+    /// with the height and an extra nonce before the data so no two coinbases share an id
+    pub fn new_coinbase(to: String,mut data: String,subsidy: i32,commitment: Option<(i32, u64)>) ->Result<Transaction>{
+        let address: Address = to.parse()?;
+        if data.is_empty() {
             data += &format!("Reward to '{}'",to);
         }
+        if data.len() > MAX_COINBASE_DATA_SIZE {
+            return Err(format_err!(
+                "coinbase message is {} bytes, at most {} are allowed",
+                data.len(),
+                MAX_COINBASE_DATA_SIZE
+            ));
+        }
+
+        let mut script_sig = Script::new();
+        if let Some((height, extra_nonce)) = commitment {
+            script_sig = script_sig.push_int(height as i64).push_data(&extra_nonce.to_le_bytes());
+        }
 
         let mut tx = Transaction {
             id: String::new(),
             vin: vec![TXInput {
                 txid: String::new(),
                 vout: -1,
-                script_sig: data,
-            }],
-            vout: vec![TXOutput{
-                value: 100,
-                script_pub_key: to,
+                script_sig: script_sig.push_data(data.as_bytes()),
+                sequence: SEQUENCE_FINAL,
             }],
+            vout: vec![TXOutput::new(subsidy, &address)],
+            lock_time: 0,
         };
         tx.set_id()?;
         Ok(tx)
     }
 
+    /// CoinbaseCommitment returns the height and extra nonce a coinbase input commits to
+    pub fn coinbase_commitment(&self) -> Option<(i32, u64)> {
+        if !self.is_coinbase() {
+            return None;
+        }
+        let mut instructions = self.vin[0].script_sig.instructions();
+        let height = instructions.next()?.ok()?.as_int()?;
+        let extra_nonce = match instructions.next()?.ok()? {
+            Instruction::Push(data) => u64::from_le_bytes(data.try_into().ok()?),
+            Instruction::Op(_) => return None,
+        };
+        Some((i32::try_from(height).ok()?, extra_nonce))
+    }
+
+    /// CheckCoinbase fails when a coinbase from the commitment height on does not commit to the height of its block
+    pub fn check_coinbase(&self, height: i32, params: &ChainParams) -> Result<()> {
+        if !self.is_coinbase() || height < params.coinbase_commitment_height {
+            return Ok(());
+        }
+        match self.coinbase_commitment() {
+            Some((committed, _)) if committed == height => Ok(()),
+            Some((committed, _)) => Err(format_err!(
+                "coinbase {} commits to height {} in a block at height {}",
+                self.id,
+                committed,
+                height
+            )),
+            None => Err(format_err!("coinbase {} does not commit to a height and extra nonce", self.id)),
+        }
+    }
+
     pub fn hash(&self) -> Result<String> {
         let mut hasher = Sha256::new();
         let data = bincode::serialize(self)?;
@@ -92,55 +443,392 @@ impl Transaction {
         Ok(hex_result)
     }
 
-    fn set_id(&mut self) -> Result<()> {
-        let mut hasher = Sha256::new();
-        let data = bincode::serialize(self)?;
-        sha2::Digest::update(&mut hasher,&data);
-        let hex_result = hasher.finalize().iter().map(|b| format!("{:02x}", b))
-        .collect::<Vec<String>>()
-        .join("");
+    pub fn set_id(&mut self) -> Result<()> {
+        self.id = self.compute_id()?;
+        Ok(())
+    }
+
+    /// Encode writes the transaction as hex or base64 for moving it between machines
+    pub fn encode(&self, encoding: TxEncoding) -> Result<String> {
+        Ok(encode_bytes(&bincode::serialize(self)?, encoding))
+    }
+
+    /// Decode reads a transaction written by encode and checks its id
+    pub fn decode(s: &str, encoding: TxEncoding) -> Result<Transaction> {
+        let tx: Transaction = bincode::deserialize(&decode_bytes(s, encoding)?)
+            .map_err(|_| format_err!("not a valid transaction"))?;
+        if tx.id != tx.compute_id()? {
+            return Err(format_err!("transaction id {} does not match its content", tx.id));
+        }
+        Ok(tx)
+    }
+
+    /// ComputeID returns the id this transaction should have, hashing it with an empty id
+    pub fn compute_id(&self) -> Result<String> {
+        let mut tx = self.clone();
+        tx.id = String::new();
+        tx.hash()
+    }
+
+    /// TrimmedCopy returns a copy with the inputs' unlocking scripts cleared, the part of a transaction that gets signed
+    fn trimmed_copy(&self) -> Transaction {
+        let mut tx = self.clone();
+        tx.id = String::new();
+        for vin in &mut tx.vin {
+            vin.script_sig = Script::new();
+        }
+        tx
+    }
 
-        self.id = hex_result;
+    /// SignatureHash returns the hash input `index` signs: the trimmed copy with that input
+    /// holding the locking script of the output it spends
+    fn signature_hash(&self, index: usize, prev_out: &TXOutput) -> Result<String> {
+        let mut tx = self.trimmed_copy();
+        tx.vin[index].script_sig = prev_out.script_pub_key.clone();
+        tx.hash()
+    }
+
+    /// Sign signs each input of the transaction with the key of the outputs it spends
+    pub fn sign(&mut self, key: &SigningKey, prev_txs: &HashMap<String, Transaction>) -> Result<()> {
+        if self.is_coinbase() {
+            return Ok(());
+        }
+        for index in 0..self.vin.len() {
+            let prev_out = prev_output(&self.vin[index], prev_txs)?.clone();
+            self.sign_input(index, key, &prev_out)?;
+        }
+        self.set_id()
+    }
+
+    /// SignWithWallet signs every input with the wallet key of the address its output is locked to
+    pub fn sign_with_wallet(&mut self, wallets: &Wallets, prev_txs: &HashMap<String, Transaction>) -> Result<()> {
+        let params = wallets.get_params();
+        for index in 0..self.vin.len() {
+            let prev_out = prev_output(&self.vin[index], prev_txs)?.clone();
+            let address = prev_out
+                .address(params)
+                .ok_or_else(|| format_err!("input {} does not spend an address of the wallet", index))?;
+            self.sign_input(index, &wallets.signing_key(&address)?, &prev_out)?;
+        }
+        self.set_id()
+    }
+
+    /// SignInput signs one input given the output it spends, the id has to be set again afterwards
+    pub fn sign_input(&mut self, index: usize, key: &SigningKey, prev_out: &TXOutput) -> Result<()> {
+        let hash = self.signature_hash(index, prev_out)?;
+        let signature = key.sign(hash.as_bytes());
+        self.vin[index].script_sig = Script::new()
+            .push_data(&signature.to_bytes())
+            .push_data(key.verifying_key().as_bytes());
         Ok(())
+    }
+
+    /// Verify checks that every input references an existing output and that its
+    /// unlocking script satisfies the output's locking script
+    pub fn verify(&self, prev_txs: &HashMap<String, Transaction>) -> Result<bool> {
+        if self.is_coinbase() {
+            return Ok(true);
+        }
+
+        for (index, vin) in self.vin.iter().enumerate() {
+            let out = match prev_output(vin, prev_txs) {
+                Ok(out) => out,
+                Err(_) if prev_txs.contains_key(&vin.txid) => return Ok(false),
+                Err(e) => return Err(e),
+            };
+            if !self.verify_input(index, out)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// SignMultisigInput adds a co-signer's signature to an input spending a multisig output,
+    /// returning false when the key had signed already or enough keys have
+    pub fn sign_multisig_input(
+        &mut self,
+        index: usize,
+        key: &SigningKey,
+        prev_out: &TXOutput,
+        script: &MultisigScript,
+    ) -> Result<bool> {
+        let hash = self.signature_hash(index, prev_out)?;
+        let redeem = script.to_bytes();
+        // 解锁脚本: <签名>... <多签脚本>
+        let mut signatures = match self.vin[index].script_sig.pushes() {
+            Some(mut pushes) if pushes.last() == Some(&redeem) => {
+                pushes.pop();
+                pushes
+            }
+            _ => Vec::new(),
+        };
+        let public_key = key.verifying_key();
+        let signed = signatures.iter().any(|sig| {
+            Signature::from_slice(sig).is_ok_and(|sig| public_key.verify(hash.as_bytes(), &sig).is_ok())
+        });
+        if signed || signatures.len() >= script.required() {
+            return Ok(false);
+        }
+        signatures.push(key.sign(hash.as_bytes()).to_bytes().to_vec());
+        let script_sig = signatures.iter().fold(Script::new(), |s, sig| s.push_data(sig));
+        self.vin[index].script_sig = script_sig.push_data(&redeem);
+        Ok(true)
+    }
 
+    /// VerifyInput runs the unlocking script of one input against the output it spends
+    pub fn verify_input(&self, index: usize, prev_out: &TXOutput) -> Result<bool> {
+        let checker = TransactionChecker {
+            hash: self.signature_hash(index, prev_out)?,
+            lock_time: self.lock_time,
+            sequence: self.vin[index].sequence,
+        };
+        Ok(verify_script(&self.vin[index].script_sig, &prev_out.script_pub_key, &checker).is_ok())
     }
 
     pub fn is_coinbase(&self) -> bool {
         self.vin.len()==1 && self.vin[0].txid.is_empty() && self.vin[0].vout == -1
     }
+
+    /// CheckOutputs fails on a transaction without inputs or outputs, an output paying nothing
+    /// or a negative value, outputs adding up past i32, and an unspendable output that is not
+    /// a data output within the size limit
+    pub fn check_outputs(&self) -> Result<()> {
+        if self.vin.is_empty() || self.vout.is_empty() {
+            return Err(format_err!("transaction {} has no inputs or no outputs", self.id));
+        }
+        for (index, out) in self.vout.iter().enumerate() {
+            if out.script_pub_key.is_unspendable() && out.data().is_none_or(|data| data.len() > MAX_NULL_DATA_SIZE) {
+                return Err(format_err!(
+                    "output {} of transaction {} is not a data output of at most {} bytes",
+                    index, self.id, MAX_NULL_DATA_SIZE
+                ));
+            }
+            // 数据输出可以为 0, 其他输出必须为正
+            if out.value < 0 || (out.value == 0 && !out.script_pub_key.is_unspendable()) {
+                return Err(format_err!("output {} of transaction {} pays {}", index, self.id, out.value));
+            }
+        }
+        if i32::try_from(self.output_value()).is_err() {
+            return Err(format_err!("outputs of transaction {} add up to more than {}", self.id, i32::MAX));
+        }
+        Ok(())
+    }
+
+    /// OutputValue returns the sum of the outputs
+    pub fn output_value(&self) -> i64 {
+        self.vout.iter().map(|out| out.value as i64).sum()
+    }
+
+    /// InputValue returns the sum of the outputs the inputs spend
+    pub fn input_value(&self, prev_txs: &HashMap<String, Transaction>) -> Result<i64> {
+        let mut value = 0;
+        for vin in &self.vin {
+            value += prev_output(vin, prev_txs)?.value as i64;
+        }
+        Ok(value)
+    }
+
+    /// IsFinal tells whether the transaction may be mined in a block at a context:
+    /// its lock time has passed or all its inputs opted out of it
+    pub fn is_final(&self, context: &SpendContext) -> bool {
+        let reached = if (self.lock_time as i64) < LOCKTIME_THRESHOLD {
+            (self.lock_time as i64) < context.height as i64
+        } else {
+            (self.lock_time as u128) * 1000 < context.median_time
+        };
+        self.lock_time == 0 || reached || self.vin.iter().all(|vin| vin.sequence == SEQUENCE_FINAL)
+    }
+
+    /// SequenceLocksReached tells whether the relative lock of every input has passed,
+    /// given the context of the block each spent output was confirmed in
+    pub fn sequence_locks_reached(&self, coins: &[SpendContext], context: &SpendContext) -> bool {
+        self.vin.iter().zip(coins).all(|(vin, coin)| {
+            if vin.sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+                return true;
+            }
+            let value = vin.sequence & SEQUENCE_LOCKTIME_MASK;
+            if vin.sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+                let wait = ((value as u128) << SEQUENCE_LOCKTIME_GRANULARITY) * 1000;
+                coin.median_time + wait <= context.median_time
+            } else {
+                coin.height as i64 + value as i64 <= context.height as i64
+            }
+        })
+    }
+}
+
+/// SpendContext is the point in the chain a block is validated at, lock times are checked against it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpendContext {
+    pub height: i32,
+    /// median timestamp of the blocks before, in milliseconds
+    pub median_time: u128,
+}
+
+/// TransactionChecker checks the signatures and lock times of one input
+struct TransactionChecker {
+    hash: String,
+    lock_time: u32,
+    sequence: u32,
 }
 
-impl TXInput{
-    pub fn can_unlock_output_with(&self, unlocking_data: &str) -> bool {
-        self.script_sig == unlocking_data
+impl Checker for TransactionChecker {
+    fn check_sig(&self, signature: &[u8], public_key: &[u8]) -> bool {
+        let key = match public_key.try_into().ok().and_then(|k| VerifyingKey::from_bytes(k).ok()) {
+            Some(key) => key,
+            None => return false,
+        };
+        match Signature::from_slice(signature) {
+            Ok(sig) => key.verify(self.hash.as_bytes(), &sig).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    // 脚本要求的锁定时间须与交易的lock_time同类型且不晚于它
+    fn check_lock_time(&self, lock_time: i64) -> bool {
+        let tx_lock_time = self.lock_time as i64;
+        (lock_time < LOCKTIME_THRESHOLD) == (tx_lock_time < LOCKTIME_THRESHOLD)
+            && lock_time <= tx_lock_time
+            && self.sequence != SEQUENCE_FINAL
+    }
+
+    fn check_sequence(&self, sequence: i64) -> bool {
+        let tx_sequence = self.sequence as i64;
+        if tx_sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG as i64 != 0 {
+            return false;
+        }
+        let mask = (SEQUENCE_LOCKTIME_TYPE_FLAG | SEQUENCE_LOCKTIME_MASK) as i64;
+        let (wanted, held) = (sequence & mask, tx_sequence & mask);
+        let flag = SEQUENCE_LOCKTIME_TYPE_FLAG as i64;
+        (wanted < flag) == (held < flag) && wanted <= held
+    }
+}
+
+fn prev_output<'a>(vin: &TXInput, prev_txs: &'a HashMap<String, Transaction>) -> Result<&'a TXOutput> {
+    let prev_tx = match prev_txs.get(&vin.txid) {
+        Some(tx) => tx,
+        None => return Err(format_err!("ERROR: Previous transaction is not correct")),
+    };
+    match prev_tx.vout.get(vin.vout as usize) {
+        Some(out) if vin.vout >= 0 => Ok(out),
+        _ => Err(format_err!("ERROR: Previous output {}:{} does not exist", vin.txid, vin.vout)),
     }
 }
 
 impl TXOutput{
-    pub fn can_be_unlock_with(&self, unlocking_data: &str) -> bool {
-        self.script_pub_key == unlocking_data
+    /// New locks a value to the standard script of an address
+    pub fn new(value: i32, address: &Address) -> TXOutput {
+        TXOutput {
+            value,
+            script_pub_key: Script::from_address(address),
+        }
     }
 
-    
+    /// NewData returns an unspendable output carrying data
+    pub fn new_data(data: &[u8]) -> Result<TXOutput> {
+        if data.len() > MAX_NULL_DATA_SIZE {
+            return Err(format_err!("data outputs carry at most {} bytes, got {}", MAX_NULL_DATA_SIZE, data.len()));
+        }
+        Ok(TXOutput {
+            value: 0,
+            script_pub_key: Script::new_null_data(data),
+        })
+    }
+
+    /// Address returns the address a standard output pays to
+    pub fn address(&self, params: &ChainParams) -> Option<String> {
+        self.script_pub_key.address(params).map(|a| a.to_string())
+    }
+
+    /// Data returns what an OP_RETURN output carries
+    pub fn data(&self) -> Option<Vec<u8>> {
+        match self.script_pub_key.template() {
+            ScriptTemplate::NullData(data) => Some(data),
+            _ => None,
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use rand_core::OsRng;
 
     #[test]
     fn test_signature() {
-        // let mut ws = Wallets::new().unwrap();
-        // let wa1 = ws.create_wallet();
-        // let w = ws.get_wallet(&wa1).unwrap().clone();
-        // ws.save_all().unwrap();
-        // drop(ws);
+        let key = SigningKey::generate(&mut OsRng);
+        let address = Address::from_pub_key(key.verifying_key().as_bytes(), 0x00).to_string();
+        let prev = Transaction::new_coinbase(address.clone(), String::new(), 100, Some((1, 0))).unwrap();
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput { txid: prev.id.clone(), vout: 0, script_sig: Script::new(), sequence: SEQUENCE_FINAL }],
+            vout: vec![TXOutput::new(100, &Address::new(0x00, vec![2; 20]))],
+            lock_time: 0,
+        };
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(prev.id.clone(), prev);
+
+        tx.sign(&key, &prev_txs).unwrap();
+        assert!(tx.verify(&prev_txs).unwrap());
 
-        // let data = String::from("test");
-        // let tx = Transaction::new_coinbase(wa1, data).unwrap();
-        // assert!(tx.is_coinbase());
+        let mut changed = tx.clone();
+        changed.vout[0].value = 99;
+        assert!(!changed.verify(&prev_txs).unwrap());
 
-        // let signature = ed25519::signature(tx.id.as_bytes(), &w.secret_key);
-        // assert!(ed25519::verify(tx.id.as_bytes(), &w.public_key, &signature));
+        let other = SigningKey::generate(&mut OsRng);
+        let mut stolen = tx.clone();
+        stolen.sign(&other, &prev_txs).unwrap();
+        assert!(!stolen.verify(&prev_txs).unwrap());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_lock_time() {
+        let input = |sequence| TXInput { txid: String::from("a"), vout: 0, script_sig: Script::new(), sequence };
+        let mut tx = Transaction { id: String::new(), vin: vec![input(SEQUENCE_FINAL - 1)], vout: Vec::new(), lock_time: 100 };
+        let at = |height, seconds: u128| SpendContext { height, median_time: seconds * 1000 };
+        assert!(!tx.is_final(&at(100, 0)));
+        assert!(tx.is_final(&at(101, 0)));
+        tx.lock_time = 600_000_000;
+        assert!(!tx.is_final(&at(1000, 600_000_000)));
+        assert!(tx.is_final(&at(0, 600_000_001)));
+        tx.vin[0].sequence = SEQUENCE_FINAL;
+        assert!(tx.is_final(&at(0, 0)));
+
+        // relative locks count from the block the spent output was confirmed in
+        tx.vin[0].sequence = relative_blocks(10);
+        assert!(!tx.sequence_locks_reached(&[at(5, 0)], &at(14, 0)));
+        assert!(tx.sequence_locks_reached(&[at(5, 0)], &at(15, 0)));
+        tx.vin[0].sequence = relative_seconds(1000).unwrap();
+        assert!(!tx.sequence_locks_reached(&[at(5, 0)], &at(100, 1023)));
+        assert!(tx.sequence_locks_reached(&[at(5, 0)], &at(6, 1024)));
+        assert!(relative_seconds(u32::MAX).is_err());
+
+        // a script lock time must be of the transaction's kind and not later than it
+        let checker = |lock_time, sequence| TransactionChecker { hash: String::new(), lock_time, sequence };
+        assert!(checker(150, 0).check_lock_time(150));
+        assert!(!checker(149, 0).check_lock_time(150));
+        assert!(!checker(600_000_000, 0).check_lock_time(150));
+        assert!(!checker(150, SEQUENCE_FINAL).check_lock_time(150));
+        assert!(checker(0, relative_blocks(10)).check_sequence(10));
+        assert!(!checker(0, relative_blocks(9)).check_sequence(10));
+        assert!(!checker(0, relative_seconds(10 * 512).unwrap()).check_sequence(10));
+    }
+
+    #[test]
+    fn test_parse_recipients() {
+        let json = parse_recipients(r#"{"m1": 10, "m2": 25}"#).unwrap();
+        assert_eq!(json, vec![(String::from("m1"), 10), (String::from("m2"), 25)]);
+        let csv = parse_recipients("address,amount\n# bonus\nm1, 10\n\nm2,25\n").unwrap();
+        assert_eq!(csv, json);
+
+        assert!(parse_recipients(r#"{"m1": 1.5}"#).is_err());
+        assert!(parse_recipients("m1,10\nm2,ten").is_err());
+        assert!(parse_recipients("m1 10").is_err());
+        assert!(parse_recipients("address,amount\n").is_err());
+
+        // 重复地址不能被静默覆盖
+        let err = parse_recipients(r#"{"m1": 10, "m2": 25, "m1": 5}"#).unwrap_err();
+        assert!(err.to_string().contains("m1 is listed more than once"), "{}", err);
+        assert!(parse_recipients("m1,10\nm1,5").is_err());
+    }
+}
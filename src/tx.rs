@@ -1,22 +1,26 @@
-use super::*;
+use crate::script::Script;
 use serde::{Deserialize, Serialize};
 
+/// SEQUENCE_FINAL opts an input out of lock times, a transaction whose inputs all have it ignores its lock_time
+pub const SEQUENCE_FINAL: u32 = 0xffff_ffff;
+/// SEQUENCE_LOCKTIME_DISABLE_FLAG set in a sequence turns off its relative lock
+pub const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+/// SEQUENCE_LOCKTIME_TYPE_FLAG makes a relative lock count 512 second units instead of blocks
+pub const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+pub const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000_ffff;
+pub const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXInput {
     pub txid: String,
     pub vout: i32,
-    pub script_sig: String,
+    pub script_sig: Script,
+    pub sequence: u32,
 }
 
 /// TXOutput represents a transaction output
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXOutput {
     pub value: i32,
-    pub script_pub_key: String,
+    pub script_pub_key: Script,
 }
-
-// TXOutputs collects TXOutput
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct TXOutputs {
-    pub outputs: Vec<TXOutput>,
-}
\ No newline at end of file
@@ -1,13 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use super::*;
 use bincode::{deserialize, serialize};
 use serde::{Deserialize, Serialize};
-use bitcoincash_addr::*;
-use sha2::{Sha256, Digest};
-use bitcoin_hashes::{ripemd160, Hash};
-use ed25519_dalek::{SigningKey, VerifyingKey, Signer};
+use crate::address::{checksum, Address};
+use crate::chainparams::ChainParams;
+use crate::crypter::{self, KeyCrypter, SCRYPT_LOG_N};
+use crate::hd::{self, ExtendedKey};
+use crate::multisig::MultisigScript;
+use crate::store;
+use ed25519_dalek::SigningKey;
+use failure::format_err;
 use log::info;
-use rand_core::OsRng;
 
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -18,18 +21,14 @@ pub struct Wallet {
 }
 
 impl Wallet {
-    /// NewWallet creates and returns a Wallet
-    fn new() -> Self {
-
-        // 生成随机的ED25519密钥对
-        let signing_key = SigningKey::generate(&mut OsRng);
-        
+    /// FromKey returns the Wallet of an existing private key
+    fn from_key(signing_key: &SigningKey, address_version: u8) -> Self {
         // 获取公钥和私钥
         let public_key = signing_key.verifying_key().as_bytes().to_vec();
         let secret_key = signing_key.as_bytes().to_vec();
         
         // 生成比特币地址
-        let address = Self::generate_address(&public_key);
+        let address = Self::generate_address(&public_key, address_version);
         
         Wallet {
             secret_key,
@@ -39,84 +38,318 @@ impl Wallet {
         
     }
 
-    fn generate_address(public_key: &Vec<u8>) -> String {
-        // 1. SHA256(公钥)
-        let mut sha256_hasher = Sha256::new();
-        sha256_hasher.update(public_key);
-        let sha256_result = sha256_hasher.finalize();
-        
-        // 2. RIPEMD160(SHA256(公钥))
-        let mut ripemd_result  = ripemd160::Hash::hash(&sha256_result);
-
-        
-         // 3. 添加版本号前缀 (0x00 为主网地址)
-        let mut version_payload = vec![0x00];
-        version_payload.extend_from_slice(&ripemd_result[..]);
-        
-        // 4. 双重SHA256用于校验
-        let mut check_hasher = Sha256::new();
-        check_hasher.update(&version_payload);
-        let mut check_result = check_hasher.finalize();
-        
-        let mut check_hasher2 = Sha256::new();
-        check_hasher2.update(&check_result);
-        check_result = check_hasher2.finalize();
-        
-        // 5. 取前4字节作为校验和
-        let checksum = &check_result[0..4];
-        
-        // 6. 将校验和附加到payload
-        version_payload.extend_from_slice(checksum);
-        
-        // 7. Base58编码
-        bs58::encode(version_payload).into_string()
+    fn generate_address(public_key: &[u8], address_version: u8) -> String {
+        Address::from_pub_key(public_key, address_version).to_string()
     }
 
     /// GetAddress returns wallet address
     pub fn get_address(&self) -> String {
         String::from(&self.address)
     }
+
+    pub fn get_public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// IsWatchOnly tells whether the wallet only watches an address, without its private key
+    pub fn is_watch_only(&self) -> bool {
+        self.secret_key.is_empty()
+    }
 }
 
 
 pub struct Wallets {
     wallets: HashMap<String, Wallet>,
+    path: String,
+    params: ChainParams,
+    crypter: Option<KeyCrypter>,
+    master_key: Option<[u8; 32]>,
+    hd_chain: Option<HdChain>,
+    change: ChangeChain,
+    scripts: HashMap<String, MultisigScript>,
+}
+
+/// HdChain is the mnemonic new addresses are derived from, encrypted like the keys
+#[derive(Serialize, Deserialize)]
+struct HdChain {
+    mnemonic: Vec<u8>,
+    next_index: u32,
+}
+
+/// ChangeChain is the internal HD chain change outputs are paid to, and the addresses derived from it
+#[derive(Serialize, Deserialize, Default)]
+struct ChangeChain {
+    next_index: u32,
+    addresses: HashSet<String>,
 }
 
 impl Wallets {
     /// NewWallets creates Wallets and fills it from a file if it exists
-    pub fn new() -> Result<Wallets> {
+    pub fn new(params: &ChainParams) -> Result<Wallets> {
         let mut wlt = Wallets {
             wallets: HashMap::<String, Wallet>::new(),
+            path: params.wallets_path(),
+            params: params.clone(),
+            crypter: None,
+            master_key: None,
+            hd_chain: None,
+            change: ChangeChain::default(),
+            scripts: HashMap::new(),
         };
-        let db = sled::open("data/wallets")?;
+        let db = store::open(&wlt.path)?;
 
         for item in db.into_iter() {
             let i = item?;
+            if i.0 == "NETWORK" {
+                params.check_magic(&i.1, "wallet store")?;
+                continue;
+            }
+            if i.0 == "CRYPT" {
+                wlt.crypter = Some(deserialize(&i.1)?);
+                continue;
+            }
+            if i.0 == "HDSEED" {
+                wlt.hd_chain = Some(deserialize(&i.1)?);
+                continue;
+            }
+            if i.0 == "SCRIPTS" {
+                wlt.scripts = deserialize(&i.1)?;
+                continue;
+            }
+            if i.0 == "CHANGE" {
+                wlt.change = deserialize(&i.1)?;
+                continue;
+            }
             let address = String::from_utf8(i.0.to_vec())?;
-            let wallet = deserialize(&i.1.to_vec())?;
+            let wallet = deserialize(&i.1)?;
             wlt.wallets.insert(address, wallet);
         }
         drop(db);
         Ok(wlt)
     }
 
-    /// CreateWallet adds a Wallet to Wallets
-    pub fn create_wallet(&mut self) -> String {
-        let wallet = Wallet::new();
+    pub fn get_params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    /// CreateWallet derives the next address of the HD chain, creating a new mnemonic
+    /// for a wallet store that has none
+    pub fn create_wallet(&mut self) -> Result<String> {
+        if self.hd_chain.is_none() {
+            self.set_mnemonic(&hd::generate_mnemonic()?)?;
+        }
+        let index = self.hd_chain.as_ref().map_or(0, |hd| hd.next_index);
+        let wallet = self.derive_wallet(&self.master_hd_key()?, false, index);
+        if let Some(hd) = self.hd_chain.as_mut() {
+            hd.next_index = index + 1;
+        }
+        self.add_wallet(wallet)
+    }
+
+    /// NextChangeAddress returns the change address new_change_address derives next, without keeping it,
+    /// a wallet without a seed has none until createwallet makes one and shows its mnemonic
+    pub fn next_change_address(&self) -> Result<Address> {
+        let wallet = self.derive_wallet(&self.master_hd_key()?, true, self.change.next_index);
+        wallet.address.parse()
+    }
+
+    /// NewChangeAddress derives the next address of the internal chain and marks it as change
+    pub fn new_change_address(&mut self) -> Result<String> {
+        let wallet = self.derive_wallet(&self.master_hd_key()?, true, self.change.next_index);
+        let address = self.add_wallet(wallet)?;
+        self.change.next_index += 1;
+        self.change.addresses.insert(address.clone());
+        Ok(address)
+    }
+
+    /// IsChange tells whether an address of the wallet only receives change
+    pub fn is_change(&self, address: &str) -> bool {
+        self.change.addresses.contains(address)
+    }
+
+    fn add_wallet(&mut self, mut wallet: Wallet) -> Result<String> {
         let address = wallet.get_address();
+        if self.crypter.is_some() {
+            let key = self.unlocked_key()?;
+            wallet.secret_key = crypter::encrypt(&key, &wallet.secret_key, address.as_bytes())?;
+        }
         self.wallets.insert(address.clone(), wallet);
         info!("create wallet: {}", address);
-        address
+        Ok(address)
+    }
+
+    fn set_mnemonic(&mut self, phrase: &str) -> Result<()> {
+        hd::mnemonic_to_seed(phrase)?;
+        let mnemonic = match self.crypter {
+            Some(_) => crypter::encrypt(&self.unlocked_key()?, phrase.as_bytes(), b"HDSEED")?,
+            None => phrase.as_bytes().to_vec(),
+        };
+        self.hd_chain = Some(HdChain {
+            mnemonic,
+            next_index: 0,
+        });
+        Ok(())
+    }
+
+    /// HasSeed tells whether addresses are derived from a mnemonic yet
+    pub fn has_seed(&self) -> bool {
+        self.hd_chain.is_some()
+    }
+
+    /// Mnemonic returns the mnemonic the wallet is derived from, the wallet has to be unlocked if encrypted
+    pub fn mnemonic(&self) -> Result<String> {
+        let hd = match &self.hd_chain {
+            Some(hd) => hd,
+            None => return Err(format_err!("Wallet has no HD seed, create an address first")),
+        };
+        let phrase = match self.crypter {
+            Some(_) => crypter::decrypt(&self.unlocked_key()?, &hd.mnemonic, b"HDSEED")?,
+            None => hd.mnemonic.clone(),
+        };
+        Ok(String::from_utf8(phrase)?)
+    }
+
+    /// DumpPrivKey returns the private key of an address in base58check with the network prefix
+    pub fn dump_priv_key(&self, address: &str) -> Result<String> {
+        let key = self.signing_key(address)?;
+        // 版本号 + 私钥 + 校验和, 再Base58编码
+        let mut payload = vec![self.params.secret_key_version];
+        payload.extend_from_slice(key.as_bytes());
+        let sum = checksum(&payload);
+        payload.extend_from_slice(&sum);
+        Ok(bs58::encode(payload).into_string())
+    }
+
+    /// ImportPrivKey adds the Wallet of a key written by dump_priv_key and returns its address
+    pub fn import_priv_key(&mut self, encoded: &str) -> Result<String> {
+        let data = bs58::decode(encoded)
+            .into_vec()
+            .map_err(|_| format_err!("Invalid private key encoding"))?;
+        if data.len() != 37 || checksum(&data[..33]) != data[33..] {
+            return Err(format_err!("Invalid private key encoding"));
+        }
+        if data[0] != self.params.secret_key_version {
+            return Err(format_err!("Private key is not a {} network key", self.params.network));
+        }
+        let secret: [u8; 32] = data[1..33].try_into()?;
+        let wallet = Wallet::from_key(&SigningKey::from_bytes(&secret), self.params.address_version);
+        if self.wallets.contains_key(&wallet.address) {
+            return Ok(wallet.get_address());
+        }
+        self.add_wallet(wallet)
+    }
+
+    /// AddWatchOnly adds an address whose balance is tracked without its private key,
+    /// the public key may be empty when only the pubkey hash is known
+    pub fn add_watch_only(&mut self, address: &Address, public_key: Vec<u8>) -> Result<String> {
+        address.check_network(&self.params)?;
+        let address = address.to_string();
+        if let Some(wallet) = self.wallets.get_mut(&address) {
+            if wallet.is_watch_only() && wallet.public_key.is_empty() {
+                wallet.public_key = public_key;
+            }
+            return Ok(address);
+        }
+        self.wallets.insert(
+            address.clone(),
+            Wallet {
+                secret_key: Vec::new(),
+                public_key,
+                address: address.clone(),
+            },
+        );
+        info!("watch address: {}", address);
+        Ok(address)
+    }
+
+    /// AddMultisig watches the address of a multisig script and keeps the script for co-signing
+    pub fn add_multisig(&mut self, script: MultisigScript) -> Result<String> {
+        let address = self.add_watch_only(&script.address(&self.params), Vec::new())?;
+        self.scripts.insert(address.clone(), script);
+        Ok(address)
+    }
+
+    /// GetScript returns the multisig script of an address added with add_multisig
+    pub fn get_script(&self, address: &str) -> Option<&MultisigScript> {
+        self.scripts.get(address)
+    }
+
+    /// IsWatchOnly tells whether an address of the wallet is watch-only
+    pub fn is_watch_only(&self, address: &str) -> bool {
+        self.wallets.get(address).is_some_and(|w| w.is_watch_only())
+    }
+
+    fn master_hd_key(&self) -> Result<ExtendedKey> {
+        Ok(ExtendedKey::from_seed(&hd::mnemonic_to_seed(&self.mnemonic()?)?))
+    }
+
+    fn derive_wallet(&self, master: &ExtendedKey, change: bool, index: u32) -> Wallet {
+        let path = hd::account_path(self.params.bip44_coin_type, change, index);
+        Wallet::from_key(&master.derive_path(&path).signing_key(), self.params.address_version)
+    }
+
+    /// Restore derives the receiving and change addresses of a mnemonic until `gap_limit` addresses
+    /// in a row are unused, keeping every address up to the last used one
+    pub fn restore<F>(&mut self, phrase: &str, gap_limit: u32, is_used: F) -> Result<Vec<String>>
+    where
+        F: Fn(&str) -> bool,
+    {
+        if self.hd_chain.is_some() || !self.wallets.is_empty() {
+            return Err(format_err!(
+                "Wallet already has keys, restore into an empty data directory"
+            ));
+        }
+        self.set_mnemonic(phrase)?;
+        let master = self.master_hd_key()?;
+
+        let mut addresses = Vec::new();
+        for change in [false, true] {
+            let derived = self.scan_chain(&master, change, gap_limit, &is_used);
+            let used = derived.len() as u32;
+            for wallet in derived {
+                let address = self.add_wallet(wallet)?;
+                if change {
+                    self.change.addresses.insert(address.clone());
+                }
+                addresses.push(address);
+            }
+            match self.hd_chain.as_mut() {
+                Some(hd) if !change => hd.next_index = used,
+                _ => self.change.next_index = used,
+            }
+        }
+        Ok(addresses)
+    }
+
+    fn scan_chain<F>(&self, master: &ExtendedKey, change: bool, gap_limit: u32, is_used: &F) -> Vec<Wallet>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let mut derived = Vec::new();
+        let mut used = 0;
+        while derived.len() < used + gap_limit as usize {
+            let wallet = self.derive_wallet(master, change, derived.len() as u32);
+            if is_used(&wallet.address) {
+                used = derived.len() + 1;
+            }
+            derived.push(wallet);
+        }
+        derived.truncate(used);
+        derived
     }
 
     /// GetAddresses returns an array of addresses stored in the wallet file
     pub fn get_all_addresses(&self) -> Vec<String> {
-        let mut addresses = Vec::<String>::new();
-        for (address, _) in &self.wallets {
-            addresses.push(address.clone());
-        }
-        addresses
+        self.wallets.keys().cloned().collect()
+    }
+
+    /// GetSpendableAddresses returns the addresses whose private keys are in the wallet
+    pub fn get_spendable_addresses(&self) -> Vec<String> {
+        self.wallets
+            .values()
+            .filter(|w| !w.is_watch_only())
+            .map(|w| w.get_address())
+            .collect()
     }
 
     /// GetWallet returns a Wallet by its address
@@ -124,9 +357,111 @@ impl Wallets {
         self.wallets.get(address)
     }
 
+    /// SigningKey returns the private key of an address, the wallet has to be unlocked if encrypted
+    pub fn signing_key(&self, address: &str) -> Result<SigningKey> {
+        let wallet = match self.wallets.get(address) {
+            Some(w) => w,
+            None => return Err(format_err!("Address {} is not in the wallet", address)),
+        };
+        if wallet.is_watch_only() {
+            return Err(format_err!(
+                "Address {} is watch-only, its private key is not in the wallet",
+                address
+            ));
+        }
+        let secret = match self.crypter {
+            Some(_) => crypter::decrypt(&self.unlocked_key()?, &wallet.secret_key, address.as_bytes())?,
+            None => wallet.secret_key.clone(),
+        };
+        let secret: [u8; 32] = secret
+            .as_slice()
+            .try_into()
+            .map_err(|_| format_err!("Private key of {} is corrupt", address))?;
+        Ok(SigningKey::from_bytes(&secret))
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.crypter.is_some()
+    }
+
+    fn unlocked_key(&self) -> Result<[u8; 32]> {
+        self.master_key
+            .ok_or_else(|| format_err!("Wallet is locked, the passphrase is needed"))
+    }
+
+    /// Encrypt protects every private key with a key derived from the passphrase and locks the wallet
+    pub fn encrypt(&mut self, passphrase: &str) -> Result<()> {
+        self.encrypt_with(passphrase, SCRYPT_LOG_N)
+    }
+
+    /// EncryptWith encrypts with a chosen scrypt cost, tests use a cheap one
+    pub(crate) fn encrypt_with(&mut self, passphrase: &str, log_n: u8) -> Result<()> {
+        if self.crypter.is_some() {
+            return Err(format_err!("Wallet is already encrypted"));
+        }
+        let (crypter, key) = KeyCrypter::new(passphrase, log_n)?;
+        for (address, wallet) in self.wallets.iter_mut().filter(|(_, w)| !w.is_watch_only()) {
+            wallet.secret_key = crypter::encrypt(&key, &wallet.secret_key, address.as_bytes())?;
+        }
+        if let Some(hd) = self.hd_chain.as_mut() {
+            hd.mnemonic = crypter::encrypt(&key, &hd.mnemonic, b"HDSEED")?;
+        }
+        self.crypter = Some(crypter);
+        // sled keeps overwritten entries in its log, so the store holding the plain keys is
+        // moved aside and the encrypted keys go to a new store in its place
+        let plain = format!("{}.plain", self.path);
+        store::close(&self.path)?;
+        std::fs::remove_dir_all(&plain).ok();
+        if std::path::Path::new(&self.path).exists() {
+            std::fs::rename(&self.path, &plain)?;
+        }
+        if let Err(e) = self.save_all() {
+            store::close(&self.path).ok();
+            std::fs::remove_dir_all(&self.path).ok();
+            std::fs::rename(&plain, &self.path).ok();
+            return Err(e);
+        }
+        std::fs::remove_dir_all(&plain).ok();
+        Ok(())
+    }
+
+    /// Unlock keeps the wallet key in memory for the rest of the process, it is never written out.
+    /// The key is returned so walletpassphrase can hand it to the wallet agent
+    pub fn unlock(&mut self, passphrase: &str) -> Result<[u8; 32]> {
+        let key = match &self.crypter {
+            Some(crypter) => crypter.unlock(passphrase)?,
+            None => return Err(format_err!("Wallet is not encrypted")),
+        };
+        self.master_key = Some(key);
+        Ok(key)
+    }
+
+    /// UnlockWithKey unlocks the wallet with the key the wallet agent keeps
+    pub fn unlock_with_key(&mut self, key: [u8; 32]) -> Result<()> {
+        match &self.crypter {
+            Some(crypter) => crypter.check_key(&key)?,
+            None => return Err(format_err!("Wallet is not encrypted")),
+        }
+        self.master_key = Some(key);
+        Ok(())
+    }
+
     /// SaveToFile saves wallets to a file
     pub fn save_all(&self) -> Result<()> {
-        let db = sled::open("data/wallets")?;
+        let db = store::open(&self.path)?;
+        db.insert("NETWORK", &self.params.magic)?;
+        if let Some(crypter) = &self.crypter {
+            db.insert("CRYPT", serialize(crypter)?)?;
+        }
+        if let Some(hd) = &self.hd_chain {
+            db.insert("HDSEED", serialize(hd)?)?;
+        }
+        if !self.scripts.is_empty() {
+            db.insert("SCRIPTS", serialize(&self.scripts)?)?;
+        }
+        if !self.change.addresses.is_empty() {
+            db.insert("CHANGE", serialize(&self.change)?)?;
+        }
 
         for (address, wallet) in &self.wallets {
             let data = serialize(wallet)?;
@@ -138,27 +473,45 @@ impl Wallets {
         Ok(())
     }
 }
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::blockchain::Fixture;
+    use crate::chainparams::Network;
+    use ed25519_dalek::Signer;
+    use rand_core::OsRng;
+
+    fn random_wallet(address_version: u8) -> Wallet {
+        Wallet::from_key(&SigningKey::generate(&mut OsRng), address_version)
+    }
 
     #[test]
     fn test_create_wallet_and_hash() {
-        let w1 = Wallet::new();
+        let w1 = random_wallet(0x00);
         println!("Bitcoin Address1: {}", w1.get_address());
-        let w2 = Wallet::new();
+        let w2 = random_wallet(0x00);
         println!("Bitcoin Address2: {}", w2.get_address());
         assert_ne!(w1, w2);
     }
 
+    #[test]
+    fn test_address_version() {
+        let main = random_wallet(ChainParams::main().address_version);
+        assert!(main.get_address().starts_with('1'));
+        let test = random_wallet(ChainParams::testnet().address_version);
+        assert!(test.get_address().starts_with('m') || test.get_address().starts_with('n'));
+    }
+
     #[test]
     fn test_wallets() {
-        let mut ws = Wallets::new().unwrap();
-        let wa1 = ws.create_wallet();
+        let f = Fixture::new(Network::Main);
+        let mut ws = Wallets::new(&f.params).unwrap();
+        let wa1 = ws.create_wallet().unwrap();
         let w1 = ws.get_wallet(&wa1).unwrap().clone();
         ws.save_all().unwrap();
 
-        let ws2 = Wallets::new().unwrap();
+        let ws2 = Wallets::new(&f.params).unwrap();
         let w2 = ws2.get_wallet(&wa1).unwrap();
         assert_eq!(&w1, w2);
     }
@@ -166,16 +519,162 @@ mod test {
     #[test]
     #[should_panic]
     fn test_wallets_not_exist() {
-        let w3 = Wallet::new();
-        let ws2 = Wallets::new().unwrap();
+        let f = Fixture::new(Network::Main);
+        let w3 = random_wallet(0x00);
+        let ws2 = Wallets::new(&f.params).unwrap();
         ws2.get_wallet(&w3.get_address()).unwrap();
     }
 
+    #[test]
+    fn test_encrypt_wallet() {
+        let mut f = Fixture::new(Network::Test);
+        let (params, address) = (f.params.clone(), f.alice.clone());
+        let ws = &mut f.ws;
+        let key = ws.signing_key(&address).unwrap();
+        ws.save_all().unwrap();
+        ws.encrypt_with("secret", 4).unwrap();
+        assert!(ws.encrypt_with("secret", 4).is_err());
+
+        // the store no longer holds the raw private key, not even in its log
+        let db = store::open(&params.wallets_path()).unwrap();
+        let stored: Wallet = deserialize(&db.get(&address).unwrap().unwrap()).unwrap();
+        assert_ne!(stored.secret_key, key.to_bytes().to_vec());
+        drop(db);
+        let mut dirs = vec![std::path::PathBuf::from(params.wallets_path())];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else {
+                    let data = std::fs::read(path).unwrap();
+                    assert!(!data.windows(32).any(|w| w == key.as_bytes()));
+                }
+            }
+        }
+
+        let mut ws = Wallets::new(&params).unwrap();
+        assert!(ws.is_encrypted());
+        assert!(ws.signing_key(&address).is_err());
+        assert!(ws.create_wallet().is_err());
+        assert!(ws.unlock("wrong").is_err());
+        let master_key = ws.unlock("secret").unwrap();
+        assert_eq!(ws.signing_key(&address).unwrap().to_bytes(), key.to_bytes());
+        let second = ws.create_wallet().unwrap();
+        assert!(ws.signing_key(&second).is_ok());
+        ws.save_all().unwrap();
+
+        // the unlocked key stays in memory, a new load is locked again
+        let mut ws = Wallets::new(&params).unwrap();
+        assert!(ws.signing_key(&address).is_err());
+        // the key the wallet agent keeps unlocks it without the passphrase
+        assert!(ws.unlock_with_key([0; 32]).is_err());
+        ws.unlock_with_key(master_key).unwrap();
+        assert!(ws.signing_key(&second).is_ok());
+    }
+
+    #[test]
+    fn test_restore_wallet() {
+        let mut f = Fixture::new(Network::Regtest);
+        let mut addresses = vec![f.alice.clone()];
+        addresses.extend((0..4).map(|_| f.ws.create_wallet().unwrap()));
+        let mnemonic = f.ws.mnemonic().unwrap();
+
+        // only the second and fourth address received coins
+        let used = [addresses[1].clone(), addresses[3].clone()];
+        let mut restored = f.wallets(Network::Regtest);
+        let found = restored.restore(&mnemonic, 3, |a| used.contains(&a.to_string())).unwrap();
+        assert_eq!(found, addresses[..4].to_vec());
+        assert_eq!(restored.create_wallet().unwrap(), addresses[4]);
+        assert!(restored.restore(&mnemonic, 3, |_| false).is_err());
+
+        // a gap longer than the limit hides later addresses
+        let mut short = f.wallets(Network::Regtest);
+        assert!(short.restore(&mnemonic, 1, |a| used.contains(&a.to_string())).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_change_addresses() {
+        let mut f = Fixture::new(Network::Regtest);
+        let mut unseeded = f.wallets(Network::Regtest);
+        assert!(unseeded.next_change_address().is_err() && unseeded.new_change_address().is_err());
+        assert!(!unseeded.has_seed());
+        let receive = f.alice.clone();
+        let ws = &mut f.ws;
+        let next = ws.next_change_address().unwrap().to_string();
+        assert_eq!(ws.next_change_address().unwrap().to_string(), next);
+        let change = ws.new_change_address().unwrap();
+        assert_eq!(change, next);
+        assert_ne!(change, receive);
+        assert!(ws.is_change(&change) && !ws.is_change(&receive));
+        assert!(ws.signing_key(&change).is_ok());
+        ws.save_all().unwrap();
+
+        let mut ws = Wallets::new(&f.params).unwrap();
+        assert!(ws.is_change(&change));
+        assert_ne!(ws.new_change_address().unwrap(), change);
+
+        // a restore finds coins sent to change addresses too
+        let mnemonic = ws.mnemonic().unwrap();
+        let mut restored = f.wallets(Network::Regtest);
+        let found = restored.restore(&mnemonic, 3, |a| a == receive || a == change).unwrap();
+        assert_eq!(found, vec![receive.clone(), change.clone()]);
+        assert!(restored.is_change(&change));
+        assert_ne!(restored.new_change_address().unwrap(), change);
+    }
+
+    #[test]
+    fn test_import_priv_key() {
+        let f = Fixture::new(Network::Test);
+        let (ws, address) = (&f.ws, f.alice.clone());
+        let encoded = ws.dump_priv_key(&address).unwrap();
+        assert!(encoded.starts_with('9'));
+
+        let mut other = f.wallets(Network::Test);
+        assert_eq!(other.import_priv_key(&encoded).unwrap(), address);
+        assert_eq!(other.import_priv_key(&encoded).unwrap(), address);
+        assert_eq!(other.signing_key(&address).unwrap().to_bytes(), ws.signing_key(&address).unwrap().to_bytes());
+
+        let mut typo = encoded.clone().into_bytes();
+        typo[5] = if typo[5] == b'2' { b'3' } else { b'2' };
+        assert!(other.import_priv_key(std::str::from_utf8(&typo).unwrap()).is_err());
+        let mut main = f.wallets(Network::Main);
+        assert!(main.import_priv_key(&encoded).is_err());
+
+        // testnet and regtest keys are told apart both ways
+        let mut regtest = f.wallets(Network::Regtest);
+        assert!(regtest.import_priv_key(&encoded).is_err());
+        let reg_address = regtest.create_wallet().unwrap();
+        assert!(other.import_priv_key(&regtest.dump_priv_key(&reg_address).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_watch_only() {
+        let f = Fixture::new(Network::Main);
+        let mut ws = f.wallets(Network::Main);
+        let params = ws.get_params().clone();
+        let cold = random_wallet(params.address_version);
+        let by_hash = Address::new(0x00, vec![9; 20]);
+        let address = ws.add_watch_only(&cold.get_address().parse().unwrap(), Vec::new()).unwrap();
+        ws.add_watch_only(&cold.get_address().parse().unwrap(), cold.public_key.clone()).unwrap();
+        ws.add_watch_only(&by_hash, Vec::new()).unwrap();
+        assert!(ws.add_watch_only(&Address::new(0x6f, vec![9; 20]), Vec::new()).is_err());
+
+        assert!(ws.is_watch_only(&address));
+        assert_eq!(ws.get_wallet(&address).unwrap().get_public_key(), cold.get_public_key());
+        assert!(ws.signing_key(&address).unwrap_err().to_string().contains("watch-only"));
+        assert!(ws.dump_priv_key(&by_hash.to_string()).is_err());
+
+        // encrypting leaves nothing to encrypt for watch-only entries
+        ws.encrypt_with("secret", 4).unwrap();
+        let ws = Wallets::new(&params).unwrap();
+        assert!(ws.is_watch_only(&by_hash.to_string()));
+        assert_eq!(ws.get_all_addresses().len(), 2);
+    }
+
     #[test]
     fn test_signature() {
-        let w = Wallet::new();
         let sk = SigningKey::generate(&mut OsRng);
-        let vk = sk.verifying_key();
         let signature = sk.sign("test".as_bytes());
         sk.verify(
             "test".as_bytes(),